@@ -8,36 +8,81 @@ use crate::{
 };
 use chappy_util::init_tracing_shared_lib;
 use nix::{
-    libc::{__errno_location, c_int, sockaddr, socklen_t, ECONNREFUSED},
+    libc::{__errno_location, c_int, c_void, msghdr, size_t, sockaddr, socklen_t, ssize_t},
     sys::socket::{SockaddrIn, SockaddrLike},
 };
 use std::ptr;
 use tracing::debug_span;
 
-use utils::{parse_virtual, request_punch};
+use utils::{
+    hooks_bypassed, is_datagram, parse_virtual, request_punch, request_udp_relay,
+    rewrite_relay_source, tunnel_errno,
+};
 
 type ConnectSymbol<'a> =
     libloading::Symbol<'a, unsafe extern "C" fn(c_int, *const sockaddr, socklen_t) -> c_int>;
 
+type SendtoSymbol<'a> = libloading::Symbol<
+    'a,
+    unsafe extern "C" fn(
+        c_int,
+        *const c_void,
+        size_t,
+        c_int,
+        *const sockaddr,
+        socklen_t,
+    ) -> ssize_t,
+>;
+
+type RecvfromSymbol<'a> = libloading::Symbol<
+    'a,
+    unsafe extern "C" fn(
+        c_int,
+        *mut c_void,
+        size_t,
+        c_int,
+        *mut sockaddr,
+        *mut socklen_t,
+    ) -> ssize_t,
+>;
+
+type RecvmsgSymbol<'a> =
+    libloading::Symbol<'a, unsafe extern "C" fn(c_int, *mut msghdr, c_int) -> ssize_t>;
+
 /// # Safety
 ///
 /// This function can be called the same way the libc `connect` function is called
 #[no_mangle]
 pub unsafe extern "C" fn connect(sockfd: c_int, addr: *const sockaddr, len: socklen_t) -> c_int {
+    let libc_connect: ConnectSymbol = LIBC_LOADED.get(b"connect").unwrap();
+    // the interceptor connecting to the perforator
+    if hooks_bypassed() {
+        return libc_connect(sockfd, addr, len);
+    }
     init_tracing_shared_lib();
     let span = debug_span!("connect", sock = sockfd);
     let _entered = span.enter();
-    let libc_connect: ConnectSymbol = LIBC_LOADED.get(b"connect").unwrap();
     let code = match parse_virtual(addr, len) {
-        RemoteVirtual(addr_in) => {
-            if let Ok(new_addr) = request_punch(sockfd, addr_in) {
+        RemoteVirtual(addr_in) if is_datagram(sockfd) => match request_udp_relay(addr_in) {
+            Ok(new_addr) => {
                 debug_fmt::dst_rewrite("connect", sockfd, &new_addr, &addr_in);
                 libc_connect(sockfd, ptr::addr_of!(new_addr).cast(), new_addr.len())
-            } else {
-                *__errno_location() = ECONNREFUSED;
+            }
+            Err(err) => {
+                *__errno_location() = tunnel_errno(&err);
                 -1
             }
-        }
+        },
+        RemoteVirtual(addr_in) => match request_punch(sockfd, addr_in) {
+            Ok(new_addr) => {
+                debug_fmt::dst_rewrite("connect", sockfd, &new_addr, &addr_in);
+                libc_connect(sockfd, ptr::addr_of!(new_addr).cast(), new_addr.len())
+            }
+            Err(err) => {
+                *__errno_location() = tunnel_errno(&err);
+                -1
+            }
+        },
         LocalVirtual(addr_in) => {
             let local = SockaddrIn::new(127, 0, 0, 1, addr_in.port());
             debug_fmt::dst_rewrite("connect", sockfd, &local, &addr_in);
@@ -51,3 +96,103 @@ pub unsafe extern "C" fn connect(sockfd: c_int, addr: *const sockaddr, len: sock
     debug_fmt::return_code("connect", sockfd, code);
     code
 }
+
+/// # Safety
+///
+/// This function can be called the same way the libc `sendto` function is called
+#[no_mangle]
+pub unsafe extern "C" fn sendto(
+    sockfd: c_int,
+    buf: *const c_void,
+    size: size_t,
+    flags: c_int,
+    addr: *const sockaddr,
+    len: socklen_t,
+) -> ssize_t {
+    let libc_sendto: SendtoSymbol = LIBC_LOADED.get(b"sendto").unwrap();
+    // connected sockets and streams don't provide a destination
+    if addr.is_null() || hooks_bypassed() {
+        return libc_sendto(sockfd, buf, size, flags, addr, len);
+    }
+    init_tracing_shared_lib();
+    let span = debug_span!("sendto", sock = sockfd);
+    let _entered = span.enter();
+    let code = match parse_virtual(addr, len) {
+        RemoteVirtual(addr_in) if is_datagram(sockfd) => match request_udp_relay(addr_in) {
+            Ok(new_addr) => {
+                debug_fmt::dst_rewrite("sendto", sockfd, &new_addr, &addr_in);
+                let new_addr_ptr = ptr::addr_of!(new_addr).cast();
+                libc_sendto(sockfd, buf, size, flags, new_addr_ptr, new_addr.len())
+            }
+            Err(err) => {
+                *__errno_location() = tunnel_errno(&err);
+                -1
+            }
+        },
+        LocalVirtual(addr_in) => {
+            let local = SockaddrIn::new(127, 0, 0, 1, addr_in.port());
+            debug_fmt::dst_rewrite("sendto", sockfd, &local, &addr_in);
+            libc_sendto(
+                sockfd,
+                buf,
+                size,
+                flags,
+                ptr::addr_of!(local).cast(),
+                local.len(),
+            )
+        }
+        // e.g. TCP fast open, which the perforator cannot register
+        RemoteVirtual(_) | NotVirtual | Unknown => libc_sendto(sockfd, buf, size, flags, addr, len),
+    };
+    // the return code is a size, only errors are traced
+    if code == -1 {
+        debug_fmt::return_code("sendto", sockfd, -1);
+    }
+    code
+}
+
+/// # Safety
+///
+/// This function can be called the same way the libc `recvfrom` function is called
+#[no_mangle]
+pub unsafe extern "C" fn recvfrom(
+    sockfd: c_int,
+    buf: *mut c_void,
+    size: size_t,
+    flags: c_int,
+    addr: *mut sockaddr,
+    len: *mut socklen_t,
+) -> ssize_t {
+    let libc_recvfrom: RecvfromSymbol = LIBC_LOADED.get(b"recvfrom").unwrap();
+    // streams and callers that ignore the source are not rewritten
+    if addr.is_null() || len.is_null() || hooks_bypassed() {
+        return libc_recvfrom(sockfd, buf, size, flags, addr, len);
+    }
+    let capacity = *len;
+    let code = libc_recvfrom(sockfd, buf, size, flags, addr, len);
+    if code >= 0 {
+        rewrite_relay_source(addr, len, capacity);
+    }
+    code
+}
+
+/// # Safety
+///
+/// This function can be called the same way the libc `recvmsg` function is called
+#[no_mangle]
+pub unsafe extern "C" fn recvmsg(sockfd: c_int, msg: *mut msghdr, flags: c_int) -> ssize_t {
+    let libc_recvmsg: RecvmsgSymbol = LIBC_LOADED.get(b"recvmsg").unwrap();
+    if msg.is_null() || (*msg).msg_name.is_null() || hooks_bypassed() {
+        return libc_recvmsg(sockfd, msg, flags);
+    }
+    let capacity = (*msg).msg_namelen;
+    let code = libc_recvmsg(sockfd, msg, flags);
+    if code >= 0 {
+        rewrite_relay_source(
+            (*msg).msg_name.cast(),
+            ptr::addr_of_mut!((*msg).msg_namelen),
+            capacity,
+        );
+    }
+    code
+}
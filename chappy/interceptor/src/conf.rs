@@ -1,4 +1,38 @@
+use crate::utils::{block_on_bypassed, PERFORATOR_ADDRESS};
+use chappy_util::optional_fields::OptionalFields;
+use chappy_util::protocol::query_virtual_ip;
 use std::env::var;
+use std::sync::OnceLock;
+use tracing::error;
+
+/// Virtual IP assigned by the seed, only cached once the perforator answered
+static ASSIGNED_VIRTUAL_IP: OnceLock<String> = OnceLock::new();
+
+/// Query the perforator for the assigned virtual IP until it answers
+fn assigned_virtual_ip() -> Option<String> {
+    if let Some(virtual_ip) = ASSIGNED_VIRTUAL_IP.get() {
+        return Some(virtual_ip.clone());
+    }
+    // the query cannot carry the virtual IP it is looking for
+    let fields = OptionalFields {
+        cluster_id: cluster_id(),
+        ..Default::default()
+    };
+    match block_on_bypassed(query_virtual_ip(PERFORATOR_ADDRESS, &fields)) {
+        Ok(virtual_ip) => Some(
+            ASSIGNED_VIRTUAL_IP
+                .get_or_init(|| virtual_ip.to_string())
+                .clone(),
+        ),
+        Err(err) => {
+            error!(
+                "Perforator call for the assigned virtual IP failed: {}",
+                err
+            );
+            None
+        }
+    }
+}
 
 pub(crate) fn virtual_subnet() -> Option<ipnet::Ipv4Net> {
     var("CHAPPY_VIRTUAL_SUBNET")
@@ -6,6 +40,33 @@ pub(crate) fn virtual_subnet() -> Option<ipnet::Ipv4Net> {
         .ok()
 }
 
+/// The static virtual IP, or the one assigned by the seed from the virtual
+/// subnet if none is provided
 pub(crate) fn virtual_ip() -> Option<String> {
-    var("CHAPPY_VIRTUAL_IP").ok()
+    var("CHAPPY_VIRTUAL_IP").ok().or_else(|| {
+        virtual_subnet()?;
+        assigned_virtual_ip()
+    })
+}
+
+/// Cluster of the virtual IP, for perforators that serve multiple clusters
+pub(crate) fn cluster_id() -> Option<String> {
+    var("CHAPPY_CLUSTER_ID").ok()
+}
+
+/// W3C trace context of the intercepted process, if it was provided one
+pub(crate) fn trace_context() -> Option<String> {
+    var("TRACEPARENT").ok()
+}
+
+/// Comma separated key=value pairs attached to the tunnels
+pub(crate) fn tags() -> Vec<(String, String)> {
+    var("CHAPPY_TAGS")
+        .map(|v| {
+            v.split(',')
+                .filter_map(|tag| tag.split_once('='))
+                .map(|(key, value)| (key.trim().to_owned(), value.trim().to_owned()))
+                .collect()
+        })
+        .unwrap_or_default()
 }
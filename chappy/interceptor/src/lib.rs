@@ -6,12 +6,13 @@ mod utils;
 #[macro_use]
 extern crate lazy_static;
 
-pub use bindings::connect;
+pub use bindings::{connect, recvfrom, recvmsg, sendto};
 
 lazy_static! {
     pub(crate) static ref RUNTIME: tokio::runtime::Runtime =
         tokio::runtime::Builder::new_multi_thread()
             .worker_threads(1)
+            .on_thread_start(utils::bypass_hooks)
             .enable_all()
             .build()
             .unwrap();
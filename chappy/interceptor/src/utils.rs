@@ -1,12 +1,52 @@
 use crate::{conf, RUNTIME};
-use nix::libc::{c_int, sockaddr, socklen_t};
-use nix::sys::socket::{self, SockaddrIn, SockaddrLike, SockaddrStorage};
-use std::io::Result as IoResult;
+use chappy_util::optional_fields::OptionalFields;
+use chappy_util::protocol::UdpRelay;
+use chappy_util::tunnel_error::TunnelError;
+use nix::libc::{
+    c_int, sockaddr, sockaddr_in, socklen_t, EACCES, EADDRNOTAVAIL, ECANCELED, ECONNABORTED,
+    ECONNREFUSED, EHOSTDOWN, EHOSTUNREACH, ENOBUFS, EPROTO, ETIMEDOUT,
+};
+use nix::sys::socket::{self, sockopt, SockType, SockaddrIn, SockaddrLike, SockaddrStorage};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{Error as IoError, Result as IoResult};
+use std::mem;
 use std::net::{Ipv4Addr, SocketAddrV4};
+use std::ptr;
 use std::str::FromStr;
+use std::sync::{Condvar, Mutex};
 use tracing::{debug, error, trace};
 
-const PERFORATOR_ADDRESS: &str = "127.0.0.1:5000";
+pub(crate) const PERFORATOR_ADDRESS: &str = "127.0.0.1:5000";
+
+thread_local! {
+    /// Set while the interceptor calls the perforator, so that the sockets it
+    /// opens are not intercepted themselves
+    static HOOKS_BYPASSED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether the calls on this thread are made by the interceptor itself
+pub(crate) fn hooks_bypassed() -> bool {
+    HOOKS_BYPASSED.with(Cell::get)
+}
+
+/// Mark the current thread as owned by the interceptor, e.g. the threads of
+/// its runtime
+pub(crate) fn bypass_hooks() {
+    HOOKS_BYPASSED.with(|bypassed| bypassed.set(true));
+}
+
+/// Run a perforator call to completion with the hooks bypassed
+///
+/// The future runs on the calling thread, which is flagged for the duration
+/// of the call.
+pub(crate) fn block_on_bypassed<F: Future>(fut: F) -> F::Output {
+    let previous = HOOKS_BYPASSED.with(|bypassed| bypassed.replace(true));
+    let output = RUNTIME.block_on(fut);
+    HOOKS_BYPASSED.with(|bypassed| bypassed.set(previous));
+    output
+}
 
 fn bind_random_port(sockfd: c_int) -> u16 {
     // TODO support ipv6
@@ -21,18 +61,30 @@ fn bind_random_port(sockfd: c_int) -> u16 {
     bound_socket.port()
 }
 
+/// Fields identifying the process towards the perforator and the targets
+fn registration_fields() -> OptionalFields {
+    OptionalFields {
+        source_virtual_ip: conf::virtual_ip().and_then(|ip| ip.parse().ok()),
+        trace_context: conf::trace_context(),
+        tags: conf::tags(),
+        cluster_id: conf::cluster_id(),
+    }
+}
+
 pub(crate) fn request_punch(sockfd: c_int, addr_in: SockaddrIn) -> IoResult<SockaddrIn> {
     let src_port = bind_random_port(sockfd);
 
     // TODO: blocking here is not ideal because it makes the connect blocking
     // event if it wasn't supposed to be. But if made none-blocking by spawning a task,
     // we have to make sure that the task is brought to completion.
-    RUNTIME.block_on(async move {
+    let fields = registration_fields();
+    block_on_bypassed(async move {
         let res = chappy_util::protocol::register_client(
             PERFORATOR_ADDRESS,
             src_port,
             addr_in.ip().into(),
             addr_in.port(),
+            &fields,
         )
         .await;
         match &res {
@@ -57,6 +109,137 @@ pub(crate) fn request_punch(sockfd: c_int, addr_in: SockaddrIn) -> IoResult<Sock
     Ok(SockaddrIn::from_str(PERFORATOR_ADDRESS).unwrap())
 }
 
+/// Relay of a UDP target
+enum RelaySlot {
+    /// Requested from the perforator by another thread
+    Registering,
+    Open(UdpRelay),
+}
+
+lazy_static! {
+    /// Local relays of the UDP targets already registered or being registered
+    static ref UDP_RELAYS: Mutex<HashMap<SocketAddrV4, RelaySlot>> = Mutex::new(HashMap::new());
+    /// Notified when a registration completes
+    static ref UDP_RELAY_REGISTERED: Condvar = Condvar::new();
+}
+
+/// Get the local address of the perforator relay for datagrams to the target
+///
+/// The relay is requested once per target and reused by all the sockets of
+/// the process, until the perforator closes it.
+pub(crate) fn request_udp_relay(addr_in: SockaddrIn) -> IoResult<SockaddrIn> {
+    let target = SocketAddrV4::new(addr_in.ip().into(), addr_in.port());
+    let mut relays = UDP_RELAYS.lock().unwrap();
+    loop {
+        match relays.get(&target) {
+            // wait for the other registration instead of opening a duplicate relay
+            Some(RelaySlot::Registering) => relays = UDP_RELAY_REGISTERED.wait(relays).unwrap(),
+            Some(RelaySlot::Open(relay)) if relay.is_closed() => {
+                debug!("Relay of {} closed, registering again", target);
+                break;
+            }
+            Some(RelaySlot::Open(relay)) => return Ok(SockaddrIn::new(127, 0, 0, 1, relay.port)),
+            None => break,
+        }
+    }
+    // the lock is released during registration so that the datagrams from
+    // the other relays can still be received
+    relays.insert(target, RelaySlot::Registering);
+    drop(relays);
+    let fields = registration_fields();
+    let register_res = block_on_bypassed(chappy_util::protocol::register_udp(
+        PERFORATOR_ADDRESS,
+        *target.ip(),
+        target.port(),
+        &fields,
+    ));
+    let mut relays = UDP_RELAYS.lock().unwrap();
+    let res = match register_res {
+        Ok(relay) => {
+            debug!("Datagrams to {} relayed by port {}", target, relay.port);
+            let relay_addr = SockaddrIn::new(127, 0, 0, 1, relay.port);
+            relays.insert(target, RelaySlot::Open(relay));
+            Ok(relay_addr)
+        }
+        Err(err) => {
+            error!(
+                "Perforator call for relaying datagrams to {} failed: {}",
+                target, err
+            );
+            relays.remove(&target);
+            Err(err)
+        }
+    };
+    UDP_RELAY_REGISTERED.notify_all();
+    res
+}
+
+/// Report a datagram received from a local relay as sent by its target, so
+/// that the applications checking the source of the replies accept them
+///
+/// # Safety
+///
+/// `addr` must point to a buffer of `capacity` bytes filled with an address
+/// of `len` bytes, e.g. by `recvfrom`.
+pub(crate) unsafe fn rewrite_relay_source(
+    addr: *mut sockaddr,
+    len: *mut socklen_t,
+    capacity: socklen_t,
+) {
+    let addr_in_len = mem::size_of::<sockaddr_in>() as socklen_t;
+    if addr.is_null() || len.is_null() || *len != addr_in_len || capacity < addr_in_len {
+        return;
+    }
+    let Some(relay_addr) = SockaddrIn::from_raw(addr, Some(*len)) else {
+        return;
+    };
+    if Ipv4Addr::from(relay_addr.ip()) != Ipv4Addr::LOCALHOST {
+        return;
+    }
+    let relays = UDP_RELAYS.lock().unwrap();
+    let target = relays
+        .iter()
+        .find(|(_, slot)| matches!(slot, RelaySlot::Open(relay) if relay.port == relay_addr.port()))
+        .map(|(target, _)| SockaddrIn::from(*target));
+    if let Some(target) = target {
+        trace!("Datagram from relay port {} rewritten", relay_addr.port());
+        ptr::copy_nonoverlapping(
+            target.as_ptr().cast::<u8>(),
+            addr.cast(),
+            target.len() as usize,
+        );
+        *len = target.len();
+    }
+}
+
+/// Whether the socket sends datagrams instead of a stream
+pub(crate) fn is_datagram(sockfd: c_int) -> bool {
+    socket::getsockopt(sockfd, sockopt::SockType) == Ok(SockType::Datagram)
+}
+
+/// The errno reported by `connect` when the tunnel could not be established
+pub(crate) fn tunnel_errno(err: &IoError) -> c_int {
+    let tunnel_err = err
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<TunnelError>());
+    match tunnel_err {
+        Some(TunnelError::TargetRefused) => ECONNREFUSED,
+        Some(TunnelError::TargetTimedOut) => ETIMEDOUT,
+        Some(TunnelError::PortNotExposed) => EACCES,
+        Some(TunnelError::QuicConnectFailed) => EHOSTUNREACH,
+        Some(TunnelError::CertificateMismatch) => ECONNABORTED,
+        Some(TunnelError::ProtocolVersionMismatch) => EPROTO,
+        Some(TunnelError::UnknownIdentity) => EADDRNOTAVAIL,
+        Some(TunnelError::TargetLeft) => EHOSTDOWN,
+        Some(TunnelError::TargetUnresolved) => EHOSTUNREACH,
+        Some(TunnelError::BarrierBroken) => ECANCELED,
+        Some(TunnelError::ClusterAborted) => ECANCELED,
+        Some(TunnelError::RelayUnavailable) => ENOBUFS,
+        // the perforator itself could not be reached
+        None => ECONNREFUSED,
+    }
+}
+
 pub(crate) enum ParsedAddress {
     RemoteVirtual(SockaddrIn),
     LocalVirtual(SockaddrIn),
@@ -0,0 +1,153 @@
+//! Processes preloaded with the interceptor, with a fake perforator
+
+use chappy_util::protocol::ParsedTcpStream;
+use lazy_static::lazy_static;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::Mutex;
+
+lazy_static! {
+    /// Held by the tests that play the perforator, which listens on a fixed port
+    static ref PERFORATOR_PORT: Mutex<()> = Mutex::new(());
+}
+
+/// The interceptor library, built next to the test binaries because the
+/// tests depend on its rlib
+fn interceptor_lib() -> PathBuf {
+    let test_exe = std::env::current_exe().unwrap();
+    test_exe.parent().unwrap().join("libchappy.so")
+}
+
+/// Run the bash script with the interceptor preloaded, None if it hangs
+fn run_preloaded(script: &str, envs: &[(&str, &str)], timeout: Duration) -> Option<ExitStatus> {
+    let mut child = Command::new("bash")
+        .args(["-c", script])
+        .env("LD_PRELOAD", interceptor_lib())
+        .env_remove("CHAPPY_VIRTUAL_IP")
+        .envs(envs.iter().copied())
+        .spawn()
+        .unwrap();
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if let Some(status) = child.try_wait().unwrap() {
+            return Some(status);
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+    None
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_subnet_only_config() {
+    let _perforator_port = PERFORATOR_PORT.lock().await;
+    let assigned_ip = Ipv4Addr::new(172, 28, 0, 7);
+    let perforator = TcpListener::bind("127.0.0.1:5000").await.unwrap();
+    let perforator_handle = tokio::spawn(async move {
+        // the first query fails, the next connect should query again
+        let (unanswered, _) = perforator.accept().await.unwrap();
+        drop(unanswered);
+        let (stream, _) = perforator.accept().await.unwrap();
+        match ParsedTcpStream::from(stream).await.unwrap() {
+            ParsedTcpStream::VirtualIpQuery {
+                response_writer, ..
+            } => response_writer.write(Ok(assigned_ip)).await,
+            _ => panic!("virtual IP query expected"),
+        }
+    });
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_port = target.local_addr().unwrap().port();
+
+    // the virtual IP is resolved from the connect hook
+    let script = format!(
+        "echo first > /dev/tcp/127.0.0.1/{port}; echo second > /dev/tcp/{}/{port}",
+        assigned_ip,
+        port = target_port
+    );
+    let child = tokio::task::spawn_blocking(move || {
+        run_preloaded(
+            &script,
+            &[("CHAPPY_VIRTUAL_SUBNET", "172.28.0.0/16")],
+            Duration::from_secs(10),
+        )
+    });
+    for expected in ["first\n", "second\n"] {
+        let (mut stream, _) = tokio::time::timeout(Duration::from_secs(10), target.accept())
+            .await
+            .expect("preloaded process should connect")
+            .unwrap();
+        let mut received = String::new();
+        stream.read_to_string(&mut received).await.unwrap();
+        assert_eq!(received, expected);
+    }
+    let status = child.await.unwrap().expect("preloaded process hung");
+    assert!(status.success());
+    perforator_handle.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_udp_relay_closed() {
+    let _perforator_port = PERFORATOR_PORT.lock().await;
+    let target_ip = Ipv4Addr::new(172, 28, 0, 9);
+    let perforator = TcpListener::bind("127.0.0.1:5000").await.unwrap();
+    let perforator_handle = tokio::spawn(async move {
+        for expected in ["first", "second"] {
+            let (stream, _) = perforator.accept().await.unwrap();
+            let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let relay_port = relay.local_addr().unwrap().port();
+            let registration = match ParsedTcpStream::from(stream).await.unwrap() {
+                ParsedTcpStream::UdpRegistration {
+                    target_virtual_ip,
+                    target_port,
+                    response_writer,
+                    ..
+                } => {
+                    assert_eq!((target_virtual_ip, target_port), (target_ip, 8125));
+                    response_writer.write_success(relay_port).await
+                }
+                _ => panic!("UDP registration expected"),
+            };
+            let mut buf = [0; 64];
+            let (len, src) = relay.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], expected.as_bytes());
+            // the relay closes after the reply, the next datagram should be
+            // relayed by a new one
+            drop(registration);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            relay.send_to(&buf[..len], src).await.unwrap();
+        }
+    });
+
+    // the replies should come from the target, as resolvers check it
+    let script = format!(
+        "exec python3 - <<EOF
+import socket
+sock = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+for payload in (b'first', b'second'):
+    sock.sendto(payload, ('{}', 8125))
+    reply, src = sock.recvfrom(64)
+    assert (reply, src) == (payload, ('{}', 8125)), (reply, src)
+EOF",
+        target_ip, target_ip
+    );
+    let status = tokio::task::spawn_blocking(move || {
+        run_preloaded(
+            &script,
+            &[
+                ("CHAPPY_VIRTUAL_IP", "172.28.0.1"),
+                ("CHAPPY_VIRTUAL_SUBNET", "172.28.0.0/16"),
+            ],
+            Duration::from_secs(10),
+        )
+    })
+    .await
+    .unwrap()
+    .expect("preloaded process hung");
+    assert!(status.success());
+    perforator_handle.await.unwrap();
+}
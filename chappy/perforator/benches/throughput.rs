@@ -0,0 +1,117 @@
+//! Throughput of concurrent tunnels depending on the number of forwarder shards
+//!
+//! Run with `cargo bench -p chappy-perforator --bench throughput`. The
+//! forwarder relays the tunnels to itself, so each shard carries both the
+//! client and the server side of its connections.
+
+use chappy_perforator::forwarder::Forwarder;
+use chappy_perforator::fwd_protocol::FWD_PROTOCOL_VERSION;
+use chappy_perforator::quic_utils::TransportProfile;
+use chappy_perforator::shutdown::Shutdown;
+use chappy_util::optional_fields::OptionalFields;
+use chappy_util::test::available_ports;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const TUNNELS: usize = 16;
+const BYTES_PER_TUNNEL: usize = 128 * 1024 * 1024;
+const WRITE_SIZE: usize = 1024 * 1024;
+
+/// Accept the tunnels and count the bytes received until they are closed
+async fn sink_server(listener: TcpListener) -> usize {
+    let mut handles = Vec::with_capacity(TUNNELS);
+    for _ in 0..TUNNELS {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        handles.push(tokio::spawn(async move {
+            let mut buf = vec![0; WRITE_SIZE];
+            let mut total = 0;
+            loop {
+                match stream.read(&mut buf).await.unwrap() {
+                    0 => return total,
+                    n => total += n,
+                }
+            }
+        }));
+    }
+    let mut total = 0;
+    for handle in handles {
+        total += handle.await.unwrap();
+    }
+    total
+}
+
+/// Open a tunnel to the target port and send the payload through it
+async fn send_through_tunnel(fwd: Arc<Forwarder>, target_port: u16) {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let mut cli_stream = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (proxied_stream, _) = listener.accept().await.unwrap();
+    let fwd_handle = tokio::spawn(async move {
+        fwd.forward(
+            proxied_stream,
+            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, fwd.port())),
+            target_port,
+            fwd.server_certificate().to_owned(),
+            FWD_PROTOCOL_VERSION,
+            OptionalFields::default(),
+        )
+        .await
+    });
+    let buf = vec![42; WRITE_SIZE];
+    for _ in 0..BYTES_PER_TUNNEL / WRITE_SIZE {
+        cli_stream.write_all(&buf).await.unwrap();
+    }
+    cli_stream.shutdown().await.unwrap();
+    fwd_handle.await.unwrap();
+}
+
+fn run(shards: usize) -> Duration {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(shards)
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let port = available_ports(1).await[0];
+        let fwd = Arc::new(Forwarder::sharded(
+            port,
+            &TransportProfile::default(),
+            shards,
+        ));
+        let srv_fwd = Arc::clone(&fwd);
+        tokio::spawn(async move { srv_fwd.run_quic_server(&Shutdown::new()).await });
+        let sink = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let sink_port = sink.local_addr().unwrap().port();
+        let sink_handle = tokio::spawn(sink_server(sink));
+
+        let start = Instant::now();
+        let tunnels = (0..TUNNELS)
+            .map(|_| tokio::spawn(send_through_tunnel(Arc::clone(&fwd), sink_port)))
+            .collect::<Vec<_>>();
+        for tunnel in tunnels {
+            tunnel.await.unwrap();
+        }
+        let received = sink_handle.await.unwrap();
+        let elapsed = start.elapsed();
+        assert_eq!(received, TUNNELS * BYTES_PER_TUNNEL);
+        elapsed
+    })
+}
+
+fn main() {
+    let max_shards = std::thread::available_parallelism().unwrap().get();
+    let mut shards = 1;
+    while shards <= max_shards {
+        let elapsed = run(shards);
+        let throughput = (TUNNELS * BYTES_PER_TUNNEL) as f64 / elapsed.as_secs_f64() / 1e6;
+        println!(
+            "shards: {:>2}  tunnels: {}  elapsed: {:>8.2?}  throughput: {:>8.1} MB/s",
+            shards, TUNNELS, elapsed, throughput
+        );
+        shards *= 2;
+    }
+}
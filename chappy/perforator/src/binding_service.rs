@@ -1,31 +1,161 @@
+use crate::conf::VirtualIpAllocation;
+use crate::fwd_protocol::FWD_PROTOCOL_VERSION;
+use crate::identity::Identity;
+use crate::nat_probe::NatProbe;
 use crate::CHAPPY_CONF;
-use chappy_seed::NodeBindingResponse;
 use chappy_seed::{
-    seed_client::SeedClient, ClientBindingRequest, ClientBindingResponse, NodeBindingRequest,
-    ServerBindingRequest, ServerPunchRequest,
+    seed_client::SeedClient, ClientBindingRequest, ClientBindingResponse, ClusterAbort,
+    NodeBindingRequest, PunchReport, PunchStatus, ServerBindingRequest, ServerPunchRequest,
+    UnbindRequest, WaitClusterRequest,
 };
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use chappy_seed::{Address, NodeEvent, NodeEventKind};
+use chappy_util::protocol::LifecycleEvent;
+use chappy_util::tunnel_error::TunnelError;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs};
 use std::os::fd::AsRawFd;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::net::TcpSocket;
 use tokio::sync::mpsc;
+use tokio::sync::watch;
 use tokio::sync::OnceCell;
 use tokio::task::JoinHandle;
+use tokio::time::interval;
 use tonic::transport::{Channel, Endpoint, Uri};
-use tonic::{Response, Status, Streaming};
+use tonic::{Code, Response, Status, Streaming};
 use tower::service_fn;
-use tracing::{debug, error, instrument, Instrument};
+use tracing::{debug, error, instrument, warn, Instrument};
 
 pub struct BindingService {
     p2p_port: u16,
     client_cell: OnceCell<SeedClient<Channel>>,
+    /// One node binding per identity, closed when the node leaves
+    node_bindings: Mutex<HashMap<Identity, NodeBindingHandle>>,
+    /// First binding refused by the seed, e.g. because another node already
+    /// bound the identity
+    rejection: Arc<watch::Sender<Option<Status>>>,
+    /// First abort of a served cluster notified by the seed
+    abort: watch::Sender<Option<ClusterAbort>>,
+}
+
+/// Whether the seed refused the binding because it is inconsistent with the
+/// cluster, in which case retrying cannot help
+fn is_rejection(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::AlreadyExists | Code::FailedPrecondition | Code::ResourceExhausted
+    )
+}
+
+fn record_first_rejection(rejection: &watch::Sender<Option<Status>>, status: &Status) {
+    rejection.send_if_modified(|current| {
+        let first = current.is_none();
+        if first {
+            *current = Some(status.clone());
+        }
+        first
+    });
+}
+
+fn record_rejection(rejection: &watch::Sender<Option<Status>>, status: &Status) {
+    if is_rejection(status) {
+        record_first_rejection(rejection, status);
+    }
+}
+
+/// Request the seed to bind the client, again without the observed mapping
+/// if the seed rejects it
+///
+/// The seed only punches mappings on the IP of the request, which a mapping
+/// probed after a NAT rebinding or through another egress might not match.
+async fn request_client_binding(
+    client: &mut SeedClient<Channel>,
+    req: ClientBindingRequest,
+) -> Result<Response<ClientBindingResponse>, Status> {
+    let observed = req.observed_client_addr.is_some();
+    match client.bind_client(req.clone()).await {
+        Err(status) if observed && status.code() == Code::InvalidArgument => {
+            warn!(%status, "observed mapping rejected, binding without it");
+            let req = ClientBindingRequest {
+                observed_client_addr: None,
+                ..req
+            };
+            client.bind_client(req).await
+        }
+        resp => resp,
+    }
 }
 
 pub struct NodeBindingHandle(
-    JoinHandle<Result<Response<NodeBindingResponse>, Status>>,
+    JoinHandle<Result<(), Status>>,
     mpsc::Sender<NodeBindingRequest>,
 );
 
+/// First request of the node binding of the identity
+fn node_request(identity: &Identity, nat_probe: NatProbe) -> NodeBindingRequest {
+    NodeBindingRequest {
+        cluster_id: identity.cluster_id.clone(),
+        source_virtual_ip: identity.virtual_ip.to_string(),
+        cluster_size: identity.cluster_size,
+        nat_type: nat_probe.nat_type.into(),
+        observed_mappings: nat_probe
+            .mappings
+            .iter()
+            .map(|addr| Address {
+                ip: addr.ip().to_string(),
+                port: addr.port().into(),
+            })
+            .collect(),
+        port_prediction_window: CHAPPY_CONF.port_prediction_window,
+        target_resolution_timeout_ms: CHAPPY_CONF.target_resolution_timeout_ms,
+        heartbeat_interval_ms: CHAPPY_CONF.heartbeat_interval_ms,
+        node_name: CHAPPY_CONF.node_name.clone(),
+        ..Default::default()
+    }
+}
+
+fn event_request(kind: NodeEventKind, exit_code: i32) -> NodeBindingRequest {
+    NodeBindingRequest {
+        event: Some(NodeEvent {
+            kind: kind.into(),
+            exit_code,
+        }),
+        ..Default::default()
+    }
+}
+
+fn lifecycle_request(event: LifecycleEvent) -> NodeBindingRequest {
+    match event {
+        LifecycleEvent::AppStarted => event_request(NodeEventKind::AppStarted, 0),
+        LifecycleEvent::AppExited(code) => event_request(NodeEventKind::AppExited, code),
+        LifecycleEvent::ShuttingDown => event_request(NodeEventKind::ShuttingDown, 0),
+    }
+}
+
+/// Send heartbeats on the node binding until all its strong senders are
+/// dropped
+fn spawn_heartbeats(sender: mpsc::WeakSender<NodeBindingRequest>, period: Duration) {
+    tokio::spawn(
+        async move {
+            let mut ticks = interval(period);
+            // the first tick completes immediately
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                let Some(sender) = sender.upgrade() else {
+                    break;
+                };
+                let req = event_request(NodeEventKind::Heartbeat, 0);
+                if sender.send(req).await.is_err() {
+                    break;
+                }
+            }
+        }
+        .instrument(tracing::Span::current()),
+    );
+}
+
 impl NodeBindingHandle {
     pub async fn close(self) {
         let NodeBindingHandle(handle, sender) = self;
@@ -42,9 +172,48 @@ impl BindingService {
         Self {
             p2p_port,
             client_cell: OnceCell::new(),
+            node_bindings: Mutex::new(HashMap::new()),
+            rejection: Arc::new(watch::channel(None).0),
+            abort: watch::channel(None).0,
         }
     }
 
+    /// Wait for the seed to refuse a binding
+    pub async fn rejected(&self) -> Status {
+        let mut rx = self.rejection.subscribe();
+        let rejection = rx.wait_for(Option::is_some).await.unwrap();
+        rejection.clone().unwrap()
+    }
+
+    /// The binding refused by the seed, if any
+    pub fn rejection(&self) -> Option<Status> {
+        self.rejection.borrow().clone()
+    }
+
+    /// Record the abort notified on a punch request stream, only the first
+    /// one is kept
+    pub fn record_abort(&self, abort: ClusterAbort) {
+        self.abort.send_if_modified(|current| {
+            let first = current.is_none();
+            if first {
+                *current = Some(abort);
+            }
+            first
+        });
+    }
+
+    /// Wait for the seed to abort a served cluster
+    pub async fn aborted(&self) -> ClusterAbort {
+        let mut rx = self.abort.subscribe();
+        let abort = rx.wait_for(Option::is_some).await.unwrap();
+        abort.clone().unwrap()
+    }
+
+    /// The abort notified by the seed, if any
+    pub fn abort(&self) -> Option<ClusterAbort> {
+        self.abort.borrow().clone()
+    }
+
     #[instrument(name = "conn_seed")]
     async fn connect_seed(src_port: u16) -> SeedClient<Channel> {
         let channel = Endpoint::from_shared(format!(
@@ -87,58 +256,347 @@ impl BindingService {
             .clone()
     }
 
-    pub async fn bind_node(&self) -> NodeBindingHandle {
-        debug!("call seed to bind node");
+    /// Open a node binding, returns the virtual IP the seed assigned to it
+    async fn start_binding(
+        &self,
+        req: NodeBindingRequest,
+    ) -> Result<(String, NodeBindingHandle), Status> {
         let (tx, rx) = mpsc::channel::<NodeBindingRequest>(1);
-
-        let mut client = self.client().await;
+        tx.send(req).await.unwrap();
+        let mut responses = self
+            .client()
+            .await
+            .bind_node_v2(tokio_stream::wrappers::ReceiverStream::new(rx))
+            .await?
+            .into_inner();
+        let assigned = responses
+            .message()
+            .await?
+            .ok_or_else(|| Status::internal("binding closed before the assignment"))?
+            .assigned_virtual_ip;
+        let rejection = Arc::clone(&self.rejection);
         // don't use a gracefull spawn here as we manually close the handle
         let handle = tokio::spawn(
             async move {
-                client
-                    .bind_node(tokio_stream::wrappers::ReceiverStream::new(rx))
-                    .await
+                // the seed ends the stream once the node left
+                let res = loop {
+                    match responses.message().await {
+                        Ok(Some(_)) => {}
+                        Ok(None) => break Ok(()),
+                        Err(status) => break Err(status),
+                    }
+                };
+                if let Err(status) = &res {
+                    record_rejection(&rejection, status);
+                }
+                res
             }
             .instrument(tracing::Span::current()),
         );
-        tx.send(NodeBindingRequest {
-            cluster_id: CHAPPY_CONF.cluster_id.clone(),
-            source_virtual_ip: CHAPPY_CONF.virtual_ip.clone(),
-            cluster_size: CHAPPY_CONF.cluster_size,
-        })
-        .await
-        .unwrap();
-        NodeBindingHandle(handle, tx)
+        if CHAPPY_CONF.heartbeat_interval_ms > 0 {
+            let period = Duration::from_millis(CHAPPY_CONF.heartbeat_interval_ms.into());
+            spawn_heartbeats(tx.downgrade(), period);
+        }
+        Ok((assigned, NodeBindingHandle(handle, tx)))
+    }
+
+    /// Bind the identity as a node of its cluster until the bindings are
+    /// closed
+    pub async fn bind_node(&self, identity: &Identity, nat_probe: NatProbe) {
+        debug!("call seed to bind node");
+        match self.start_binding(node_request(identity, nat_probe)).await {
+            Ok((_, handle)) => {
+                self.node_bindings
+                    .lock()
+                    .unwrap()
+                    .insert(identity.clone(), handle);
+            }
+            Err(status) => {
+                error!(%status, "node binding failed");
+                record_rejection(&self.rejection, &status);
+            }
+        }
+    }
+
+    /// Bind the primary identity with a virtual IP allocated by the seed,
+    /// after which all the identities are served. Any failure is recorded as
+    /// a rejection as nothing can be served without the primary identity.
+    pub async fn allocate_node(
+        &self,
+        allocation: &VirtualIpAllocation,
+        nat_probe: NatProbe,
+    ) -> Result<&'static Identity, Status> {
+        debug!(subnet = %allocation.subnet, hint = ?allocation.hint, "call seed to allocate node");
+        let req = NodeBindingRequest {
+            source_virtual_ip: String::new(),
+            virtual_subnet: allocation.subnet.to_string(),
+            virtual_ip_hint: allocation
+                .hint
+                .map(|hint| hint.to_string())
+                .unwrap_or_default(),
+            ..node_request(&allocation.primary, nat_probe)
+        };
+        let res = async {
+            let (assigned, handle) = self.start_binding(req).await?;
+            let virtual_ip = assigned
+                .parse()
+                .map_err(|_| Status::internal(format!("bad assigned virtual IP {}", assigned)))?;
+            let identities = CHAPPY_CONF
+                .assign_virtual_ip(virtual_ip)
+                .map_err(Status::internal)?;
+            self.node_bindings
+                .lock()
+                .unwrap()
+                .insert(identities.primary().clone(), handle);
+            Ok(identities.primary())
+        }
+        .await;
+        if let Err(status) = &res {
+            record_first_rejection(&self.rejection, status);
+        }
+        res
+    }
+
+    /// Forward a lifecycle event of the app to the seed through the node
+    /// binding of the identity
+    pub async fn report_event(
+        &self,
+        identity: &Identity,
+        event: LifecycleEvent,
+    ) -> Result<(), TunnelError> {
+        let sender = self
+            .node_bindings
+            .lock()
+            .unwrap()
+            .get(identity)
+            .map(|handle| handle.1.clone())
+            .ok_or(TunnelError::UnknownIdentity)?;
+        debug!(?event, "report lifecycle event");
+        sender
+            .send(lifecycle_request(event))
+            .await
+            .map_err(|_| TunnelError::UnknownIdentity)
     }
 
-    pub async fn bind_client(&self, target_virtual_ip: String) -> ClientBindingResponse {
+    /// Withdraw the identity from the seed, which stops routing clients to it
+    /// and closes its punch request stream
+    async fn unbind(&self, identity: &Identity) {
+        let resp = self
+            .client()
+            .await
+            .unbind(UnbindRequest {
+                cluster_id: identity.cluster_id.clone(),
+                virtual_ip: identity.virtual_ip.to_string(),
+            })
+            .await;
+        if let Err(err) = resp {
+            error!(%err, virt = %identity.virtual_ip, "unbind failed");
+        }
+    }
+
+    /// Withdraw all the bound identities, then close their node bindings
+    pub async fn deregister(&self) {
+        let bindings = std::mem::take(&mut *self.node_bindings.lock().unwrap());
+        futures::future::join_all(bindings.into_iter().map(|(identity, handle)| async move {
+            handle
+                .1
+                .send(lifecycle_request(LifecycleEvent::ShuttingDown))
+                .await
+                .ok();
+            self.unbind(&identity).await;
+            handle.close().await;
+        }))
+        .await;
+    }
+
+    /// Request the seed to resolve the target and to have it punch a hole
+    /// towards this perforator, at the mapping observed by a NAT probe if
+    /// provided
+    pub async fn bind_client(
+        &self,
+        identity: &Identity,
+        target_virtual_ip: String,
+        observed_client_addr: Option<SocketAddrV4>,
+    ) -> Result<ClientBindingResponse, TunnelError> {
         debug!("call seed to bind client");
+        let req = ClientBindingRequest {
+            cluster_id: identity.cluster_id.clone(),
+            source_virtual_ip: identity.virtual_ip.to_string(),
+            target_virtual_ip,
+            punch_ack_timeout_ms: CHAPPY_CONF.punch_ack_timeout_ms,
+            observed_client_addr: observed_client_addr.map(|addr| Address {
+                ip: addr.ip().to_string(),
+                port: addr.port().into(),
+            }),
+        };
+        let resp = request_client_binding(&mut self.client().await, req).await;
+        match resp {
+            Ok(resp) => Ok(resp.into_inner()),
+            Err(status) => {
+                error!(%status, "cli binding failed");
+                match status.code() {
+                    Code::FailedPrecondition => Err(TunnelError::TargetLeft),
+                    Code::Aborted => Err(TunnelError::ClusterAborted),
+                    // the target is missing or the seed failed to resolve it
+                    _ => Err(TunnelError::TargetUnresolved),
+                }
+            }
+        }
+    }
+
+    /// Wait until all the nodes of the cluster are bound, or until they all
+    /// reached the stage if one is provided
+    pub async fn wait_cluster(
+        &self,
+        identity: &Identity,
+        stage: Option<String>,
+    ) -> Result<(), TunnelError> {
+        debug!("call seed to wait for the cluster");
+        let resp = self
+            .client()
+            .await
+            .wait_cluster(WaitClusterRequest {
+                cluster_id: identity.cluster_id.clone(),
+                virtual_ip: identity.virtual_ip.to_string(),
+                stage: stage.unwrap_or_default(),
+            })
+            .await;
+        match resp {
+            Ok(_) => Ok(()),
+            Err(status) => {
+                error!(%status, "cluster wait failed");
+                match status.code() {
+                    Code::NotFound => Err(TunnelError::UnknownIdentity),
+                    _ => Err(TunnelError::BarrierBroken),
+                }
+            }
+        }
+    }
+
+    /// Let the seed know whether the punch packets could be emitted
+    pub async fn report_punch(&self, punch_id: u64, status: PunchStatus) {
         let resp = self
             .client()
             .await
-            .bind_client(ClientBindingRequest {
-                cluster_id: CHAPPY_CONF.cluster_id.clone(),
-                source_virtual_ip: CHAPPY_CONF.virtual_ip.clone(),
-                target_virtual_ip,
+            .report_punch(PunchReport {
+                punch_id,
+                status: status.into(),
             })
             .await;
-        if let Err(err) = &resp {
-            error!(%err,"cli binding failed");
+        if let Err(err) = resp {
+            error!(%err, "punch report failed");
         }
-        resp.unwrap().into_inner()
     }
 
-    pub async fn bind_server(&self, server_certificate: Vec<u8>) -> Streaming<ServerPunchRequest> {
+    pub async fn bind_server(
+        &self,
+        identity: &Identity,
+        server_certificate: Vec<u8>,
+    ) -> Result<Streaming<ServerPunchRequest>, Status> {
         debug!("call seed to bind server");
-        self.client()
+        let resp = self
+            .client()
             .await
             .bind_server(ServerBindingRequest {
-                cluster_id: CHAPPY_CONF.cluster_id.clone(),
-                virtual_ip: CHAPPY_CONF.virtual_ip.clone(),
+                cluster_id: identity.cluster_id.clone(),
+                virtual_ip: identity.virtual_ip.to_string(),
                 server_certificate,
+                protocol_version: FWD_PROTOCOL_VERSION.into(),
+            })
+            .await;
+        if let Err(status) = &resp {
+            record_rejection(&self.rejection, status);
+        }
+        resp.map(Response::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chappy_seed::seed_server::SeedServer;
+    use chappy_seed::seed_service::SeedService;
+    use chappy_util::test;
+    use tonic::transport::Server;
+
+    /// Serve a seed on localhost and connect to it
+    async fn start_seed() -> (SeedClient<Channel>, JoinHandle<()>) {
+        let seed_port = test::available_ports(1).await[0];
+        let (service, _) = SeedService::new();
+        let seed_handle = tokio::spawn(async move {
+            Server::builder()
+                .add_service(SeedServer::new(service))
+                .serve(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), seed_port))
+                .await
+                .unwrap();
+        });
+        let client = loop {
+            match SeedClient::connect(format!("http://127.0.0.1:{}", seed_port)).await {
+                Ok(client) => break client,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        (client, seed_handle)
+    }
+
+    #[tokio::test]
+    async fn test_legacy_bind_node() {
+        let (mut client, seed_handle) = start_seed().await;
+        let (tx, rx) = mpsc::channel(1);
+        tx.send(NodeBindingRequest {
+            cluster_id: String::from("cluster"),
+            cluster_size: 1,
+            source_virtual_ip: String::from("172.28.0.1"),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        // legacy perforators expect a single response once the binding ends
+        let binding = tokio::spawn(async move {
+            client
+                .bind_node(tokio_stream::wrappers::ReceiverStream::new(rx))
+                .await
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!binding.is_finished());
+        drop(tx);
+        let resp = binding.await.unwrap().unwrap().into_inner();
+        assert_eq!(resp.assigned_virtual_ip, "172.28.0.1");
+        seed_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_observed_mapping_rejected() {
+        let (mut client, seed_handle) = start_seed().await;
+        let _punch_stream = client
+            .bind_server(ServerBindingRequest {
+                cluster_id: String::from("cluster"),
+                virtual_ip: String::from("172.28.0.2"),
+                server_certificate: vec![],
+                protocol_version: FWD_PROTOCOL_VERSION.into(),
             })
             .await
-            .unwrap()
-            .into_inner()
+            .unwrap();
+
+        // the mapping was probed through another egress IP than the one of
+        // the gRPC connection
+        let req = ClientBindingRequest {
+            cluster_id: String::from("cluster"),
+            source_virtual_ip: String::from("172.28.0.1"),
+            target_virtual_ip: String::from("172.28.0.2"),
+            punch_ack_timeout_ms: 0,
+            observed_client_addr: Some(Address {
+                ip: String::from("127.0.0.2"),
+                port: 5000,
+            }),
+        };
+        let status = client.bind_client(req.clone()).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        let resp = request_client_binding(&mut client, req)
+            .await
+            .expect("binding should be retried without the observed mapping")
+            .into_inner();
+        assert!(resp.target_nated_addr.is_some());
+        seed_handle.abort();
     }
 }
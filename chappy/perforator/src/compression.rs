@@ -0,0 +1,147 @@
+use crate::conf::parse_port_map;
+use crate::fwd_protocol::LZ4_COMPRESSION;
+use std::collections::HashMap;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Size of the blocks compressed independently
+pub const BLOCK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "lz4" => Ok(Self::Lz4),
+            _ => Err(format!("unknown compression {}", s)),
+        }
+    }
+}
+
+/// Compression of the tunneled streams according to their target port
+///
+/// A stream is only compressed if the policies of both the client and the
+/// target perforators enable it for the target port.
+#[derive(Clone, Debug, Default)]
+pub struct CompressionPolicy {
+    pub default: Compression,
+    /// Overrides of the default, e.g. to opt out for ports that serve already
+    /// compressed data
+    pub ports: HashMap<u16, Compression>,
+}
+
+impl CompressionPolicy {
+    /// Parse overrides formatted as `port=compression,port=compression`
+    pub fn with_ports(default: Compression, ports: &str) -> Result<Self, String> {
+        Ok(Self {
+            default,
+            ports: parse_port_map(ports)?,
+        })
+    }
+
+    pub fn for_port(&self, port: u16) -> Compression {
+        self.ports.get(&port).copied().unwrap_or(self.default)
+    }
+
+    /// Capability bits enabled by the policy for the port
+    pub fn capabilities(&self, port: u16) -> u32 {
+        match self.for_port(port) {
+            Compression::None => 0,
+            Compression::Lz4 => LZ4_COMPRESSION,
+        }
+    }
+}
+
+/// Encode a block of at most BLOCK_SIZE bytes
+///
+/// The block is prefixed by its raw length and its payload length. The payload
+/// is only compressed if that makes it smaller, so a payload with the raw
+/// length is stored as is.
+pub fn encode_block(raw: &[u8]) -> Vec<u8> {
+    let compressed = lz4_flex::block::compress(raw);
+    let payload = if compressed.len() < raw.len() {
+        &compressed[..]
+    } else {
+        raw
+    };
+    let mut block = Vec::with_capacity(8 + payload.len());
+    block.extend_from_slice(&(raw.len() as u32).to_be_bytes());
+    block.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    block.extend_from_slice(payload);
+    block
+}
+
+fn invalid_block(msg: &str) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, msg)
+}
+
+/// Read and decode the next block into `raw`, returns false once the stream
+/// is finished
+pub async fn read_block<R: AsyncRead + Unpin>(recv: &mut R, raw: &mut Vec<u8>) -> IoResult<bool> {
+    let raw_len = match recv.read_u32().await {
+        Ok(len) => len as usize,
+        Err(err) if err.kind() == IoErrorKind::UnexpectedEof => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    let payload_len = recv.read_u32().await? as usize;
+    if raw_len > BLOCK_SIZE || payload_len > raw_len {
+        return Err(invalid_block("block too large"));
+    }
+    let mut payload = vec![0; payload_len];
+    recv.read_exact(&mut payload).await?;
+    if payload_len == raw_len {
+        *raw = payload;
+    } else {
+        raw.resize(raw_len, 0);
+        let len = lz4_flex::block::decompress_into(&payload, raw)
+            .map_err(|_| invalid_block("corrupted block"))?;
+        if len != raw_len {
+            return Err(invalid_block("block shorter than announced"));
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_blocks_roundtrip() {
+        let compressible = "{\"key\": \"value\"}".repeat(1000).into_bytes();
+        let random = (0..1000).map(|_| rand::random()).collect::<Vec<u8>>();
+        let mut stream = encode_block(&compressible);
+        assert!(stream.len() < compressible.len());
+        // incompressible data is stored as is
+        let stored = encode_block(&random);
+        assert_eq!(stored.len(), random.len() + 8);
+        stream.extend(stored);
+
+        let mut recv = stream.as_slice();
+        let mut raw = vec![];
+        assert!(read_block(&mut recv, &mut raw).await.unwrap());
+        assert_eq!(raw, compressible);
+        assert!(read_block(&mut recv, &mut raw).await.unwrap());
+        assert_eq!(raw, random);
+        assert!(!read_block(&mut recv, &mut raw).await.unwrap());
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy =
+            CompressionPolicy::with_ports(Compression::Lz4, "8080=none, 9000=lz4").unwrap();
+        assert_eq!(policy.capabilities(8080), 0);
+        assert_eq!(policy.capabilities(9000), LZ4_COMPRESSION);
+        assert_eq!(policy.capabilities(80), LZ4_COMPRESSION);
+        CompressionPolicy::with_ports(Compression::None, "8080=gzip").unwrap_err();
+    }
+}
@@ -1,24 +1,245 @@
+use crate::compression::CompressionPolicy;
+use crate::identity::{Identities, Identity};
+use crate::quic_utils::TransportProfile;
+use crate::shaping::ShapingPolicy;
+use crate::sharding::MAX_SHARDS;
+use ipnet::Ipv4Net;
+use std::collections::{HashMap, HashSet};
 use std::env::var;
+use std::fmt::{Debug, Display};
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// Parse the variable if it is defined
+fn parse_var<T: FromStr>(key: &str) -> Option<T>
+where
+    T::Err: Debug,
+{
+    var(key).ok().map(|v| v.parse().unwrap())
+}
+
+/// Parse values by port formatted as `port=value,port=value`
+pub(crate) fn parse_port_map<T: FromStr>(values: &str) -> Result<HashMap<u16, T>, String>
+where
+    T::Err: Display,
+{
+    values
+        .split(',')
+        .filter(|v| !v.trim().is_empty())
+        .map(|v| {
+            let (port, value) = v
+                .split_once('=')
+                .ok_or_else(|| format!("expected port=value, got {}", v))?;
+            let port = port
+                .trim()
+                .parse()
+                .map_err(|_| format!("bad port {}", port))?;
+            let value = value
+                .trim()
+                .parse()
+                .map_err(|err| format!("bad value for port {}: {}", port, err))?;
+            Ok((port, value))
+        })
+        .collect()
+}
+
+/// Parse a number of forwarder shards, each shard owning a runtime thread
+/// and a range of connection IDs
+pub(crate) fn parse_shards(value: &str) -> Result<usize, String> {
+    value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|shards| (1..=MAX_SHARDS).contains(shards))
+        .ok_or_else(|| {
+            format!(
+                "CHAPPY_FORWARDER_SHARDS should be between 1 and {}, got {}",
+                MAX_SHARDS, value
+            )
+        })
+}
+
+/// Primary identity to be assigned a virtual IP by the seed, used when
+/// CHAPPY_VIRTUAL_IP is not set
+pub struct VirtualIpAllocation {
+    /// The virtual IP is unspecified until assigned
+    pub primary: Identity,
+    pub subnet: Ipv4Net,
+    /// Assigned if it is still free in the subnet
+    pub hint: Option<Ipv4Addr>,
+    others: Vec<Identity>,
+}
 
 pub struct ChappyConf {
-    pub cluster_id: String,
-    pub cluster_size: u32,
+    pub allocation: Option<VirtualIpAllocation>,
+    pub compression_policy: CompressionPolicy,
     pub connection_timeout_ms: u64,
+    pub exposed_ports: Option<HashSet<u16>>,
+    /// Number of QUIC endpoints and runtime threads, 1 for a single threaded
+    /// runtime
+    pub forwarder_shards: usize,
+    /// Period of the heartbeats sent on node bindings, 0 to disable them
+    pub heartbeat_interval_ms: u32,
+    /// Virtual IPs and clusters served by this perforator, connections
+    /// between identities of the same cluster are spliced locally
+    identities: OnceLock<Identities>,
+    /// Name reported to the watchers of the clusters, e.g. the invocation ID
+    pub node_name: String,
+    pub port_prediction_window: u32,
+    pub punch_ack_timeout_ms: u32,
     pub seed_hostname: String,
     pub seed_port: String,
-    pub virtual_ip: String,
+    pub seed_nat_probe_port: Option<u16>,
+    pub shaping_policy: ShapingPolicy,
+    /// How long clients of the cluster wait for targets to register, 0 to
+    /// use the seed default
+    pub target_resolution_timeout_ms: u32,
+    pub transport_profile: TransportProfile,
 }
 
 impl ChappyConf {
     pub(crate) fn load() -> Self {
+        let (identities, allocation) = Self::load_identities();
         Self {
-            cluster_id: var("CHAPPY_CLUSTER_ID").unwrap_or_else(|_| String::from("default")),
-            cluster_size: var("CHAPPY_CLUSTER_SIZE").unwrap().parse().unwrap(),
+            allocation,
+            compression_policy: CompressionPolicy::with_ports(
+                parse_var("CHAPPY_COMPRESSION").unwrap_or_default(),
+                &var("CHAPPY_COMPRESSION_PORTS").unwrap_or_default(),
+            )
+            .unwrap(),
             connection_timeout_ms: 3000,
+            exposed_ports: var("CHAPPY_EXPOSED_PORTS").ok().map(|ports| {
+                ports
+                    .split(',')
+                    .map(|p| p.trim().parse().unwrap())
+                    .collect()
+            }),
+            forwarder_shards: var("CHAPPY_FORWARDER_SHARDS")
+                .map(|v| parse_shards(&v).unwrap())
+                .unwrap_or(1),
+            heartbeat_interval_ms: parse_var("CHAPPY_HEARTBEAT_INTERVAL_MS").unwrap_or(1000),
+            identities,
+            node_name: var("CHAPPY_NODE_NAME").unwrap_or_default(),
+            port_prediction_window: var("CHAPPY_PORT_PREDICTION_WINDOW")
+                .map(|v| v.parse().unwrap())
+                .unwrap_or(0),
+            punch_ack_timeout_ms: var("CHAPPY_PUNCH_ACK_TIMEOUT_MS")
+                .map(|v| v.parse().unwrap())
+                .unwrap_or(1000),
             seed_hostname: var("CHAPPY_SEED_HOSTNAME").unwrap(),
 
             seed_port: var("CHAPPY_SEED_PORT").unwrap(),
-            virtual_ip: var("CHAPPY_VIRTUAL_IP").unwrap(),
+            seed_nat_probe_port: var("CHAPPY_SEED_NAT_PROBE_PORT")
+                .ok()
+                .map(|p| p.parse().unwrap()),
+            shaping_policy: ShapingPolicy::with_ports(
+                &var("CHAPPY_PORT_PRIORITIES").unwrap_or_default(),
+                &var("CHAPPY_PORT_RATE_LIMITS").unwrap_or_default(),
+            )
+            .unwrap(),
+            target_resolution_timeout_ms: parse_var("CHAPPY_TARGET_RESOLUTION_TIMEOUT_MS")
+                .unwrap_or(0),
+            transport_profile: Self::load_transport_profile(),
+        }
+    }
+
+    /// Served identities, panics if the primary virtual IP was not assigned
+    /// yet
+    pub fn identities(&self) -> &Identities {
+        self.identities
+            .get()
+            .expect("primary virtual IP not assigned")
+    }
+
+    /// Serve the identities once the seed assigned the primary virtual IP
+    pub fn assign_virtual_ip(&self, virtual_ip: Ipv4Addr) -> Result<&Identities, String> {
+        let allocation = self
+            .allocation
+            .as_ref()
+            .ok_or("the primary virtual IP is static")?;
+        let primary = Identity {
+            virtual_ip,
+            ..allocation.primary.clone()
+        };
+        let identities = Identities::new(primary, allocation.others.clone())?;
+        self.identities
+            .set(identities)
+            .map_err(|_| "the primary virtual IP was already assigned")?;
+        Ok(self.identities())
+    }
+
+    /// The primary identity from CHAPPY_VIRTUAL_IP, CHAPPY_CLUSTER_ID and
+    /// CHAPPY_CLUSTER_SIZE, then the comma separated CHAPPY_LOCAL_VIRTUAL_IPS
+    /// formatted as `ip` or `ip@cluster_id:cluster_size`. Without
+    /// CHAPPY_VIRTUAL_IP, the primary one is allocated by the seed from
+    /// CHAPPY_VIRTUAL_SUBNET, preferably CHAPPY_VIRTUAL_IP_HINT.
+    fn load_identities() -> (OnceLock<Identities>, Option<VirtualIpAllocation>) {
+        let mut primary = Identity {
+            virtual_ip: Ipv4Addr::UNSPECIFIED,
+            cluster_id: var("CHAPPY_CLUSTER_ID").unwrap_or_else(|_| String::from("default")),
+            cluster_size: var("CHAPPY_CLUSTER_SIZE").unwrap().parse().unwrap(),
+        };
+        let others = var("CHAPPY_LOCAL_VIRTUAL_IPS")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| Identity::parse(entry, &primary).unwrap())
+            .collect();
+        let identities = OnceLock::new();
+        match parse_var("CHAPPY_VIRTUAL_IP") {
+            Some(virtual_ip) => {
+                primary.virtual_ip = virtual_ip;
+                identities
+                    .set(Identities::new(primary, others).unwrap())
+                    .unwrap();
+                (identities, None)
+            }
+            None => {
+                let allocation = VirtualIpAllocation {
+                    primary,
+                    subnet: parse_var("CHAPPY_VIRTUAL_SUBNET")
+                        .expect("CHAPPY_VIRTUAL_IP or CHAPPY_VIRTUAL_SUBNET required"),
+                    hint: parse_var("CHAPPY_VIRTUAL_IP_HINT"),
+                    others,
+                };
+                (identities, Some(allocation))
+            }
         }
     }
+
+    /// Override the default transport parameters with the CHAPPY_QUIC_*
+    /// variables
+    fn load_transport_profile() -> TransportProfile {
+        let default = TransportProfile::default();
+        TransportProfile {
+            stream_receive_window: parse_var("CHAPPY_QUIC_STREAM_WINDOW")
+                .unwrap_or(default.stream_receive_window),
+            receive_window: parse_var("CHAPPY_QUIC_CONNECTION_WINDOW")
+                .unwrap_or(default.receive_window),
+            send_window: parse_var("CHAPPY_QUIC_SEND_WINDOW").unwrap_or(default.send_window),
+            congestion_controller: parse_var("CHAPPY_QUIC_CONGESTION_CONTROLLER")
+                .unwrap_or(default.congestion_controller),
+            mtu_discovery: parse_var("CHAPPY_QUIC_MTU_DISCOVERY").unwrap_or(default.mtu_discovery),
+            keep_alive_ms: parse_var("CHAPPY_QUIC_KEEP_ALIVE_MS").unwrap_or(default.keep_alive_ms),
+            idle_timeout_ms: parse_var("CHAPPY_QUIC_IDLE_TIMEOUT_MS")
+                .unwrap_or(default.idle_timeout_ms),
+            silence_timeout_ms: parse_var("CHAPPY_QUIC_SILENCE_TIMEOUT_MS")
+                .unwrap_or(default.silence_timeout_ms),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_shards() {
+        assert_eq!(parse_shards("4"), Ok(4));
+        assert_eq!(parse_shards("256"), Ok(256));
+        assert!(parse_shards("0").is_err());
+        assert!(parse_shards("257").is_err());
+        assert!(parse_shards("many").is_err());
+    }
 }
@@ -1,16 +1,143 @@
-use crate::fwd_protocol::{copy, InitQuery, InitResponse};
-use crate::spawn::spawn_task;
-use crate::{quic_utils, shutdown::Shutdown, PUNCH_SERVER_NAME, SERVER_NAME};
+use crate::compression::CompressionPolicy;
+use crate::fwd_protocol::{
+    pipe, InitQuery, InitResponse, StreamOptions, LZ4_COMPRESSION, MULTIPLEXED_TUNNELS_VERSION,
+    UDP_DATAGRAMS_VERSION,
+};
+use crate::nat_probe::ProbingSocket;
+use crate::quic_utils::{self, TransportProfile};
+use crate::shaping::ShapingPolicy;
+use crate::sharding::{self, ShardedCidGenerator};
+use crate::shutdown::{Cancelled, Shutdown, ShutdownGuard};
+use crate::spawn::{spawn_task, spawn_tunnel_task};
+use crate::udp_relay::{self, ReplyRoutes};
+use crate::{PUNCH_SERVER_NAME, SERVER_NAME};
 use anyhow::{anyhow, Result};
+use chappy_util::optional_fields::OptionalFields;
 use chappy_util::tcp_connect::connect_retry;
-use quinn::{Connection, ConnectionError, Endpoint};
+use chappy_util::tunnel_error::TunnelError;
+use futures::stream::FuturesUnordered;
+use futures::{Future, StreamExt, TryFutureExt};
+use quinn::{
+    Connecting, Connection, ConnectionError, Endpoint, RecvStream, Runtime, SendStream,
+    TransportConfig, VarInt,
+};
 use quinn_proto::{TransportError, TransportErrorCode};
 use rustls::AlertDescription::UnknownCA;
+use std::collections::{HashMap, HashSet};
+use std::io::{ErrorKind as IoErrorKind, Result as IoResult};
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::net::TcpStream;
-use tracing::{debug, debug_span, error, info, instrument, trace, Instrument};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::broadcast;
+use tokio::task::{AbortHandle, JoinHandle};
+use tracing::{debug, debug_span, error, info, instrument, trace, warn, Instrument};
+
+/// Silent targets published before the subscribers lag
+const SILENT_PEERS_CAPACITY: usize = 64;
+
+/// Application code of the connections closed by a shutting down server
+const SHUTDOWN_CLOSE_CODE: u32 = 0;
+
+/// Bound on the connection to a local target, including the retries
+const TARGET_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Task bound to a client connection, stopped along with its owner
+#[derive(Debug)]
+struct ConnectionTask(AbortHandle);
+
+impl Drop for ConnectionTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Single reader of the datagrams of a client connection, which sends the
+/// replies back through the relays
+#[derive(Debug)]
+struct ReplyRouter {
+    routes: ReplyRoutes,
+    _task: ConnectionTask,
+}
+
+impl ReplyRouter {
+    fn spawn(conn: Connection) -> Self {
+        let routes = ReplyRoutes::default();
+        let route_fut = udp_relay::route_replies(conn, Arc::clone(&routes)).in_current_span();
+        Self {
+            routes,
+            _task: ConnectionTask(tokio::spawn(route_fut).abort_handle()),
+        }
+    }
+}
+
+/// Client connection shared by the tunnels towards a target
+#[derive(Debug)]
+struct SharedConnection {
+    conn: Connection,
+    tunnels: usize,
+    /// Single watcher for all the tunnels of the connection
+    _watcher: ConnectionTask,
+    /// Started along with the first datagram relay of the connection
+    replies: Option<ReplyRouter>,
+}
+
+/// Client connections shared by the tunnels towards a target
+type SharedConnections = Arc<Mutex<HashMap<SocketAddr, SharedConnection>>>;
+
+/// Use of a client connection by a tunnel or a datagram relay
+///
+/// A shared connection is forgotten once its last tunnel ends, so that it is
+/// closed like the connections dedicated to a single tunnel. The path of a
+/// dedicated connection is watched by its lease.
+struct ConnectionLease {
+    conn: Connection,
+    nated_addr: SocketAddr,
+    shared: Option<SharedConnections>,
+    _watcher: Option<ConnectionTask>,
+    replies: Option<ReplyRouter>,
+}
+
+impl ConnectionLease {
+    /// Routes of the replies received on the connection, all read by the
+    /// same task
+    fn reply_routes(&mut self) -> ReplyRoutes {
+        if let Some(shared) = &self.shared {
+            let mut conns = shared.lock().unwrap();
+            let entry = conns
+                .get_mut(&self.nated_addr)
+                .filter(|entry| entry.conn.stable_id() == self.conn.stable_id());
+            if let Some(entry) = entry {
+                let router = entry
+                    .replies
+                    .get_or_insert_with(|| ReplyRouter::spawn(self.conn.clone()));
+                return Arc::clone(&router.routes);
+            }
+        }
+        let router = self
+            .replies
+            .get_or_insert_with(|| ReplyRouter::spawn(self.conn.clone()));
+        Arc::clone(&router.routes)
+    }
+}
+
+impl Drop for ConnectionLease {
+    fn drop(&mut self) {
+        let Some(shared) = &self.shared else {
+            return;
+        };
+        let mut conns = shared.lock().unwrap();
+        if let Some(entry) = conns.get_mut(&self.nated_addr) {
+            if entry.conn.stable_id() == self.conn.stable_id() {
+                entry.tunnels -= 1;
+                if entry.tunnels == 0 {
+                    conns.remove(&self.nated_addr);
+                }
+            }
+        }
+    }
+}
 
 /// A service relays TCP streams through a QUIC tunnel
 ///
@@ -24,106 +151,437 @@ use tracing::{debug, debug_span, error, info, instrument, trace, Instrument};
 /// QUIC connection would actually be fairly complex.
 #[derive(Debug)]
 pub struct Forwarder {
-    quic_endpoint: Endpoint,
+    /// Endpoints sharing the forwarder port, each of them owning a shard of
+    /// the connections
+    quic_endpoints: Vec<Endpoint>,
+    /// Socket of the first endpoint, used to probe the mapping of the port
+    probing_socket: ProbingSocket,
+    next_endpoint: AtomicUsize,
     port: u16,
     server_certificate_der: Vec<u8>,
+    /// Target ports that tunnels can reach, all of them if undefined
+    exposed_ports: Option<Arc<HashSet<u16>>>,
+    /// Shared by the server and client connections of the endpoint
+    transport: Arc<TransportConfig>,
+    compression: Arc<CompressionPolicy>,
+    shaping: Arc<ShapingPolicy>,
+    silence_timeout: Duration,
+    /// Targets of the client connections that went silent
+    silent_peers: broadcast::Sender<SocketAddr>,
+    /// Client connections to the targets that accept multiple tunnels, so
+    /// that the priorities of their streams apply
+    shared_conns: SharedConnections,
 }
 
 impl Forwarder {
-    fn create_quic_endpoint(
+    /// Create one endpoint per shard, all bound to the same port
+    ///
+    /// With multiple shards, a reuseport filter routes the datagrams to the
+    /// endpoint that issued their destination connection ID.
+    fn create_quic_endpoints(
         port: u16,
+        shards: usize,
         server_certificate_der: Vec<u8>,
         private_key_der: Vec<u8>,
-    ) -> Endpoint {
-        // configure socket
-        let sock = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, None).unwrap();
-        sock.set_reuse_port(true).unwrap();
-        let src_addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port);
-        sock.bind(&src_addr.into()).unwrap();
-
-        let server_config = quic_utils::configure_server(server_certificate_der, private_key_der);
-        quinn::Endpoint::new(
+        transport: Arc<TransportConfig>,
+    ) -> (Vec<Endpoint>, ProbingSocket) {
+        // configure sockets, the bind order defines their index in the group
+        let socks = (0..shards)
+            .map(|_| {
+                let sock = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, None)
+                    .unwrap();
+                sock.set_reuse_port(true).unwrap();
+                let src_addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port);
+                sock.bind(&src_addr.into()).unwrap();
+                sock
+            })
+            .collect::<Vec<_>>();
+        if shards > 1 {
+            sharding::attach_reuseport_filter(&socks[0], shards).unwrap();
+        }
+
+        let server_config =
+            quic_utils::configure_server(server_certificate_der, private_key_der, transport);
+        let runtime = Arc::new(quinn::TokioRuntime);
+        let mut probing_socket: Option<ProbingSocket> = None;
+        let endpoints = socks
+            .into_iter()
+            .enumerate()
+            .map(|(shard, sock)| {
+                let mut endpoint_config = quinn::EndpointConfig::default();
+                endpoint_config
+                    .cid_generator(move || Box::new(ShardedCidGenerator::new(shard, shards)));
+                let socket = runtime.wrap_udp_socket(sock.into()).unwrap();
+                let socket = match &probing_socket {
+                    Some(first) => first.sibling(socket),
+                    None => probing_socket.insert(ProbingSocket::new(socket)).clone(),
+                };
+                quinn::Endpoint::new_with_abstract_socket(
+                    endpoint_config,
+                    Some(server_config.clone()),
+                    socket,
+                    runtime.clone(),
+                )
+                .unwrap()
+            })
+            .collect();
+        (endpoints, probing_socket.unwrap())
+    }
+
+    /// Create a forwarder on top of a custom socket, e.g. a NAT emulator
+    #[cfg(test)]
+    pub(crate) fn with_socket(socket: impl quinn::AsyncUdpSocket) -> Self {
+        let cert = rcgen::generate_simple_self_signed(vec![SERVER_NAME.into()]).unwrap();
+        let server_certificate_der = cert.serialize_der().unwrap();
+        let private_key_der = cert.serialize_private_key_der();
+        let port = socket.local_addr().unwrap().port();
+        let profile = TransportProfile::default();
+        let transport = Arc::new(profile.transport_config());
+        let server_config = quic_utils::configure_server(
+            server_certificate_der.clone(),
+            private_key_der,
+            Arc::clone(&transport),
+        );
+        let probing_socket = ProbingSocket::new(Box::new(socket));
+        let quic_endpoint = quinn::Endpoint::new_with_abstract_socket(
             quinn::EndpointConfig::default(),
             Some(server_config),
-            sock.into(),
+            probing_socket.clone(),
             Arc::new(quinn::TokioRuntime),
         )
-        .unwrap()
+        .unwrap();
+        Self {
+            quic_endpoints: vec![quic_endpoint],
+            probing_socket,
+            next_endpoint: AtomicUsize::new(0),
+            port,
+            server_certificate_der,
+            exposed_ports: None,
+            transport,
+            compression: Arc::default(),
+            shaping: Arc::default(),
+            silence_timeout: Duration::from_millis(profile.silence_timeout_ms),
+            silent_peers: broadcast::channel(SILENT_PEERS_CAPACITY).0,
+            shared_conns: SharedConnections::default(),
+        }
     }
 
-    pub fn new(port: u16) -> Self {
+    pub fn new(port: u16, transport_profile: &TransportProfile) -> Self {
+        Self::sharded(port, transport_profile, 1)
+    }
+
+    /// Spread the connections over multiple endpoints, so that they can be
+    /// driven by different threads of a multi-threaded runtime
+    pub fn sharded(port: u16, transport_profile: &TransportProfile, shards: usize) -> Self {
         let cert = rcgen::generate_simple_self_signed(vec![SERVER_NAME.into()]).unwrap();
         let server_certificate_der = cert.serialize_der().unwrap();
         let private_key_der = cert.serialize_private_key_der();
+        let transport = Arc::new(transport_profile.transport_config());
+        let (quic_endpoints, probing_socket) = Self::create_quic_endpoints(
+            port,
+            shards,
+            server_certificate_der.clone(),
+            private_key_der,
+            Arc::clone(&transport),
+        );
 
         Self {
-            quic_endpoint: Self::create_quic_endpoint(
-                port,
-                server_certificate_der.clone(),
-                private_key_der,
-            ),
+            quic_endpoints,
+            probing_socket,
+            next_endpoint: AtomicUsize::new(0),
             port,
             server_certificate_der,
+            exposed_ports: None,
+            transport,
+            compression: Arc::default(),
+            shaping: Arc::default(),
+            silence_timeout: Duration::from_millis(transport_profile.silence_timeout_ms),
+            silent_peers: broadcast::channel(SILENT_PEERS_CAPACITY).0,
+            shared_conns: SharedConnections::default(),
+        }
+    }
+
+    /// Pick the endpoint of the next client connection, in a round robin way
+    fn client_endpoint(&self) -> &Endpoint {
+        let next = self.next_endpoint.fetch_add(1, Ordering::Relaxed);
+        &self.quic_endpoints[next % self.quic_endpoints.len()]
+    }
+
+    /// Only accept tunnels towards the provided target ports
+    pub fn expose_ports(mut self, ports: HashSet<u16>) -> Self {
+        self.exposed_ports = Some(Arc::new(ports));
+        self
+    }
+
+    /// Compress the tunneled streams according to the policy
+    pub fn compression_policy(mut self, policy: CompressionPolicy) -> Self {
+        self.compression = Arc::new(policy);
+        self
+    }
+
+    /// Prioritize and rate limit the tunneled streams according to the policy
+    pub fn shaping_policy(mut self, policy: ShapingPolicy) -> Self {
+        self.shaping = Arc::new(policy);
+        self
+    }
+
+    /// Options of the streams towards the target port
+    fn stream_options(
+        shaping: &ShapingPolicy,
+        target_port: u16,
+        compressed: bool,
+    ) -> StreamOptions {
+        StreamOptions {
+            compressed,
+            priority: shaping.priority(target_port),
+            rate_limit: shaping.rate_limit(target_port),
         }
     }
 
-    /// Accept one bi QUIC stream, decode the target_port and forward the rest
-    /// of the stream to localhost:target_port
+    /// Receive the targets of the client connections that went silent, the
+    /// path towards them should be punched again
+    pub fn subscribe_silent_peers(&self) -> broadcast::Receiver<SocketAddr> {
+        self.silent_peers.subscribe()
+    }
+
+    /// Publish the target of the client connection if nothing was received
+    /// from it during the silence timeout, e.g. because the NAT mapping of this
+    /// node changed and the NAT of the target drops the migrated packets
     ///
-    /// Panic if receives a second bi on the connection
-    async fn handle_srv_conn(conn: Connection) {
-        let (mut quic_send, mut quic_recv) = match conn.accept_bi().await {
-            Ok(streams) => {
-                trace!("new bi accepted");
-                streams
-            }
-            Err(e) => {
-                info!("connection ended: {}", e);
-                return;
+    /// The keepalives should be acknowledged well within the silence timeout.
+    /// A silent target is published again after each silence timeout. Never
+    /// completes, even once the connection is closed.
+    fn watch_path(&self, conn: Connection) -> impl Future<Output = ()> + 'static {
+        let silence_timeout = self.silence_timeout;
+        let silent_peers = self.silent_peers.clone();
+        async move {
+            let mut received = conn.stats().udp_rx.datagrams;
+            let mut last_received = Instant::now();
+            while conn.close_reason().is_none() {
+                tokio::time::sleep(silence_timeout / 4).await;
+                let now_received = conn.stats().udp_rx.datagrams;
+                if now_received != received {
+                    received = now_received;
+                    last_received = Instant::now();
+                } else if last_received.elapsed() >= silence_timeout {
+                    warn!(tgt_nat = %conn.remote_address(), "target silent, punching again");
+                    // no subscriber is not an error
+                    silent_peers.send(conn.remote_address()).ok();
+                    last_received = Instant::now();
+                }
             }
-        };
-        let query = InitQuery::read(&mut quic_recv).await;
-        debug!(?query, "init query read");
+            futures::future::pending().await
+        }
+    }
 
-        // forwarding connection
+    /// Watch the path of the connection until the returned watcher is dropped
+    fn spawn_path_watcher(&self, conn: Connection) -> ConnectionTask {
+        let watch_fut = self.watch_path(conn).in_current_span();
+        ConnectionTask(tokio::spawn(watch_fut).abort_handle())
+    }
 
-        // TODO: make timeout configurable according to expected target startup
-        // duration
-        let fwd_stream = match connect_retry(
-            (Ipv4Addr::LOCALHOST, query.target_port),
+    /// Connect to the local target, reporting failures as tunnel errors
+    async fn connect_target(
+        target_port: u16,
+        exposed_ports: Option<&HashSet<u16>>,
+    ) -> Result<TcpStream, TunnelError> {
+        if exposed_ports.is_some_and(|ports| !ports.contains(&target_port)) {
+            error!(target_port, "target port not exposed");
+            return Err(TunnelError::PortNotExposed);
+        }
+        // TODO: make timeouts configurable according to expected target
+        // startup duration
+        let connect_fut = connect_retry(
+            (Ipv4Addr::LOCALHOST, target_port),
             Duration::from_millis(500),
-        )
-        .await
-        {
-            Ok(stream) => {
-                InitResponse { code: 0 }.write(&mut quic_send).await;
-                stream
+        );
+        // refusals are retried for 500ms, unanswered connections are bounded
+        // by the overall timeout
+        match tokio::time::timeout(TARGET_CONNECT_TIMEOUT, connect_fut).await {
+            Ok(Ok(stream)) => Ok(stream),
+            Ok(Err(err)) => {
+                error!(err=%err, "connection to target failed");
+                match err.kind() {
+                    IoErrorKind::TimedOut => Err(TunnelError::TargetTimedOut),
+                    _ => Err(TunnelError::TargetRefused),
+                }
+            }
+            Err(_) => {
+                error!("connection to target timed out");
+                Err(TunnelError::TargetTimedOut)
+            }
+        }
+    }
+
+    /// Check that a local target port accepts connections, the equivalent of
+    /// `try_target` for virtual IPs served by this node
+    pub async fn probe_local(&self, target_port: u16) -> Result<(), TunnelError> {
+        Self::connect_target(target_port, self.exposed_ports.as_deref())
+            .await
+            .map(|_| ())
+    }
+
+    /// Relay a TCP stream to a local target port without any tunnel, for
+    /// connections between virtual IPs served by this node
+    #[instrument(name = "splice_local", skip(self, tcp_stream))]
+    pub async fn splice_local(
+        &self,
+        mut tcp_stream: TcpStream,
+        target_port: u16,
+    ) -> Result<(), TunnelError> {
+        let mut target_stream =
+            match Self::connect_target(target_port, self.exposed_ports.as_deref()).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    // the client already thinks it is connected
+                    tcp_stream.set_linger(Some(Duration::ZERO)).ok();
+                    return Err(err);
+                }
+            };
+        match tokio::io::copy_bidirectional(&mut tcp_stream, &mut target_stream).await {
+            Ok((sent, received)) => debug!(sent, received, "completed"),
+            Err(err) => {
+                debug!(%err, "resetting connection");
+                tcp_stream.set_linger(Some(Duration::ZERO)).ok();
+                target_stream.set_linger(Some(Duration::ZERO)).ok();
+            }
+        }
+        Ok(())
+    }
+
+    /// Relay the datagrams and the bi QUIC streams received on the connection
+    async fn handle_srv_conn(
+        conn: Connection,
+        exposed_ports: Option<Arc<HashSet<u16>>>,
+        compression: Arc<CompressionPolicy>,
+        shaping: Arc<ShapingPolicy>,
+        shutdown_guard: ShutdownGuard,
+    ) {
+        tokio::join!(
+            Self::handle_srv_bis(
+                conn.clone(),
+                exposed_ports.clone(),
+                compression,
+                shaping,
+                shutdown_guard
+            ),
+            udp_relay::relay_server(conn, exposed_ports),
+        );
+    }
+
+    /// Accept the bi QUIC streams of the connection, one per tunnel, until the
+    /// connection is closed
+    ///
+    /// On shutdown, the open tunnels are drained before the connection is
+    /// closed, which also ends its datagram relay.
+    async fn handle_srv_bis(
+        conn: Connection,
+        exposed_ports: Option<Arc<HashSet<u16>>>,
+        compression: Arc<CompressionPolicy>,
+        shaping: Arc<ShapingPolicy>,
+        mut shutdown_guard: ShutdownGuard,
+    ) {
+        let mut tunnels = FuturesUnordered::new();
+        let shutdown_fut = shutdown_guard.wait_shutdown();
+        tokio::pin!(shutdown_fut);
+        loop {
+            tokio::select! {
+                _ = &mut shutdown_fut => {
+                    debug!(tunnels = tunnels.len(), "draining tunnels");
+                    while tunnels.next().await.is_some() {}
+                    conn.close(VarInt::from_u32(SHUTDOWN_CLOSE_CODE), b"shutdown");
+                    return;
+                }
+                bi = conn.accept_bi() => match bi {
+                    Ok((quic_send, quic_recv)) => {
+                        trace!("new bi accepted");
+                        tunnels.push(Self::handle_srv_bi(
+                            quic_send,
+                            quic_recv,
+                            exposed_ports.clone(),
+                            Arc::clone(&compression),
+                            Arc::clone(&shaping),
+                        ));
+                    }
+                    Err(e) => {
+                        info!("connection ended: {}", e);
+                        break;
+                    }
+                },
+                Some(()) = tunnels.next(), if !tunnels.is_empty() => {}
+            }
+        }
+        // the remaining streams fail with the connection
+        while tunnels.next().await.is_some() {}
+    }
+
+    /// Decode the target_port from the bi QUIC stream and forward the rest of
+    /// the stream to localhost:target_port
+    async fn handle_srv_bi(
+        mut quic_send: SendStream,
+        mut quic_recv: RecvStream,
+        exposed_ports: Option<Arc<HashSet<u16>>>,
+        compression: Arc<CompressionPolicy>,
+        shaping: Arc<ShapingPolicy>,
+    ) {
+        let query = match InitQuery::read(&mut quic_recv).await {
+            Ok(query) => query,
+            Err(err) if err.kind() == IoErrorKind::InvalidData => {
+                error!(%err, "unexpected init query");
+                InitResponse::undecodable().write(&mut quic_send).await.ok();
+                quic_send.finish().await.ok();
+                return;
             }
             Err(err) => {
-                error!(err=%err, "connection to target failed");
-                InitResponse { code: 1 }.write(&mut quic_send).await;
-                quic_send.finish().await.unwrap();
+                error!(%err, "init query could not be read");
                 return;
             }
         };
+        debug!(?query, "init query read");
+
+        // forwarding connection
+        let (fwd_stream, compressed) =
+            match Self::connect_target(query.target_port, exposed_ports.as_deref()).await {
+                Ok(stream) => {
+                    // only compress if enabled by the policies of both ends
+                    let mut resp = InitResponse::new(&query, Ok(()));
+                    resp.capabilities &=
+                        !LZ4_COMPRESSION | compression.capabilities(query.target_port);
+                    let compressed = resp.capabilities & LZ4_COMPRESSION != 0;
+                    if let Err(err) = resp.write(&mut quic_send).await {
+                        error!(%err, "init response could not be written");
+                        return;
+                    }
+                    (stream, compressed)
+                }
+                Err(err) => {
+                    let resp = InitResponse::new(&query, Err(err));
+                    if let Err(err) = resp.write(&mut quic_send).await {
+                        error!(%err, "init failure could not be written");
+                    } else if let Err(err) = quic_send.finish().await {
+                        error!(%err, "init failure could not be reported");
+                    }
+                    return;
+                }
+            };
 
         if query.connect_only {
-            quic_send.finish().await.unwrap();
+            if let Err(err) = quic_send.finish().await {
+                error!(%err, "connect only query could not be closed");
+            }
             return;
         }
 
         // pipe holepunch connection to forwarding connection
-        let (fwd_read, fwd_write) = fwd_stream.into_split();
-        let out_fut = copy(quic_recv, fwd_write)
-            .instrument(debug_span!("cp_quic_tcp", port = query.target_port));
-        let in_fut = copy(fwd_read, quic_send)
-            .instrument(debug_span!("cp_tcp_quic", port = query.target_port));
-        tokio::try_join!(out_fut, in_fut).ok();
+        pipe(
+            fwd_stream,
+            quic_send,
+            quic_recv,
+            query.target_port,
+            Self::stream_options(&shaping, query.target_port, compressed),
+        )
+        .await;
         trace!("closing bi");
-        // expect the connection to be closed by caller
-        conn.accept_bi()
-            .await
-            .expect_err("Unexpected second bi on connection");
     }
 
     /// Run the forwarder p2p server
@@ -132,8 +590,17 @@ impl Forwarder {
     #[instrument(name = "quic_srv", skip_all)]
     pub async fn run_quic_server(&self, shutdown: &Shutdown) {
         debug!("start QUIC server");
+        futures::future::join_all(
+            self.quic_endpoints
+                .iter()
+                .map(|endpoint| self.accept_loop(endpoint, shutdown)),
+        )
+        .await;
+    }
+
+    async fn accept_loop(&self, endpoint: &Endpoint, shutdown: &Shutdown) {
         loop {
-            let connecting = self.quic_endpoint.accept().await.unwrap();
+            let connecting = endpoint.accept().await.unwrap();
             let remote_addr = connecting.remote_address();
             let conn = match connecting.await {
                 Ok(conn) => {
@@ -146,20 +613,168 @@ impl Forwarder {
                 }
             };
             let shdwn_guard = shutdown.create_guard();
-            spawn_task(
+            spawn_tunnel_task(
                 shdwn_guard,
                 debug_span!("srv_quic_conn", src_nat = %remote_addr),
-                Self::handle_srv_conn(conn),
+                Self::handle_srv_conn(
+                    conn,
+                    self.exposed_ports.clone(),
+                    Arc::clone(&self.compression),
+                    Arc::clone(&self.shaping),
+                    shutdown.create_guard(),
+                ),
             );
         }
     }
 
-    /// Open a QUIC connection on the forwarder endpoint to relay the provided
-    /// TcpStream
+    /// Send the init query on a new bi and read the response
+    async fn query_target(
+        quic_conn: &Connection,
+        query: InitQuery,
+    ) -> IoResult<(SendStream, RecvStream, InitResponse)> {
+        let (mut quic_send, mut quic_recv) = quic_conn.open_bi().await?;
+        trace!("new bi opened");
+        let query_version = query.version;
+        query.write(&mut quic_send).await?;
+        let resp = InitResponse::read(&mut quic_recv, query_version).await?;
+        Ok((quic_send, quic_recv, resp))
+    }
+
+    /// Lease the live connection shared with the target, if any
+    fn lease_shared(&self, nated_addr: SocketAddr) -> Option<ConnectionLease> {
+        let mut conns = self.shared_conns.lock().unwrap();
+        let entry = conns.get_mut(&nated_addr)?;
+        if entry.conn.close_reason().is_some() {
+            conns.remove(&nated_addr);
+            return None;
+        }
+        entry.tunnels += 1;
+        Some(ConnectionLease {
+            conn: entry.conn.clone(),
+            nated_addr,
+            shared: Some(Arc::clone(&self.shared_conns)),
+            _watcher: None,
+            replies: None,
+        })
+    }
+
+    /// Lease a new connection, shared with the next tunnels towards the
+    /// target if it accepts multiple tunnels and no other one is shared yet
+    fn lease_new(
+        &self,
+        nated_addr: SocketAddr,
+        conn: Connection,
+        shareable: bool,
+    ) -> ConnectionLease {
+        let watcher = self.spawn_path_watcher(conn.clone());
+        let mut conns = self.shared_conns.lock().unwrap();
+        let shared_live = conns
+            .get(&nated_addr)
+            .is_some_and(|entry| entry.conn.close_reason().is_none());
+        if shareable && !shared_live {
+            let entry = SharedConnection {
+                conn: conn.clone(),
+                tunnels: 1,
+                _watcher: watcher,
+                replies: None,
+            };
+            conns.insert(nated_addr, entry);
+            ConnectionLease {
+                conn,
+                nated_addr,
+                shared: Some(Arc::clone(&self.shared_conns)),
+                _watcher: None,
+                replies: None,
+            }
+        } else {
+            ConnectionLease {
+                conn,
+                nated_addr,
+                shared: None,
+                _watcher: Some(watcher),
+                replies: None,
+            }
+        }
+    }
+
+    /// Initialize a stream with the provided query, on the connection shared
+    /// with the target or on a new QUIC connection
+    ///
+    /// If the target was already reached before, the query on a new
+    /// connection is sent with the first flight (0-RTT), which the target
+    /// accepts at most once per ticket so that its side effects cannot be
+    /// replayed. A full handshake is used if the target rejects it. The lease
+    /// should be kept until the stream is closed.
+    async fn init_stream(
+        &self,
+        nated_addr: SocketAddr,
+        target_server_certificate_der: Vec<u8>,
+        query: InitQuery,
+    ) -> Result<(ConnectionLease, SendStream, RecvStream, InitResponse), TunnelError> {
+        let shareable = query.version >= MULTIPLEXED_TUNNELS_VERSION;
+        if let Some(lease) = shareable.then(|| self.lease_shared(nated_addr)).flatten() {
+            match Self::query_target(&lease.conn, query.clone()).await {
+                Ok((send, recv, resp)) => return resp.result.map(|_| (lease, send, recv, resp)),
+                Err(err) => debug!(%err, "shared connection failed"),
+            }
+        }
+        let zero_rtt = quic_utils::connect_0rtt(
+            self.client_endpoint(),
+            nated_addr,
+            target_server_certificate_der.clone(),
+            Arc::clone(&self.transport),
+        );
+        let quic_conn = match zero_rtt {
+            Ok((quic_conn, accepted)) => {
+                match Self::query_target(&quic_conn, query.clone()).await {
+                    Ok((send, recv, resp)) => {
+                        let lease = self.lease_new(nated_addr, quic_conn, shareable);
+                        return resp.result.map(|_| (lease, send, recv, resp));
+                    }
+                    Err(err) if !accepted.await => debug!(%err, "0-RTT rejected"),
+                    Err(err) => {
+                        error!(%err, "init query failed");
+                        return Err(TunnelError::QuicConnectFailed);
+                    }
+                }
+                quic_utils::connect_with_retry(
+                    self.client_endpoint(),
+                    nated_addr,
+                    target_server_certificate_der,
+                    Arc::clone(&self.transport),
+                )
+                .await?
+            }
+            // without a ticket, the handshake already started is completed
+            Err(connecting) => {
+                quic_utils::handshake_with_retry(
+                    connecting,
+                    self.client_endpoint(),
+                    nated_addr,
+                    target_server_certificate_der,
+                    Arc::clone(&self.transport),
+                )
+                .await?
+            }
+        };
+        match Self::query_target(&quic_conn, query).await {
+            Ok((send, recv, resp)) => {
+                let lease = self.lease_new(nated_addr, quic_conn, shareable);
+                resp.result.map(|_| (lease, send, recv, resp))
+            }
+            Err(err) => {
+                error!(%err, "init query failed");
+                Err(TunnelError::QuicConnectFailed)
+            }
+        }
+    }
+
+    /// Relay the provided TcpStream through a QUIC stream towards the target
     ///
-    /// Empirically, opening multiple client QUIC connections to the same target
-    /// on a given endpoint works. It might be suboptimal as the connection
-    /// management might involve some overhead (keepalive...)
+    /// The init query is encoded with the highest protocol version supported
+    /// by both this forwarder and the target. The tunnels towards targets that
+    /// accept multiple tunnels share a connection, so that the priorities of
+    /// their streams apply. Older targets get a connection per tunnel.
     #[instrument(
         name = "cli_quic_conn",
         skip_all,
@@ -174,48 +789,45 @@ impl Forwarder {
         nated_addr: SocketAddr,
         target_port: u16,
         target_server_certificate_der: Vec<u8>,
+        target_version: u8,
+        fields: OptionalFields,
     ) {
-        let conn_result = quic_utils::connect_with_retry(
-            &self.quic_endpoint,
-            nated_addr,
-            target_server_certificate_der,
-        )
-        .await;
-        if conn_result.is_none() {
-            error!("QUIC conn failed, dropping upstream connection");
-            tcp_stream.set_linger(None).unwrap();
-            return;
-        }
-        let quic_conn = conn_result.unwrap();
-        let (mut quic_send, mut quic_recv) = quic_conn.open_bi().await.unwrap();
-        trace!("new bi opened");
-        let query = InitQuery {
-            target_port,
-            connect_only: false,
-        };
-        query.write(&mut quic_send).await;
-        let InitResponse { code } = InitResponse::read(&mut quic_recv).await.unwrap();
-        match code {
-            0 => debug!("target conn successful"),
-            err_code => {
+        let mut query = InitQuery::new(target_port, false, target_version);
+        query.fields = fields;
+        query.capabilities &= !LZ4_COMPRESSION | self.compression.capabilities(target_port);
+        let init_res = self
+            .init_stream(nated_addr, target_server_certificate_der, query)
+            .await;
+        let (lease, quic_send, quic_recv, resp) = match init_res {
+            Ok(init) => {
+                debug!("target conn successful");
+                init
+            }
+            Err(err) => {
                 // at this point the clients already think they are connected,
                 // so we are converting a connection establishment error into a
                 // lost connection error
-                error!(err_code, "target conn failed, dropping upstream connection");
-                quic_send.finish().await.unwrap();
-                tcp_stream.set_linger(None).unwrap();
+                error!(%err, "tunnel failed, dropping upstream connection");
+                if let Err(err) = tcp_stream.set_linger(Some(Duration::ZERO)) {
+                    warn!(%err, "upstream connection will be closed gracefully");
+                }
                 return;
             }
-        }
-        let (tcp_read, tcp_write) = tcp_stream.into_split();
-        let out_fut =
-            copy(tcp_read, quic_send).instrument(debug_span!("cp_tcp_quic", port = target_port));
-        let in_fut =
-            copy(quic_recv, tcp_write).instrument(debug_span!("cp_quic_tcp", port = target_port));
-        tokio::try_join!(out_fut, in_fut).ok();
+        };
+        let compressed = resp.capabilities & LZ4_COMPRESSION != 0;
+        let options = Self::stream_options(&self.shaping, target_port, compressed);
+        // the path is watched as long as the lease is kept, the streams resume
+        // once it is punched again
+        pipe(tcp_stream, quic_send, quic_recv, target_port, options).await;
+        drop(lease);
         trace!("closing bi");
     }
 
+    /// Check that the target accepts connections on the port
+    ///
+    /// The probe is not on the latency path of a tunnel, so it always waits
+    /// for a full handshake and leaves the session tickets of the target to
+    /// the tunnels.
     #[instrument(
         name = "cli_try_tgt",
         skip_all,
@@ -229,49 +841,127 @@ impl Forwarder {
         nated_addr: SocketAddr,
         target_port: u16,
         target_server_certificate_der: Vec<u8>,
-    ) -> Result<()> {
-        let conn_result = quic_utils::connect_with_retry(
-            &self.quic_endpoint,
+        target_version: u8,
+    ) -> Result<(), TunnelError> {
+        let quic_conn = quic_utils::connect_with_retry(
+            self.client_endpoint(),
             nated_addr,
             target_server_certificate_der,
+            Arc::clone(&self.transport),
         )
-        .await;
-        if conn_result.is_none() {
-            return Err(anyhow!("quic conn failed"));
-        }
-        let quic_conn = conn_result.unwrap();
+        .await?;
 
         // bi opening timeout means an unexpected QUIC flow control kicked in
-        let (mut quic_send, mut quic_recv) =
-            tokio::time::timeout(Duration::from_millis(50), quic_conn.open_bi())
-                .await
-                .unwrap()
-                .unwrap();
+        let bi_fut = tokio::time::timeout(Duration::from_millis(50), quic_conn.open_bi());
+        let (mut quic_send, mut quic_recv) = match bi_fut.await {
+            Ok(Ok(bi)) => bi,
+            Ok(Err(err)) => {
+                error!(%err, "bi could not be opened");
+                return Err(TunnelError::QuicConnectFailed);
+            }
+            Err(_) => {
+                error!("bi opening timed out");
+                return Err(TunnelError::QuicConnectFailed);
+            }
+        };
 
         trace!("new bi opened");
-        let query = InitQuery {
-            target_port,
-            connect_only: true,
-        };
-        query.write(&mut quic_send).await;
-        let InitResponse { code } = match InitResponse::read(&mut quic_recv).await {
+        let query = InitQuery::new(target_port, true, target_version);
+        let query_version = query.version;
+        if let Err(err) = query.write(&mut quic_send).await {
+            error!(%err, "init query could not be written");
+            return Err(TunnelError::QuicConnectFailed);
+        }
+        let resp = match InitResponse::read(&mut quic_recv, query_version).await {
             Ok(r) => r,
             Err(err) => {
                 error!(%err, "proxy conn failed");
-                return Err(err.into());
+                return Err(TunnelError::QuicConnectFailed);
             }
         };
-        match code {
-            0 => debug!("target conn successful"),
-            err_code => {
-                error!(err_code, "target conn failed");
-                return Err(anyhow!("target conn failed with code {}", err_code));
+        match resp.result {
+            Ok(()) => debug!("target conn successful"),
+            Err(err) => {
+                error!(%err, "target conn failed");
+                return Err(err);
             }
         }
         trace!("closing bi");
         Ok(())
     }
 
+    /// Relay the datagrams sent to the returned local UDP port towards the
+    /// target port, on the connection shared with the target or on a new QUIC
+    /// connection
+    ///
+    /// The relay runs until shutdown or until the QUIC connection is lost,
+    /// which completes the returned handle.
+    #[instrument(
+        name = "cli_udp_relay",
+        skip_all,
+        fields(
+            tgt_nat = %nated_addr,
+            tgt_port = target_port
+        )
+    )]
+    pub async fn open_udp_relay(
+        &self,
+        shutdown_guard: ShutdownGuard,
+        nated_addr: SocketAddr,
+        target_port: u16,
+        target_server_certificate_der: Vec<u8>,
+        target_version: u8,
+    ) -> Result<(u16, JoinHandle<Result<(), Cancelled>>), TunnelError> {
+        if target_version < UDP_DATAGRAMS_VERSION {
+            error!(target_version, "target cannot relay datagrams");
+            return Err(TunnelError::ProtocolVersionMismatch);
+        }
+        let shareable = target_version >= MULTIPLEXED_TUNNELS_VERSION;
+        let mut lease = match shareable.then(|| self.lease_shared(nated_addr)).flatten() {
+            Some(lease) => lease,
+            None => {
+                let quic_conn = quic_utils::connect_with_retry(
+                    self.client_endpoint(),
+                    nated_addr,
+                    target_server_certificate_der,
+                    Arc::clone(&self.transport),
+                )
+                .await?;
+                self.lease_new(nated_addr, quic_conn, shareable)
+            }
+        };
+        let bind_res = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .and_then(|sock| Ok((sock.local_addr()?.port(), sock)));
+        let (relay_port, relay_sock) = match bind_res {
+            Ok(bound) => bound,
+            Err(err) => {
+                error!(%err, "relay socket could not be opened");
+                return Err(TunnelError::RelayUnavailable);
+            }
+        };
+        debug!(relay_port, "datagram relay opened");
+        let routes = lease.reply_routes();
+        let relay_handle = spawn_task(
+            shutdown_guard,
+            debug_span!("udp_relay", relay_port),
+            async move {
+                let relay_sock = Arc::new(relay_sock);
+                udp_relay::relay_client(lease.conn.clone(), relay_sock, target_port, routes).await;
+                // the path is watched as long as the lease is kept
+                drop(lease);
+                debug!("datagram relay closed");
+            },
+        );
+        Ok((relay_port, relay_handle))
+    }
+
+    /// Probe the current NAT mapping of the forwarder port from a seed probe
+    /// port, through the QUIC endpoint socket
+    pub async fn probe_mapping(&self, seed_probe_addr: SocketAddr) -> Option<SocketAddrV4> {
+        self.probing_socket.probe_mapping(seed_probe_addr).await
+    }
+
     pub fn port(&self) -> u16 {
         self.port
     }
@@ -280,13 +970,8 @@ impl Forwarder {
         &self.server_certificate_der
     }
 
-    #[instrument(skip(self))]
-    pub async fn punch_hole(&self, nat: SocketAddr, virt: String) -> Result<()> {
-        debug!("make punch conn to client");
-        let connecting = self
-            .quic_endpoint
-            .connect_with(quic_utils::configure_punch_client(), nat, PUNCH_SERVER_NAME)
-            .unwrap();
+    /// Wait for the punch connection to be rejected by the client
+    async fn await_punch(connecting: Connecting) -> Result<()> {
         // we expect the connection establishment mechanism to handle retries
         // until the hole is actually punched
         match connecting.await {
@@ -300,18 +985,52 @@ impl Forwarder {
             Err(e) => Err(anyhow!("Unexpected error {:?}", e)),
         }
     }
+
+    /// Punch holes towards all the provided client addresses concurrently
+    ///
+    /// Besides the address observed by the seed, the candidates can contain
+    /// mappings predicted for NATs that allocate ports sequentially. Returns
+    /// once the punch packets are emitted, with a future that completes as
+    /// soon as one of the candidates was reached.
+    pub fn punch_hole(
+        &self,
+        nats: Vec<SocketAddr>,
+        virt: String,
+    ) -> Result<impl Future<Output = Result<()>>> {
+        let span = debug_span!("punch_hole", ?nats, virt);
+        let _entered = span.enter();
+        debug!("make punch conns to client");
+        if nats.is_empty() {
+            return Err(anyhow!("No address to punch"));
+        }
+        let punches = nats
+            .into_iter()
+            .map(|nat| {
+                self.client_endpoint()
+                    .connect_with(quic_utils::configure_punch_client(), nat, PUNCH_SERVER_NAME)
+                    .map(|connecting| Box::pin(Self::await_punch(connecting)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(futures::future::select_ok(punches)
+            .map_ok(|_| ())
+            .instrument(span.clone()))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fwd_protocol::FWD_PROTOCOL_VERSION;
+    use crate::nat_emulator::{PortRestrictedNat, SymmetricNat};
+    use crate::shaping::TokenBucket;
+    use crate::spawn::TUNNEL_DRAIN_PERIOD;
+    use chappy_seed::nat_probe::{predict_mappings, run_probe_server};
     use chappy_util::test;
     use futures::StreamExt;
     use rand::seq::SliceRandom;
     use std::sync::Arc;
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
-    use tokio::net::TcpListener;
-    use tokio::task::JoinHandle;
+    use tokio::net::{TcpListener, UdpSocket};
 
     /// Create a TCP server on the specified port and connect to it, then
     /// forward the server side stream using the provided forwarder and target
@@ -320,6 +1039,20 @@ mod tests {
         port: u16,
         fwd: &Arc<Forwarder>,
         target_port: u16,
+    ) -> (TcpStream, JoinHandle<()>) {
+        let fwd_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, fwd.port()));
+        let cert = fwd.server_certificate().to_owned();
+        simulate_proxied_connect_to(port, fwd, fwd_addr, cert, target_port).await
+    }
+
+    /// Same as `simulate_proxied_connect`, through the forwarder at the
+    /// provided address
+    async fn simulate_proxied_connect_to(
+        port: u16,
+        fwd: &Arc<Forwarder>,
+        target_fwd_addr: SocketAddr,
+        target_fwd_cert: Vec<u8>,
+        target_port: u16,
     ) -> (TcpStream, JoinHandle<()>) {
         let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
         let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
@@ -336,9 +1069,11 @@ mod tests {
         let fwd_handle = tokio::spawn(async move {
             fwd.forward(
                 proxied_stream,
-                SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, fwd.port().into())),
+                target_fwd_addr,
                 target_port,
-                fwd.server_certificate().to_owned(),
+                target_fwd_cert,
+                FWD_PROTOCOL_VERSION,
+                OptionalFields::default(),
             )
             .await;
             debug!("dropping moved listener {}", listener.local_addr().unwrap());
@@ -358,7 +1093,12 @@ mod tests {
     }
 
     async fn create_and_start_forwarder(port: u16) -> (Arc<Forwarder>, JoinHandle<()>) {
-        let fwd = Arc::new(Forwarder::new(port));
+        start_forwarder(Forwarder::new(port, &TransportProfile::default())).await
+    }
+
+    /// Run the QUIC server of a forwarder created with custom settings
+    async fn start_forwarder(fwd: Forwarder) -> (Arc<Forwarder>, JoinHandle<()>) {
+        let fwd = Arc::new(fwd);
 
         let srv_handle = {
             let fwd = Arc::clone(&fwd);
@@ -402,27 +1142,267 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_multiple_targets() {
-        chappy_util::init_tracing("test");
-        let nb_target = 20;
-        let avail_ports = test::available_ports(2 * nb_target + 1).await;
-        let echo_srv_ports = &avail_ports[0..nb_target];
-        let cli_proxy_ports = &avail_ports[nb_target..2 * nb_target];
-        let fwd_quic_port = avail_ports[2 * nb_target];
-        let (fwd, fwd_srv_handle) = create_and_start_forwarder(fwd_quic_port).await;
+    async fn test_custom_transport_profile() {
+        let avail_ports = test::available_ports(3).await;
+        let echo_srv_port = avail_ports[0];
+        let profile = TransportProfile {
+            stream_receive_window: 16_000_000,
+            receive_window: 64_000_000,
+            send_window: 64_000_000,
+            congestion_controller: "bbr".parse().unwrap(),
+            mtu_discovery: false,
+            ..Default::default()
+        };
+        let (fwd, fwd_srv_handle) = start_forwarder(Forwarder::new(avail_ports[1], &profile)).await;
+        let echo_srv_handle = tokio::spawn(echo_server(echo_srv_port));
+        let (mut cli_stream, fwd_handle) =
+            simulate_proxied_connect(avail_ports[2], &fwd, echo_srv_port).await;
+        assert_echo(&mut cli_stream, 1_000_000).await;
 
-        let mut cli_streams = futures::stream::iter(0..nb_target)
-            .then(|t| {
-                let echo_srv_port = echo_srv_ports[t];
-                let cli_proxy_port = cli_proxy_ports[t];
-                let fwd = Arc::clone(&fwd);
-                async move {
-                    let echo_srv_handle = tokio::spawn(echo_server(echo_srv_port));
-                    let (cli_stream, fwd_handle) =
-                        simulate_proxied_connect(cli_proxy_port, &fwd, echo_srv_port).await;
-                    (cli_stream, echo_srv_handle, fwd_handle)
-                }
-            })
+        // cleanup
+        echo_srv_handle.abort();
+        fwd_srv_handle.abort();
+        fwd_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_compressed_tunnels() {
+        let avail_ports = test::available_ports(6).await;
+        let compressed_port = avail_ports[0];
+        let opted_out_port = avail_ports[1];
+        let policy = CompressionPolicy::with_ports(
+            "lz4".parse().unwrap(),
+            &format!("{}=none", opted_out_port),
+        )
+        .unwrap();
+        let fwd = Arc::new(
+            Forwarder::new(avail_ports[2], &TransportProfile::default()).compression_policy(policy),
+        );
+        let fwd_srv_handle = {
+            let fwd = Arc::clone(&fwd);
+            tokio::spawn(async move { fwd.run_quic_server(&Shutdown::new()).await })
+        };
+        let mut handles = vec![fwd_srv_handle];
+        for (target_port, proxy_port) in [
+            (compressed_port, avail_ports[3]),
+            (opted_out_port, avail_ports[4]),
+        ] {
+            handles.push(tokio::spawn(echo_server(target_port)));
+            let (mut cli_stream, fwd_handle) =
+                simulate_proxied_connect(proxy_port, &fwd, target_port).await;
+            handles.push(fwd_handle);
+            // spans several compression blocks
+            assert_echo(&mut cli_stream, 4).await;
+            assert_echo(&mut cli_stream, 1_000_000).await;
+            assert_echo(&mut cli_stream, 4).await;
+        }
+
+        // cleanup
+        handles.iter().for_each(JoinHandle::abort);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_port() {
+        let avail_ports = test::available_ports(5).await;
+        let limited_port = avail_ports[0];
+        let other_port = avail_ports[1];
+        let policy = ShapingPolicy::with_ports("", &format!("{}=1000000", limited_port)).unwrap();
+        let fwd = Arc::new(
+            Forwarder::new(avail_ports[2], &TransportProfile::default()).shaping_policy(policy),
+        );
+        let fwd_srv_handle = {
+            let fwd = Arc::clone(&fwd);
+            tokio::spawn(async move { fwd.run_quic_server(&Shutdown::new()).await })
+        };
+        let mut handles = vec![fwd_srv_handle];
+        let mut elapsed = vec![];
+        for (target_port, proxy_port) in
+            [(limited_port, avail_ports[3]), (other_port, avail_ports[4])]
+        {
+            handles.push(tokio::spawn(echo_server(target_port)));
+            let (mut cli_stream, fwd_handle) =
+                simulate_proxied_connect(proxy_port, &fwd, target_port).await;
+            handles.push(fwd_handle);
+            let start = tokio::time::Instant::now();
+            assert_echo(&mut cli_stream, 300_000).await;
+            elapsed.push(start.elapsed());
+        }
+        // both directions draw from the bucket of the port, minus the burst
+        assert!(elapsed[0] >= Duration::from_millis(450), "{:?}", elapsed);
+        assert!(elapsed[1] < Duration::from_millis(450), "{:?}", elapsed);
+
+        // cleanup
+        handles.iter().for_each(JoinHandle::abort);
+    }
+
+    /// Echo the connections accepted on the port until aborted
+    async fn echo_server_loop(port: u16) {
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port))
+            .await
+            .unwrap();
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut r, mut w) = socket.split();
+                tokio::io::copy(&mut r, &mut w).await.ok();
+            });
+        }
+    }
+
+    /// Relay the datagrams sent to the port towards the server at a bounded
+    /// rate in each direction, like a slow link
+    async fn slow_link(port: u16, server_addr: SocketAddr, bytes_per_sec: u64) {
+        let cli_sock = UdpSocket::bind((Ipv4Addr::LOCALHOST, port)).await.unwrap();
+        let srv_sock = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        srv_sock.connect(server_addr).await.unwrap();
+        let (up, down) = (
+            TokenBucket::new(bytes_per_sec),
+            TokenBucket::new(bytes_per_sec),
+        );
+        let mut cli_addr = None;
+        let (mut up_buf, mut down_buf) = (vec![0; 2048], vec![0; 2048]);
+        loop {
+            tokio::select! {
+                Ok((len, addr)) = cli_sock.recv_from(&mut up_buf) => {
+                    cli_addr = Some(addr);
+                    up.acquire(len).await;
+                    srv_sock.send(&up_buf[..len]).await.ok();
+                }
+                Ok(len) = srv_sock.recv(&mut down_buf), if cli_addr.is_some() => {
+                    down.acquire(len).await;
+                    cli_sock.send_to(&down_buf[..len], cli_addr.unwrap()).await.ok();
+                }
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_prioritized_port() {
+        let avail_ports = test::available_ports(13).await;
+        let (bulk_port, rpc_port) = (avail_ports[0], avail_ports[1]);
+        let policy = ShapingPolicy::with_ports(&format!("{}=10", rpc_port), "").unwrap();
+        let (fwd, fwd_srv_handle) = start_forwarder(
+            Forwarder::new(avail_ports[2], &TransportProfile::default()).shaping_policy(policy),
+        )
+        .await;
+        // the tunnels go through a link slower than their transfers, so that
+        // their data waits to be sent by the connection
+        let fwd_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, fwd.port()));
+        let link_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, avail_ports[3]));
+        let cert = fwd.server_certificate().to_owned();
+        let mut handles = vec![
+            fwd_srv_handle,
+            tokio::spawn(echo_server_loop(bulk_port)),
+            tokio::spawn(echo_server_loop(rpc_port)),
+            tokio::spawn(slow_link(avail_ports[3], fwd_addr, 2_000_000)),
+        ];
+        // saturate the link in both directions with concurrent bulk tunnels
+        for &proxy_port in &avail_ports[4..12] {
+            let (bulk_stream, fwd_handle) =
+                simulate_proxied_connect_to(proxy_port, &fwd, link_addr, cert.clone(), bulk_port)
+                    .await;
+            handles.push(fwd_handle);
+            let (mut bulk_read, mut bulk_write) = bulk_stream.into_split();
+            handles.push(tokio::spawn(async move {
+                let chunk = vec![7; 1 << 16];
+                while bulk_write.write_all(&chunk).await.is_ok() {}
+            }));
+            handles.push(tokio::spawn(async move {
+                let mut buf = vec![0; 1 << 16];
+                while bulk_read.read(&mut buf).await.unwrap_or(0) > 0 {}
+            }));
+        }
+        let (mut rpc_stream, fwd_handle) =
+            simulate_proxied_connect_to(avail_ports[12], &fwd, link_addr, cert, rpc_port).await;
+        handles.push(fwd_handle);
+        assert_echo(&mut rpc_stream, 100).await;
+        // the priority only applies to the streams of the same connection
+        {
+            let shared_conns = fwd.shared_conns.lock().unwrap();
+            assert_eq!(shared_conns.len(), 1);
+            assert_eq!(shared_conns.values().next().unwrap().tunnels, 9);
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        // served in turn with the bulk streams, the echo would only get a
+        // ninth of the link
+        for _ in 0..5 {
+            let start = tokio::time::Instant::now();
+            assert_echo(&mut rpc_stream, 100_000).await;
+            let elapsed = start.elapsed();
+            assert!(elapsed < Duration::from_millis(400), "{:?}", elapsed);
+        }
+
+        // cleanup
+        handles.iter().for_each(JoinHandle::abort);
+    }
+
+    #[tokio::test]
+    async fn test_zero_rtt_resumption() {
+        let avail_ports = test::available_ports(4).await;
+        let echo_srv_port = avail_ports[0];
+        let fwd_quic_port = avail_ports[1];
+        let (fwd, fwd_srv_handle) = create_and_start_forwarder(fwd_quic_port).await;
+        let tgt_fwd_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, fwd.port()));
+        let cert = fwd.server_certificate().to_owned();
+        assert!(quic_utils::connect_0rtt(
+            fwd.client_endpoint(),
+            tgt_fwd_addr,
+            cert.clone(),
+            Arc::clone(&fwd.transport)
+        )
+        .is_err());
+
+        // the first tunnel does a full handshake and receives a session ticket
+        let echo_srv_handle = tokio::spawn(echo_server(echo_srv_port));
+        let (mut cli_stream, fwd_handle) =
+            simulate_proxied_connect(avail_ports[2], &fwd, echo_srv_port).await;
+        assert_echo(&mut cli_stream, 4).await;
+        drop(cli_stream);
+        fwd_handle.await.unwrap();
+        echo_srv_handle.await.unwrap();
+        let (_conn, accepted) = quic_utils::connect_0rtt(
+            fwd.client_endpoint(),
+            tgt_fwd_addr,
+            cert,
+            Arc::clone(&fwd.transport),
+        )
+        .unwrap();
+        assert!(accepted.await);
+
+        // the next tunnel sends its init query with the first flight
+        let echo_srv_handle = tokio::spawn(echo_server(echo_srv_port));
+        let (mut cli_stream, fwd_handle) =
+            simulate_proxied_connect(avail_ports[3], &fwd, echo_srv_port).await;
+        assert_echo(&mut cli_stream, 10000).await;
+
+        // cleanup
+        echo_srv_handle.abort();
+        fwd_srv_handle.abort();
+        fwd_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_multiple_targets() {
+        chappy_util::init_tracing("test");
+        let nb_target = 20;
+        let avail_ports = test::available_ports(2 * nb_target + 1).await;
+        let echo_srv_ports = &avail_ports[0..nb_target];
+        let cli_proxy_ports = &avail_ports[nb_target..2 * nb_target];
+        let fwd_quic_port = avail_ports[2 * nb_target];
+        let (fwd, fwd_srv_handle) = create_and_start_forwarder(fwd_quic_port).await;
+
+        let mut cli_streams = futures::stream::iter(0..nb_target)
+            .then(|t| {
+                let echo_srv_port = echo_srv_ports[t];
+                let cli_proxy_port = cli_proxy_ports[t];
+                let fwd = Arc::clone(&fwd);
+                async move {
+                    let echo_srv_handle = tokio::spawn(echo_server(echo_srv_port));
+                    let (cli_stream, fwd_handle) =
+                        simulate_proxied_connect(cli_proxy_port, &fwd, echo_srv_port).await;
+                    (cli_stream, echo_srv_handle, fwd_handle)
+                }
+            })
             .collect::<Vec<_>>()
             .await;
 
@@ -437,10 +1417,46 @@ mod tests {
 
         // cleanup
         fwd_srv_handle.abort();
-        for i in 0..nb_target {
-            cli_streams[i].1.abort();
-            cli_streams[i].2.abort();
+        for (_, echo_srv_handle, fwd_handle) in &cli_streams {
+            echo_srv_handle.abort();
+            fwd_handle.abort();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_sharded_targets() {
+        let nb_target = 12;
+        let avail_ports = test::available_ports(2 * nb_target + 1).await;
+        let fwd = Arc::new(Forwarder::sharded(
+            avail_ports[2 * nb_target],
+            &TransportProfile::default(),
+            4,
+        ));
+        let fwd_srv_handle = {
+            let fwd = Arc::clone(&fwd);
+            tokio::spawn(async move { fwd.run_quic_server(&Shutdown::new()).await })
+        };
+
+        // connections are opened round robin by all the endpoints, the replies
+        // should be routed back to the endpoint that opened them
+        let mut handles = vec![];
+        for t in 0..nb_target {
+            let echo_srv_handle = tokio::spawn(echo_server(avail_ports[t]));
+            let (mut cli_stream, fwd_handle) =
+                simulate_proxied_connect(avail_ports[nb_target + t], &fwd, avail_ports[t]).await;
+            handles.push(tokio::spawn(async move {
+                assert_echo(&mut cli_stream, 100_000).await;
+                (echo_srv_handle, fwd_handle)
+            }));
         }
+
+        // cleanup
+        for handle in handles {
+            let (echo_srv_handle, fwd_handle) = handle.await.unwrap();
+            echo_srv_handle.abort();
+            fwd_handle.abort();
+        }
+        fwd_srv_handle.abort();
     }
 
     #[tokio::test]
@@ -460,21 +1476,77 @@ mod tests {
         echo_srv_handle.abort();
         tokio::time::sleep(Duration::from_millis(50)).await;
         let bytes = &[1u8, 2, 3, 4];
-        let mut read_buf = vec![0; bytes.len()];
-        // the first write is expected to succeed because it is aknowledged by
-        // the first proxy that cannot know yet that the target is disconnected.
+        // the write reaches the closed target socket, which answers with a RST
+        // that resets the tunnel before the held target FIN is relayed
         cli_stream.write_all(bytes).await.unwrap();
-        cli_stream.flush().await.unwrap();
         tokio::time::sleep(Duration::from_millis(50)).await;
-        // this write succeeds also, but ideally by this time the stream should
-        // know that the connection is broken
-        cli_stream.write_all(bytes).await.unwrap();
-        cli_stream
-            .read_exact(&mut read_buf)
+        let err = cli_stream
+            .write_all(bytes)
             .await
-            .expect_err("read from aborted target should not succeed");
+            .expect_err("write to aborted target should not succeed");
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+
+        // cleanup
+        fwd_srv_handle.abort();
+        fwd_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_client_reset() {
+        let avail_ports = test::available_ports(3).await;
+        let tgt_srv_port = avail_ports[0];
+        let fwd_quic_port = avail_ports[1];
+        let cli_proxy_port = avail_ports[2];
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, tgt_srv_port))
+            .await
+            .unwrap();
+        let tgt_srv_handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket.read_to_end(&mut vec![]).await
+        });
+        let (fwd, fwd_srv_handle) = create_and_start_forwarder(fwd_quic_port).await;
+        let (cli_stream, fwd_handle) =
+            simulate_proxied_connect(cli_proxy_port, &fwd, tgt_srv_port).await;
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // abortive close of the client connection
+        cli_stream.set_linger(Some(Duration::ZERO)).unwrap();
+        drop(cli_stream);
+        let tgt_res = tokio::time::timeout(Duration::from_millis(200), tgt_srv_handle)
+            .await
+            .expect("target should be notified promptly")
+            .unwrap();
+        assert_eq!(
+            tgt_res.expect_err("target read should fail").kind(),
+            std::io::ErrorKind::ConnectionReset
+        );
+
+        // cleanup
+        fwd_srv_handle.abort();
+        fwd_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_half_close() {
+        let avail_ports = test::available_ports(3).await;
+        let echo_srv_port = avail_ports[0];
+        let fwd_quic_port = avail_ports[1];
+        let cli_proxy_port = avail_ports[2];
+        let echo_srv_handle = tokio::spawn(echo_server(echo_srv_port));
+        let (fwd, fwd_srv_handle) = create_and_start_forwarder(fwd_quic_port).await;
+        let (mut cli_stream, fwd_handle) =
+            simulate_proxied_connect(cli_proxy_port, &fwd, echo_srv_port).await;
+
+        // the echo server only responds fully once it reads the end of stream
+        let bytes = &[1u8, 2, 3, 4];
+        cli_stream.write_all(bytes).await.unwrap();
+        cli_stream.shutdown().await.unwrap();
+        let mut read_buf = vec![];
+        cli_stream.read_to_end(&mut read_buf).await.unwrap();
+        assert_eq!(bytes, read_buf.as_slice());
 
         // cleanup
+        echo_srv_handle.abort();
         fwd_srv_handle.abort();
         fwd_handle.abort();
     }
@@ -486,12 +1558,12 @@ mod tests {
         let fwd_quic_port = avail_ports[1];
         let echo_srv_handle = tokio::spawn(echo_server(echo_srv_port));
         let (fwd, fwd_srv_handle) = create_and_start_forwarder(fwd_quic_port).await;
-        let tgt_fwd_addr =
-            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, fwd.port().into()));
+        let tgt_fwd_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, fwd.port()));
         fwd.try_target(
             tgt_fwd_addr,
             echo_srv_port,
             fwd.server_certificate().to_owned(),
+            FWD_PROTOCOL_VERSION,
         )
         .await
         .unwrap();
@@ -500,21 +1572,495 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_try_target_missing() {
+    async fn test_try_target_legacy_query() {
         let avail_ports = test::available_ports(2).await;
         let echo_srv_port = avail_ports[0];
         let fwd_quic_port = avail_ports[1];
-        // here the echo server is not started
+        let echo_srv_handle = tokio::spawn(echo_server(echo_srv_port));
         let (fwd, fwd_srv_handle) = create_and_start_forwarder(fwd_quic_port).await;
-        let tgt_fwd_addr =
-            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, fwd.port().into()));
+        let tgt_fwd_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, fwd.port()));
+        // a target version of 0 makes the client talk like a legacy peer
         fwd.try_target(
             tgt_fwd_addr,
             echo_srv_port,
             fwd.server_certificate().to_owned(),
+            0,
         )
         .await
-        .expect_err("should detect that target isn't running");
+        .unwrap();
         fwd_srv_handle.abort();
+        echo_srv_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_try_target_missing() {
+        let avail_ports = test::available_ports(2).await;
+        let echo_srv_port = avail_ports[0];
+        let fwd_quic_port = avail_ports[1];
+        // here the echo server is not started
+        let (fwd, fwd_srv_handle) = create_and_start_forwarder(fwd_quic_port).await;
+        let tgt_fwd_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, fwd.port()));
+        let err = fwd
+            .try_target(
+                tgt_fwd_addr,
+                echo_srv_port,
+                fwd.server_certificate().to_owned(),
+                FWD_PROTOCOL_VERSION,
+            )
+            .await
+            .expect_err("should detect that target isn't running");
+        assert_eq!(err, TunnelError::TargetRefused);
+        fwd_srv_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_udp_relay() {
+        let avail_ports = test::available_ports(1).await;
+        let fwd_quic_port = avail_ports[0];
+        let (echo_srv_port, echo_srv_handle) = udp_echo_server().await;
+        let (fwd, fwd_srv_handle) = create_and_start_forwarder(fwd_quic_port).await;
+        let shutdown = Shutdown::new();
+        let (relay_port, _relay_handle) = fwd
+            .open_udp_relay(
+                shutdown.create_guard(),
+                SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, fwd.port())),
+                echo_srv_port,
+                fwd.server_certificate().to_owned(),
+                FWD_PROTOCOL_VERSION,
+            )
+            .await
+            .unwrap();
+
+        // two client sockets get their own replies
+        let cli_socks = [
+            UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap(),
+            UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap(),
+        ];
+        for (i, sock) in cli_socks.iter().enumerate() {
+            sock.connect((Ipv4Addr::LOCALHOST, relay_port))
+                .await
+                .unwrap();
+            assert_udp_echo(sock, &format!("hello {}", i)).await;
+        }
+
+        // cleanup
+        shutdown.wait().await;
+        echo_srv_handle.abort();
+        fwd_srv_handle.abort();
+    }
+
+    /// Start a UDP echo server on a free port until aborted
+    async fn udp_echo_server() -> (u16, JoinHandle<()>) {
+        let echo_sock = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let echo_srv_port = echo_sock.local_addr().unwrap().port();
+        let echo_srv_handle = tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            loop {
+                let (len, src) = echo_sock.recv_from(&mut buf).await.unwrap();
+                echo_sock.send_to(&buf[..len], src).await.unwrap();
+            }
+        });
+        (echo_srv_port, echo_srv_handle)
+    }
+
+    /// Check that the payload sent on the connected socket is echoed
+    async fn assert_udp_echo(sock: &UdpSocket, payload: &str) {
+        let mut buf = [0; 1024];
+        // datagrams might be dropped before the flow is setup
+        let len = loop {
+            sock.send(payload.as_bytes()).await.unwrap();
+            let recv = tokio::time::timeout(Duration::from_millis(100), sock.recv(&mut buf));
+            if let Ok(len) = recv.await {
+                break len.unwrap();
+            }
+        };
+        assert_eq!(&buf[..len], payload.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_udp_relays_shared_connection() {
+        let avail_ports = test::available_ports(1).await;
+        let echo_srvs = [udp_echo_server().await, udp_echo_server().await];
+        let (fwd, fwd_srv_handle) = create_and_start_forwarder(avail_ports[0]).await;
+        let shutdown = Shutdown::new();
+        let mut cli_socks = vec![];
+        for (echo_srv_port, _) in &echo_srvs {
+            let (relay_port, _relay_handle) = fwd
+                .open_udp_relay(
+                    shutdown.create_guard(),
+                    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, fwd.port())),
+                    *echo_srv_port,
+                    fwd.server_certificate().to_owned(),
+                    FWD_PROTOCOL_VERSION,
+                )
+                .await
+                .unwrap();
+            let sock = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            sock.connect((Ipv4Addr::LOCALHOST, relay_port))
+                .await
+                .unwrap();
+            cli_socks.push(sock);
+        }
+        {
+            let shared_conns = fwd.shared_conns.lock().unwrap();
+            assert_eq!(shared_conns.len(), 1);
+            assert_eq!(shared_conns.values().next().unwrap().tunnels, 2);
+        }
+
+        // the replies read from the shared connection reach their own relay
+        for _ in 0..3 {
+            for (i, sock) in cli_socks.iter().enumerate() {
+                assert_udp_echo(sock, &format!("hello {}", i)).await;
+            }
+        }
+
+        // cleanup
+        shutdown.wait().await;
+        echo_srvs.iter().for_each(|(_, handle)| handle.abort());
+        fwd_srv_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_udp_relay_legacy_target() {
+        let avail_ports = test::available_ports(1).await;
+        let (fwd, fwd_srv_handle) = create_and_start_forwarder(avail_ports[0]).await;
+        let err = fwd
+            .open_udp_relay(
+                Shutdown::new().create_guard(),
+                SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, fwd.port())),
+                8125,
+                fwd.server_certificate().to_owned(),
+                1,
+            )
+            .await
+            .expect_err("legacy targets cannot relay datagrams");
+        assert_eq!(err, TunnelError::ProtocolVersionMismatch);
+        fwd_srv_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_try_target_not_exposed() {
+        let avail_ports = test::available_ports(2).await;
+        let echo_srv_port = avail_ports[0];
+        let fwd_quic_port = avail_ports[1];
+        let echo_srv_handle = tokio::spawn(echo_server(echo_srv_port));
+        let fwd = Arc::new(
+            Forwarder::new(fwd_quic_port, &TransportProfile::default())
+                .expose_ports(HashSet::new()),
+        );
+        let fwd_srv_handle = {
+            let fwd = Arc::clone(&fwd);
+            tokio::spawn(async move { fwd.run_quic_server(&Shutdown::new()).await })
+        };
+        let tgt_fwd_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, fwd.port()));
+        let err = fwd
+            .try_target(
+                tgt_fwd_addr,
+                echo_srv_port,
+                fwd.server_certificate().to_owned(),
+                FWD_PROTOCOL_VERSION,
+            )
+            .await
+            .expect_err("target port should not be reachable");
+        assert_eq!(err, TunnelError::PortNotExposed);
+        fwd_srv_handle.abort();
+        echo_srv_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_try_target_wrong_certificate() {
+        let avail_ports = test::available_ports(3).await;
+        let echo_srv_port = avail_ports[0];
+        let (fwd, fwd_srv_handle) = create_and_start_forwarder(avail_ports[1]).await;
+        let other_fwd = Forwarder::new(avail_ports[2], &TransportProfile::default());
+        let tgt_fwd_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, fwd.port()));
+        let err = fwd
+            .try_target(
+                tgt_fwd_addr,
+                echo_srv_port,
+                other_fwd.server_certificate().to_owned(),
+                FWD_PROTOCOL_VERSION,
+            )
+            .await
+            .expect_err("certificate should be rejected");
+        assert_eq!(err, TunnelError::CertificateMismatch);
+        fwd_srv_handle.abort();
+    }
+
+    /// Find a base port followed by a range of free UDP ports
+    async fn available_udp_range(len: u16) -> u16 {
+        loop {
+            let base = test::available_ports(1).await[0];
+            if base > u16::MAX - len {
+                continue;
+            }
+            let all_free = (base..base + len)
+                .all(|port| std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, port)).is_ok());
+            if all_free {
+                return base;
+            }
+        }
+    }
+
+    /// Try to reach a server behind a port restricted NAT from a client behind
+    /// a symmetric NAT, the server punching the provided candidates computed
+    /// from the two previous client mappings
+    async fn punch_through_symmetric_nat<F>(candidates: F) -> bool
+    where
+        F: FnOnce(SocketAddr, SocketAddr) -> Vec<SocketAddr>,
+    {
+        let avail_ports = test::available_ports(4).await;
+        let echo_srv_port = avail_ports[0];
+        let srv_nat_port = avail_ports[1];
+        let echo_srv_handle = tokio::spawn(echo_server(echo_srv_port));
+
+        let srv_fwd = Arc::new(Forwarder::with_socket(PortRestrictedNat::bind(
+            srv_nat_port,
+        )));
+        let srv_handle = {
+            let fwd = Arc::clone(&srv_fwd);
+            tokio::spawn(async move { fwd.run_quic_server(&Shutdown::new()).await })
+        };
+        let cli_nat = SymmetricNat::new(available_udp_range(8).await);
+        let cli_fwd = Forwarder::with_socket(cli_nat.clone());
+
+        // the client NAT mappings as they would be observed by the seed probes
+        let mut observed = vec![];
+        for seed_port in &avail_ports[2..4] {
+            let seed_sock = UdpSocket::bind((Ipv4Addr::LOCALHOST, *seed_port))
+                .await
+                .unwrap();
+            cli_nat.send_raw(seed_sock.local_addr().unwrap(), b"probe");
+            let (_, mapping) = seed_sock.recv_from(&mut [0; 16]).await.unwrap();
+            observed.push(mapping);
+        }
+
+        let punch_handle = {
+            let fwd = Arc::clone(&srv_fwd);
+            let candidates = candidates(observed[0], observed[1]);
+            tokio::spawn(fwd.punch_hole(candidates, String::from("cli")).unwrap())
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // stay below the first connection retry of the forwarder
+        let try_res = tokio::time::timeout(
+            Duration::from_millis(400),
+            cli_fwd.try_target(
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), srv_nat_port),
+                echo_srv_port,
+                srv_fwd.server_certificate().to_owned(),
+                FWD_PROTOCOL_VERSION,
+            ),
+        )
+        .await;
+
+        punch_handle.abort();
+        srv_handle.abort();
+        echo_srv_handle.abort();
+        matches!(try_res, Ok(Ok(())))
+    }
+
+    #[tokio::test]
+    async fn test_punch_symmetric_nat_plain() {
+        let reached = punch_through_symmetric_nat(|_, last| vec![last]).await;
+        assert!(!reached, "plain punching should not reach a symmetric NAT");
+    }
+
+    #[tokio::test]
+    async fn test_punch_symmetric_nat_predicted() {
+        let reached = punch_through_symmetric_nat(|prev, last| {
+            std::iter::once(last)
+                .chain(predict_mappings(&[prev, last], 3))
+                .collect()
+        })
+        .await;
+        assert!(reached, "predicted punching should reach a symmetric NAT");
+    }
+
+    #[tokio::test]
+    async fn test_probe_mapping() {
+        let avail_ports = test::available_ports(5).await;
+        let echo_srv_port = avail_ports[0];
+        let echo_srv_handle = tokio::spawn(echo_server(echo_srv_port));
+        let probe_srv_handle = tokio::spawn(run_probe_server(avail_ports[1], avail_ports[2]));
+        let fwd_port = avail_ports[3];
+        let (fwd, fwd_srv_handle) = start_forwarder(Forwarder::sharded(
+            fwd_port,
+            &TransportProfile::default(),
+            2,
+        ))
+        .await;
+
+        // the probe responses reach the probe whichever shard receives them,
+        // and are not seen by the endpoints
+        let seed_probe_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), avail_ports[1]);
+        for _ in 0..4 {
+            let mapping = fwd.probe_mapping(seed_probe_addr).await;
+            assert_eq!(
+                mapping,
+                Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, fwd_port))
+            );
+        }
+        let (mut cli_stream, fwd_handle) =
+            simulate_proxied_connect(avail_ports[4], &fwd, echo_srv_port).await;
+        assert_echo(&mut cli_stream, 10000).await;
+
+        // cleanup
+        fwd_handle.abort();
+        fwd_srv_handle.abort();
+        probe_srv_handle.abort();
+        echo_srv_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_nat_rebinding() {
+        let avail_ports = test::available_ports(7).await;
+        let echo_srv_port = avail_ports[0];
+        let srv_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), avail_ports[1]);
+        let echo_srv_handle = tokio::spawn(echo_server(echo_srv_port));
+        let srv_fwd = Arc::new(Forwarder::with_socket(PortRestrictedNat::bind(
+            avail_ports[1],
+        )));
+        let srv_handle = {
+            let fwd = Arc::clone(&srv_fwd);
+            tokio::spawn(async move { fwd.run_quic_server(&Shutdown::new()).await })
+        };
+        let cli_nat = PortRestrictedNat::bind(avail_ports[2]);
+        let mut cli_fwd = Forwarder::with_socket(cli_nat.clone());
+        cli_fwd.silence_timeout = Duration::from_millis(500);
+        let cli_fwd = Arc::new(cli_fwd);
+        let probe_srv_handle = tokio::spawn(run_probe_server(avail_ports[5], avail_ports[6]));
+        let seed_probe_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), avail_ports[5]);
+
+        // play the client and the seed, punching from the server to the client
+        // mapping probed through its endpoint initially and whenever the
+        // client reports the server silent
+        let repunches = Arc::new(AtomicUsize::new(0));
+        let punch_handle = {
+            let mut silent_peers = cli_fwd.subscribe_silent_peers();
+            let (srv_fwd, cli_fwd) = (Arc::clone(&srv_fwd), Arc::clone(&cli_fwd));
+            let repunches = Arc::clone(&repunches);
+            tokio::spawn(async move {
+                loop {
+                    let cli_addr = cli_fwd.probe_mapping(seed_probe_addr).await.unwrap();
+                    let punch = srv_fwd.punch_hole(vec![cli_addr.into()], String::from("cli"));
+                    tokio::spawn(punch.unwrap());
+                    assert_eq!(silent_peers.recv().await.unwrap(), srv_addr);
+                    repunches.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        };
+
+        let (mut cli_stream, fwd_handle) = simulate_proxied_connect_to(
+            avail_ports[3],
+            &cli_fwd,
+            srv_addr,
+            srv_fwd.server_certificate().to_owned(),
+            echo_srv_port,
+        )
+        .await;
+        assert_echo(&mut cli_stream, 10000).await;
+        assert_eq!(repunches.load(Ordering::Relaxed), 0);
+
+        // the server NAT drops the migrated packets until punched again, the
+        // stream must resume before the idle timeout
+        cli_nat.rebind(avail_ports[4]);
+        tokio::time::timeout(Duration::from_secs(4), assert_echo(&mut cli_stream, 10000))
+            .await
+            .expect("stream should resume on the new path");
+        assert!(repunches.load(Ordering::Relaxed) >= 1);
+
+        // cleanup
+        probe_srv_handle.abort();
+        punch_handle.abort();
+        fwd_handle.abort();
+        srv_handle.abort();
+        echo_srv_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_drain_on_shutdown() {
+        let avail_ports = test::available_ports(4).await;
+        let echo_srv_port = avail_ports[0];
+        let srv_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), avail_ports[1]);
+        let echo_srv_handle = tokio::spawn(echo_server(echo_srv_port));
+        let srv_fwd = Forwarder::new(avail_ports[1], &TransportProfile::default());
+        let cli_fwd = Arc::new(Forwarder::new(avail_ports[2], &TransportProfile::default()));
+        let shutdown = Shutdown::new();
+
+        let open_tunnel = async {
+            let (mut cli_stream, fwd_handle) = simulate_proxied_connect_to(
+                avail_ports[3],
+                &cli_fwd,
+                srv_addr,
+                srv_fwd.server_certificate().to_owned(),
+                echo_srv_port,
+            )
+            .await;
+            assert_echo(&mut cli_stream, 10000).await;
+            (cli_stream, fwd_handle)
+        };
+        // like in the perforator, the server stops accepting when the shutdown
+        // starts
+        let (mut cli_stream, fwd_handle) = tokio::select! {
+            _ = srv_fwd.run_quic_server(&shutdown) => unreachable!(),
+            tunnel = open_tunnel => tunnel,
+        };
+        let shutdown_handle = tokio::spawn(shutdown.wait());
+
+        // the transfer outlasts the grace period of the other tasks
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_echo(&mut cli_stream, 10000).await;
+        assert!(!shutdown_handle.is_finished());
+
+        // the shutdown completes once the tunnel is closed
+        drop(cli_stream);
+        tokio::time::timeout(TUNNEL_DRAIN_PERIOD, shutdown_handle)
+            .await
+            .expect("server should be drained")
+            .unwrap();
+
+        // cleanup
+        fwd_handle.abort();
+        echo_srv_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_splice_local() {
+        let avail_ports = test::available_ports(4).await;
+        let echo_srv_port = avail_ports[0];
+        let echo_srv_handle = tokio::spawn(echo_server(echo_srv_port));
+        let fwd = Arc::new(
+            Forwarder::new(avail_ports[1], &TransportProfile::default())
+                .expose_ports(HashSet::from([echo_srv_port])),
+        );
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, avail_ports[2]))
+            .await
+            .unwrap();
+        let mut cli_stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (proxied_stream, _) = listener.accept().await.unwrap();
+        let splice_handle = {
+            let fwd = Arc::clone(&fwd);
+            tokio::spawn(async move { fwd.splice_local(proxied_stream, echo_srv_port).await })
+        };
+        assert_echo(&mut cli_stream, 4).await;
+        assert_echo(&mut cli_stream, 10000).await;
+        cli_stream.shutdown().await.unwrap();
+        splice_handle.await.unwrap().unwrap();
+
+        // the exposed ports also apply to local connections
+        let mut cli_stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (proxied_stream, _) = listener.accept().await.unwrap();
+        let splice_res = fwd.splice_local(proxied_stream, avail_ports[3]).await;
+        assert_eq!(splice_res, Err(TunnelError::PortNotExposed));
+        let err = cli_stream.read(&mut [0; 1]).await.unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::ConnectionReset);
+
+        // cleanup
+        echo_srv_handle.abort();
     }
 }
@@ -1,90 +1,515 @@
-use core::panic;
+use crate::compression;
+use crate::shaping::TokenBucket;
+use chappy_util::optional_fields::OptionalFields;
+use chappy_util::tunnel_error::TunnelError;
+use quinn::{RecvStream, SendStream, VarInt};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tracing::{debug, error};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tracing::{debug, debug_span, Instrument};
+
+/// Current version of the forwarding protocol
+///
+/// Peers that predate versioning are considered as version 0. The version of
+/// the target is advertised through the seed so that queries towards legacy
+/// targets can use the legacy layout. Version 2 relays UDP datagrams and
+/// version 3 accepts multiple tunnels on a connection.
+pub const FWD_PROTOCOL_VERSION: u8 = 3;
+
+/// First version that relays UDP datagrams
+pub const UDP_DATAGRAMS_VERSION: u8 = 2;
+
+/// First version that accepts a bi stream per tunnel on a shared connection
+pub const MULTIPLEXED_TUNNELS_VERSION: u8 = 3;
+
+/// Set in a query to request the stream to be compressed with LZ4 blocks, and
+/// in the response if the target accepted
+pub const LZ4_COMPRESSION: u32 = 1;
+
+/// Capabilities supported by this version, as bit flags
+pub const FWD_CAPABILITIES: u32 = LZ4_COMPRESSION;
+
+/// Placed where legacy queries have their target port
+const VERSIONED_MARKER: u16 = 0;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct InitQuery {
     pub target_port: u16,
     pub connect_only: bool,
+    /// Version the query is encoded with, 0 being the legacy layout
+    pub version: u8,
+    pub capabilities: u32,
+    /// Only transmitted from version 1
+    pub fields: OptionalFields,
 }
 
 impl InitQuery {
-    pub async fn read<R: AsyncRead + Unpin>(recv: &mut R) -> Self {
-        let target_port = recv.read_u16().await.unwrap();
-        let connect_only = match recv.read_u8().await.unwrap() {
+    /// Create a query encoded with the highest version known by both peers
+    ///
+    /// Capabilities cannot be encoded in the legacy layout.
+    pub fn new(target_port: u16, connect_only: bool, peer_version: u8) -> Self {
+        let version = peer_version.min(FWD_PROTOCOL_VERSION);
+        Self {
+            target_port,
+            connect_only,
+            version,
+            capabilities: if version > 0 { FWD_CAPABILITIES } else { 0 },
+            fields: OptionalFields::default(),
+        }
+    }
+
+    pub async fn read<R: AsyncRead + Unpin>(recv: &mut R) -> IoResult<Self> {
+        let mut target_port = recv.read_u16().await?;
+        let mut version = 0;
+        let mut capabilities = 0;
+        if target_port == VERSIONED_MARKER {
+            version = recv.read_u8().await?;
+            if version == 0 {
+                return Err(IoError::new(
+                    IoErrorKind::InvalidData,
+                    "versioned query with version 0",
+                ));
+            }
+            target_port = recv.read_u16().await?;
+        }
+        let connect_only = match recv.read_u8().await? {
             1 => true,
             0 => false,
-            _ => panic!("expect 0 or 1"),
+            _ => {
+                return Err(IoError::new(
+                    IoErrorKind::InvalidData,
+                    "connect_only flag should be 0 or 1",
+                ))
+            }
         };
-        Self {
+        let fields = if version > 0 {
+            capabilities = recv.read_u32().await?;
+            OptionalFields::read(recv).await?
+        } else {
+            OptionalFields::default()
+        };
+        Ok(Self {
             target_port,
             connect_only,
-        }
+            version,
+            capabilities,
+            fields,
+        })
     }
 
-    pub async fn write<W: AsyncWrite + Unpin>(self, send: &mut W) {
-        send.write_u16(self.target_port).await.unwrap();
-        send.write_u8(u8::from(self.connect_only)).await.unwrap();
+    /// Fails if the stream was reset, e.g. because 0-RTT data was rejected
+    pub async fn write<W: AsyncWrite + Unpin>(self, send: &mut W) -> IoResult<()> {
+        if self.version > 0 {
+            send.write_u16(VERSIONED_MARKER).await?;
+            send.write_u8(self.version).await?;
+        }
+        send.write_u16(self.target_port).await?;
+        send.write_u8(u8::from(self.connect_only)).await?;
+        if self.version > 0 {
+            send.write_u32(self.capabilities).await?;
+            self.fields.write(send).await?;
+        }
+        Ok(())
     }
 }
 
+/// The negotiated version and capabilities are only transmitted from version 1
+/// and for successful responses, so that errors can be read by all peers
 #[derive(Clone, Debug, PartialEq)]
 pub struct InitResponse {
-    pub code: u8,
+    pub result: Result<(), TunnelError>,
+    /// Version negotiated by the server
+    pub version: u8,
+    /// Capabilities supported by both peers
+    pub capabilities: u32,
 }
 
 impl InitResponse {
-    pub async fn read<R: AsyncRead + Unpin>(recv: &mut R) -> std::io::Result<Self> {
-        let code = recv.read_u8().await?;
-        Ok(InitResponse { code })
+    /// Answer the query with the highest version known by both peers
+    pub fn new(query: &InitQuery, result: Result<(), TunnelError>) -> Self {
+        if let Err(err) = result {
+            return Self::failure(err);
+        }
+        Self {
+            result,
+            version: query.version.min(FWD_PROTOCOL_VERSION),
+            capabilities: query.capabilities & FWD_CAPABILITIES,
+        }
+    }
+
+    fn failure(err: TunnelError) -> Self {
+        Self {
+            result: Err(err),
+            version: 0,
+            capabilities: 0,
+        }
+    }
+
+    /// Answer a query that could not be decoded
+    pub fn undecodable() -> Self {
+        Self::failure(TunnelError::ProtocolVersionMismatch)
+    }
+
+    /// Read the response to a query encoded with the provided version
+    pub async fn read<R: AsyncRead + Unpin>(recv: &mut R, query_version: u8) -> IoResult<Self> {
+        let result = TunnelError::decode(recv.read_u8().await?);
+        let (version, capabilities) = if query_version > 0 && result.is_ok() {
+            (recv.read_u8().await?, recv.read_u32().await?)
+        } else {
+            (0, 0)
+        };
+        Ok(InitResponse {
+            result,
+            version,
+            capabilities,
+        })
+    }
+
+    pub async fn write<W: AsyncWrite + Unpin>(self, send: &mut W) -> IoResult<()> {
+        send.write_u8(TunnelError::encode(self.result)).await?;
+        if self.version > 0 && self.result.is_ok() {
+            send.write_u8(self.version).await?;
+            send.write_u32(self.capabilities).await?;
+        }
+        Ok(())
+    }
+}
+
+const DATAGRAM_HEADER_LENGTH: usize = 4;
+
+/// Prefix of the UDP payloads relayed as QUIC datagrams
+///
+/// The flow is identified by the source port on the client node and the
+/// target port, so that replies can be relayed back to the right socket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DatagramHeader {
+    pub src_port: u16,
+    pub target_port: u16,
+}
+
+impl DatagramHeader {
+    pub fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(DATAGRAM_HEADER_LENGTH + payload.len());
+        buf.extend_from_slice(&self.src_port.to_be_bytes());
+        buf.extend_from_slice(&self.target_port.to_be_bytes());
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    /// Split a datagram into its header and payload
+    pub fn decode(datagram: &[u8]) -> Option<(Self, &[u8])> {
+        if datagram.len() < DATAGRAM_HEADER_LENGTH {
+            return None;
+        }
+        let header = Self {
+            src_port: u16::from_be_bytes([datagram[0], datagram[1]]),
+            target_port: u16::from_be_bytes([datagram[2], datagram[3]]),
+        };
+        Some((header, &datagram[DATAGRAM_HEADER_LENGTH..]))
     }
+}
+
+/// Error code of the QUIC streams reset or stopped because the corresponding
+/// TCP connection was reset
+pub const TCP_RESET_CODE: u32 = 1;
+
+const COPY_BUFFER_SIZE: usize = 8 * 1024;
+
+/// How long the end of a TCP stream is held before being relayed while the
+/// other direction is still open
+///
+/// A peer that closed its socket answers the data still sent towards it with a
+/// RST. Once the FIN was relayed, the other end would see that reset as EPIPE
+/// instead of ECONNRESET.
+const FIN_HOLD_PERIOD: Duration = Duration::from_millis(100);
+
+/// How the data sent on a tunneled stream is encoded and scheduled
+#[derive(Clone, Debug, Default)]
+pub struct StreamOptions {
+    /// Whether LZ4 compression was negotiated
+    pub compressed: bool,
+    /// QUIC priority of the send stream
+    pub priority: i32,
+    pub rate_limit: Option<Arc<TokenBucket>>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Closing {
+    Graceful,
+    Reset,
+}
+
+/// Copy until the TCP read half is closed, then finish the QUIC stream
+///
+/// The finish is held for [`FIN_HOLD_PERIOD`] unless the other direction is
+/// already closed, so that a reset of the connection can be relayed instead.
+async fn tcp_to_quic(
+    tcp_read: &mut OwnedReadHalf,
+    quic_send: &mut SendStream,
+    options: &StreamOptions,
+    mut in_closed: watch::Receiver<bool>,
+    eof_read: &AtomicBool,
+) -> Closing {
+    let compressed = options.compressed;
+    let buf_size = if compressed {
+        compression::BLOCK_SIZE
+    } else {
+        COPY_BUFFER_SIZE
+    };
+    let mut buf = vec![0; buf_size];
+    let mut bytes_read = 0;
+    loop {
+        let read_res = tokio::select! {
+            read_res = tcp_read.read(&mut buf) => read_res,
+            // the peer stops the stream when its TCP connection is reset
+            stopped = quic_send.stopped() => {
+                debug!(?stopped, "QUIC stream stopped by peer");
+                return Closing::Reset;
+            }
+        };
+        match read_res {
+            Ok(0) => {
+                debug!(bytes_read, "completed");
+                // a half-close, the other direction might still be open
+                eof_read.store(true, Ordering::Relaxed);
+                tokio::select! {
+                    _ = in_closed.wait_for(|closed| *closed) => {}
+                    _ = tokio::time::sleep(FIN_HOLD_PERIOD) => {}
+                    stopped = quic_send.stopped() => {
+                        debug!(?stopped, "QUIC stream stopped by peer");
+                        return Closing::Reset;
+                    }
+                }
+                quic_send.finish().await.ok();
+                return Closing::Graceful;
+            }
+            Ok(len) => {
+                bytes_read += len;
+                if let Some(bucket) = &options.rate_limit {
+                    bucket.acquire(len).await;
+                }
+                let write_res = if compressed {
+                    let block = compression::encode_block(&buf[..len]);
+                    quic_send.write_all(&block).await
+                } else {
+                    quic_send.write_all(&buf[..len]).await
+                };
+                if let Err(err) = write_res {
+                    debug!(%err, "QUIC write failed");
+                    return Closing::Reset;
+                }
+            }
+            Err(err) => {
+                debug!(%err, "TCP read failed");
+                return Closing::Reset;
+            }
+        }
+    }
+}
 
-    pub async fn write<W: AsyncWrite + Unpin>(self, send: &mut W) {
-        send.write_u8(self.code).await.unwrap();
+/// Read the next chunk of the stream, None once it is finished
+async fn read_chunk(
+    quic_recv: &mut RecvStream,
+    buf: &mut Vec<u8>,
+    compressed: bool,
+) -> IoResult<Option<usize>> {
+    if compressed {
+        let more = compression::read_block(quic_recv, buf).await?;
+        Ok(more.then_some(buf.len()))
+    } else {
+        Ok(quic_recv.read(buf).await?)
     }
 }
 
-/// Async copy then shutdown writer.
-pub async fn copy<R, W>(mut reader: R, mut writer: W) -> std::io::Result<()>
-where
-    R: AsyncRead + Unpin,
-    W: AsyncWrite + Unpin,
-{
-    match tokio::io::copy(&mut reader, &mut writer).await {
-        Ok(bytes_read) => {
-            debug!(bytes_read, "completed");
-            writer.shutdown().await?;
-            Ok(())
+/// Copy until the QUIC stream is finished, then shutdown the TCP write half
+async fn quic_to_tcp(
+    quic_recv: &mut RecvStream,
+    tcp_write: &mut OwnedWriteHalf,
+    compressed: bool,
+    eof_read: &AtomicBool,
+) -> Closing {
+    let mut buf = vec![0; COPY_BUFFER_SIZE];
+    let mut bytes_read = 0;
+    loop {
+        match read_chunk(quic_recv, &mut buf, compressed).await {
+            Ok(None) => {
+                debug!(bytes_read, "completed");
+                tcp_write.shutdown().await.ok();
+                return Closing::Graceful;
+            }
+            Ok(Some(len)) => {
+                bytes_read += len;
+                if let Err(err) = tcp_write.write_all(&buf[..len]).await {
+                    debug!(%err, "TCP write failed");
+                    return Closing::Reset;
+                }
+                // after its FIN, a closed peer answers with a RST, which is
+                // received right away on loopback
+                if eof_read.load(Ordering::Relaxed) {
+                    if let Ok(Some(err)) = tcp_write.as_ref().take_error() {
+                        debug!(%err, "TCP peer closed");
+                        return Closing::Reset;
+                    }
+                }
+            }
+            Err(err) => {
+                debug!(%err, "QUIC read failed");
+                return Closing::Reset;
+            }
         }
-        Err(err) => {
-            error!(%err, "error while copying");
-            Err(err)
+    }
+}
+
+/// Relay a TCP stream through a pair of QUIC streams in both directions
+///
+/// Half-closes are forwarded as stream finishes, held for a short while if the
+/// other direction is still open. If either side is reset, the
+/// QUIC streams are reset and stopped with [`TCP_RESET_CODE`] and the TCP
+/// connection is aborted, so that both ends see ECONNRESET. If compression was
+/// negotiated, both directions are encoded as compressed blocks. The priority
+/// and rate limit only apply to the data sent by this end.
+pub async fn pipe(
+    tcp_stream: TcpStream,
+    mut quic_send: SendStream,
+    mut quic_recv: RecvStream,
+    target_port: u16,
+    options: StreamOptions,
+) {
+    let compressed = options.compressed;
+    quic_send.set_priority(options.priority).ok();
+    let (mut tcp_read, mut tcp_write) = tcp_stream.into_split();
+    let (in_closed_tx, in_closed_rx) = watch::channel(false);
+    let eof_read = AtomicBool::new(false);
+    let reset = {
+        let out_fut = tcp_to_quic(
+            &mut tcp_read,
+            &mut quic_send,
+            &options,
+            in_closed_rx,
+            &eof_read,
+        )
+        .instrument(debug_span!("cp_tcp_quic", port = target_port, compressed));
+        let in_fut = quic_to_tcp(&mut quic_recv, &mut tcp_write, compressed, &eof_read)
+            .instrument(debug_span!("cp_quic_tcp", port = target_port, compressed));
+        tokio::pin!(out_fut, in_fut);
+        let (mut out_closed, mut in_closed) = (false, false);
+        loop {
+            let closing = tokio::select! {
+                closing = &mut out_fut, if !out_closed => {
+                    out_closed = true;
+                    closing
+                }
+                closing = &mut in_fut, if !in_closed => {
+                    in_closed = true;
+                    in_closed_tx.send_replace(true);
+                    closing
+                }
+            };
+            if closing == Closing::Reset {
+                break true;
+            }
+            if out_closed && in_closed {
+                break false;
+            }
         }
+    };
+    if reset {
+        debug!("resetting connection");
+        quic_send.reset(VarInt::from_u32(TCP_RESET_CODE)).ok();
+        quic_recv.stop(VarInt::from_u32(TCP_RESET_CODE)).ok();
+        // a zero linger makes the close send a RST, the write half is forgotten
+        // to avoid sending a FIN first
+        tcp_read.as_ref().set_linger(Some(Duration::ZERO)).ok();
+        tcp_write.forget();
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::Ipv4Addr;
 
     #[tokio::test]
     async fn query_roundtrip() {
-        let original = InitQuery {
-            target_port: 80,
-            connect_only: true,
-        };
+        let mut original = InitQuery::new(80, true, FWD_PROTOCOL_VERSION);
+        original.fields.source_virtual_ip = Some(Ipv4Addr::new(172, 28, 0, 1));
+        original.fields.tags = vec![(String::from("app"), String::from("trino"))];
         let mut buf = vec![];
-        original.clone().write(&mut buf).await;
-        let result = InitQuery::read(&mut buf.as_slice()).await;
+        original.clone().write(&mut buf).await.unwrap();
+        let result = InitQuery::read(&mut buf.as_slice()).await.unwrap();
         assert_eq!(original, result);
+        InitQuery::read(&mut [0, 80, 2].as_slice())
+            .await
+            .expect_err("invalid flag should be rejected");
+    }
+
+    #[tokio::test]
+    async fn legacy_query() {
+        // query as sent by peers that predate versioning
+        let result = InitQuery::read(&mut [0, 80, 1].as_slice()).await.unwrap();
+        assert_eq!(result, InitQuery::new(80, true, 0));
+
+        // query towards a legacy peer
+        let mut query = InitQuery::new(80, false, 0);
+        query.fields.source_virtual_ip = Some(Ipv4Addr::new(172, 28, 0, 1));
+        let mut buf = vec![];
+        query.write(&mut buf).await.unwrap();
+        assert_eq!(buf, vec![0, 80, 0]);
+    }
+
+    #[tokio::test]
+    async fn newer_query() {
+        // a newer peer with unknown capabilities and fields
+        let mut buf = vec![0, 0, FWD_PROTOCOL_VERSION + 1, 0, 80, 0, 0, 0, 0, 0xff];
+        buf.extend_from_slice(&[42, 0, 1, 7, 0]);
+        let query = InitQuery::read(&mut buf.as_slice()).await.unwrap();
+        assert_eq!(query.target_port, 80);
+        let resp = InitResponse::new(&query, Ok(()));
+        assert_eq!(resp.version, FWD_PROTOCOL_VERSION);
+        assert_eq!(resp.capabilities, FWD_CAPABILITIES);
     }
 
     #[tokio::test]
     async fn response_roundtrip() {
-        let original = InitResponse { code: 1 };
+        let query = InitQuery::new(80, true, FWD_PROTOCOL_VERSION);
+        for result in [Ok(()), Err(TunnelError::PortNotExposed)] {
+            let original = InitResponse::new(&query, result);
+            let mut buf = vec![];
+            original.clone().write(&mut buf).await.unwrap();
+            let result = InitResponse::read(&mut buf.as_slice(), query.version)
+                .await
+                .unwrap();
+            assert_eq!(original, result);
+        }
+    }
+
+    #[tokio::test]
+    async fn legacy_response() {
+        let query = InitQuery::new(80, true, 0);
+        let original = InitResponse::new(&query, Err(TunnelError::TargetRefused));
         let mut buf = vec![];
-        original.clone().write(&mut buf).await;
-        let result = InitResponse::read(&mut buf.as_slice()).await.unwrap();
+        original.clone().write(&mut buf).await.unwrap();
+        assert_eq!(buf, vec![1]);
+        let result = InitResponse::read(&mut buf.as_slice(), 0).await.unwrap();
         assert_eq!(original, result);
     }
+
+    #[test]
+    fn datagram_roundtrip() {
+        let header = DatagramHeader {
+            src_port: 40000,
+            target_port: 8125,
+        };
+        let datagram = header.encode(b"metric:1|c");
+        assert_eq!(
+            DatagramHeader::decode(&datagram),
+            Some((header, b"metric:1|c".as_slice()))
+        );
+        assert_eq!(DatagramHeader::decode(&[0, 1, 2]), None);
+    }
 }
@@ -0,0 +1,126 @@
+use std::net::Ipv4Addr;
+
+/// A virtual IP in a cluster, bound to the seed by the perforator on behalf
+/// of the processes using it
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Identity {
+    pub virtual_ip: Ipv4Addr,
+    pub cluster_id: String,
+    pub cluster_size: u32,
+}
+
+impl Identity {
+    /// Parse `ip` as an identity in the cluster of the primary identity, or
+    /// `ip@cluster_id:cluster_size` as an identity in another cluster
+    pub fn parse(entry: &str, primary: &Identity) -> Result<Self, String> {
+        let entry = entry.trim();
+        let (ip, cluster) = match entry.split_once('@') {
+            Some((ip, cluster)) => (ip, Some(cluster)),
+            None => (entry, None),
+        };
+        let virtual_ip = ip.parse().map_err(|_| format!("bad virtual IP {}", ip))?;
+        let (cluster_id, cluster_size) = match cluster {
+            Some(cluster) => {
+                let (id, size) = cluster
+                    .split_once(':')
+                    .ok_or_else(|| format!("expected cluster_id:cluster_size, got {}", cluster))?;
+                let size = size
+                    .parse()
+                    .map_err(|_| format!("bad cluster size {}", size))?;
+                (id.to_owned(), size)
+            }
+            None => (primary.cluster_id.clone(), primary.cluster_size),
+        };
+        Ok(Self {
+            virtual_ip,
+            cluster_id,
+            cluster_size,
+        })
+    }
+}
+
+/// The identities served by a perforator, the first one being the primary
+#[derive(Clone, Debug)]
+pub struct Identities(Vec<Identity>);
+
+impl Identities {
+    /// Fails if an identity is repeated
+    pub fn new(primary: Identity, others: Vec<Identity>) -> Result<Self, String> {
+        let mut identities = vec![primary];
+        for identity in others {
+            if identities.iter().any(|id| {
+                id.virtual_ip == identity.virtual_ip && id.cluster_id == identity.cluster_id
+            }) {
+                return Err(format!(
+                    "{} served twice in cluster {}",
+                    identity.virtual_ip, identity.cluster_id
+                ));
+            }
+            identities.push(identity);
+        }
+        Ok(Self(identities))
+    }
+
+    pub fn primary(&self) -> &Identity {
+        &self.0[0]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Identity> {
+        self.0.iter()
+    }
+
+    /// The first identity matching the provided components, the primary one
+    /// if none is provided, e.g. by legacy interceptors
+    pub fn find(
+        &self,
+        cluster_id: Option<&str>,
+        virtual_ip: Option<Ipv4Addr>,
+    ) -> Option<&Identity> {
+        self.0.iter().find(|id| {
+            cluster_id.is_none_or(|cluster_id| cluster_id == id.cluster_id)
+                && virtual_ip.is_none_or(|ip| ip == id.virtual_ip)
+        })
+    }
+
+    /// Whether the virtual IP is served in the cluster
+    pub fn contains(&self, cluster_id: &str, virtual_ip: Ipv4Addr) -> bool {
+        self.find(Some(cluster_id), Some(virtual_ip)).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identities() {
+        let primary = Identity {
+            virtual_ip: Ipv4Addr::new(172, 28, 0, 1),
+            cluster_id: String::from("etl"),
+            cluster_size: 3,
+        };
+        let alias = Identity::parse("172.28.0.2", &primary).unwrap();
+        assert_eq!(alias.cluster_id, "etl");
+        assert_eq!(alias.cluster_size, 3);
+        let other = Identity::parse(" 172.28.0.1@bi:2 ", &primary).unwrap();
+        assert_eq!(other.cluster_id, "bi");
+        assert_eq!(other.cluster_size, 2);
+        Identity::parse("172.28.0.3@bi", &primary).unwrap_err();
+
+        let identities =
+            Identities::new(primary.clone(), vec![alias.clone(), other.clone()]).unwrap();
+        assert_eq!(identities.find(None, None), Some(&primary));
+        assert_eq!(
+            identities.find(None, Some(Ipv4Addr::new(172, 28, 0, 2))),
+            Some(&alias)
+        );
+        assert_eq!(
+            identities.find(Some("bi"), Some(primary.virtual_ip)),
+            Some(&other)
+        );
+        assert_eq!(identities.find(Some("ml"), None), None);
+        assert!(identities.contains("bi", primary.virtual_ip));
+        assert!(!identities.contains("bi", alias.virtual_ip));
+        Identities::new(primary, vec![alias.clone(), alias]).unwrap_err();
+    }
+}
@@ -1,12 +1,20 @@
 pub mod binding_service;
+pub mod compression;
 mod conf;
 pub mod forwarder;
 pub mod fwd_protocol;
+pub mod identity;
 pub mod metrics;
+#[cfg(test)]
+mod nat_emulator;
+pub mod nat_probe;
 pub mod perforator;
 pub mod quic_utils;
+pub mod shaping;
+mod sharding;
 pub mod shutdown;
 pub mod spawn;
+pub mod udp_relay;
 
 #[macro_use]
 extern crate lazy_static;
@@ -2,74 +2,176 @@ use chappy_perforator::{
     binding_service::BindingService,
     forwarder::Forwarder,
     metrics::{meter, print_metrics},
+    nat_probe::{probe_nat, NatProbe},
     perforator::Perforator,
     shutdown::{gracefull, GracefullyRunnable, Shutdown},
+    spawn::TUNNEL_DRAIN_PERIOD,
     CHAPPY_CONF,
 };
 use chappy_util::{close_tracing, init_tracing};
 use futures::FutureExt;
 use std::{sync::Arc, time::Duration};
+use tokio::time::timeout;
 use tonic::async_trait;
-use tracing::{info, info_span, Instrument};
+use tracing::{error, info, info_span, warn, Instrument};
 
-struct SrvRunnable;
+const TCP_PORT: u16 = 5000;
+const QUIC_PORT: u16 = 5001;
+/// Bound on the time spent stopping the servers and draining the tunnels
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(1);
+/// Bound on the time spent withdrawing from the seed once drained
+const DEREGISTRATION_TIMEOUT: Duration = Duration::from_secs(1);
+/// Exit code when the seed rejected a binding
+const REJECTED_EXIT_CODE: i32 = 1;
+/// Exit code when the seed aborted a served cluster, the launcher is expected
+/// to terminate the app
+const ABORTED_EXIT_CODE: i32 = 2;
+
+struct SrvRunnable {
+    binding_service: Arc<BindingService>,
+}
 
 #[async_trait]
 impl GracefullyRunnable for SrvRunnable {
     async fn run(&self, shutdown: &Shutdown) {
-        let tcp_port = 5000;
+        let tcp_port = TCP_PORT;
         let seed_addr = format!("{}:{}", CHAPPY_CONF.seed_hostname, CHAPPY_CONF.seed_port);
-        let quic_port = 5001;
+        let quic_port = QUIC_PORT;
         info!(
             perforator_tcp_port = tcp_port,
             perforator_quic_port = quic_port,
             seed_address = %seed_addr
         );
 
-        let forwarder = Arc::new(Forwarder::new(quic_port));
-        let binding_service = Arc::new(BindingService::new(quic_port));
+        // probe from the QUIC port before the forwarder endpoint binds it
+        let nat_probe = match CHAPPY_CONF.seed_nat_probe_port {
+            Some(probe_port) => {
+                probe_nat(
+                    quic_port,
+                    &CHAPPY_CONF.seed_hostname,
+                    CHAPPY_CONF.seed_port.parse().unwrap(),
+                    probe_port,
+                )
+                .await
+            }
+            None => NatProbe::unknown(),
+        };
+        info!(nat_type = ?nat_probe.nat_type, mappings = ?nat_probe.mappings);
+
+        let forwarder = Forwarder::sharded(
+            quic_port,
+            &CHAPPY_CONF.transport_profile,
+            CHAPPY_CONF.forwarder_shards,
+        )
+        .compression_policy(CHAPPY_CONF.compression_policy.clone())
+        .shaping_policy(CHAPPY_CONF.shaping_policy.clone());
+        let forwarder = match &CHAPPY_CONF.exposed_ports {
+            Some(ports) => forwarder.expose_ports(ports.clone()),
+            None => forwarder,
+        };
+        let forwarder = Arc::new(forwarder);
         let perforator = Arc::new(Perforator::new(
             Arc::clone(&forwarder),
-            binding_service,
+            Arc::clone(&self.binding_service),
             tcp_port,
         ));
-        let node_binding = perforator.bind_node(shutdown.create_guard()).await;
-
-        let mut shtdwn_hook_guard = shutdown.create_guard();
-        tokio::spawn(async move {
-            shtdwn_hook_guard.wait_shutdown().await;
-            print_metrics();
-            // TODO -> this call seems to be stuck
-            node_binding.close().await;
-        });
+        if let Err(status) = perforator.bind_node(shutdown, nat_probe).await {
+            error!(%status, "virtual IP allocation failed, shutting down");
+            return;
+        }
 
-        tokio::join!(
-            shutdown
-                .create_guard()
-                .run_cancellable(
-                    perforator.run_tcp_server(shutdown),
-                    Duration::from_millis(10)
-                )
-                .map(|o| o.ok()),
-            shutdown
-                .create_guard()
-                .run_cancellable(
-                    forwarder.run_quic_server(shutdown),
-                    Duration::from_millis(10)
-                )
-                .map(|o| o.ok()),
-        );
+        let servers = async {
+            tokio::join!(
+                shutdown
+                    .create_guard()
+                    .run_cancellable(
+                        perforator.run_tcp_server(shutdown),
+                        Duration::from_millis(10)
+                    )
+                    .map(|o| o.ok()),
+                shutdown
+                    .create_guard()
+                    .run_cancellable(
+                        forwarder.run_quic_server(shutdown),
+                        Duration::from_millis(10)
+                    )
+                    .map(|o| o.ok()),
+                shutdown
+                    .create_guard()
+                    .run_cancellable(perforator.run_repunch(shutdown), Duration::from_millis(10))
+                    .map(|o| o.ok()),
+            )
+        };
+        // the bindings cannot be fixed by retrying, e.g. if the identity is
+        // already bound by another node, and an aborted cluster gives up
+        tokio::select! {
+            _ = servers => {}
+            status = self.binding_service.rejected() => {
+                error!(%status, "seed rejected the binding, shutting down");
+            }
+            abort = self.binding_service.aborted() => {
+                error!(node = abort.virtual_ip, reason = abort.reason, "cluster aborted, shutting down");
+            }
+        }
     }
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
-    init_tracing(&format!("perf-{}", CHAPPY_CONF.virtual_ip));
+// the tunnels get cancelled before the process gives up on them
+const _: () = assert!(TUNNEL_DRAIN_PERIOD.as_millis() < SHUTDOWN_GRACE_PERIOD.as_millis());
+
+fn main() {
+    // each forwarder shard gets its own thread
+    let runtime = match CHAPPY_CONF.forwarder_shards {
+        1 => tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build(),
+        shards => tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(shards)
+            .enable_all()
+            .build(),
+    }
+    .unwrap();
+
+    let exit_code = runtime.block_on(async {
+        // the primary virtual IP is unknown until allocated by the seed
+        let label = match &CHAPPY_CONF.allocation {
+            Some(allocation) => format!("alloc-{}", allocation.primary.cluster_id),
+            None => CHAPPY_CONF.identities().primary().virtual_ip.to_string(),
+        };
+        init_tracing(&format!("perf-{}", label));
 
-    meter(
-        gracefull(SrvRunnable, Duration::from_secs(1))
-            .instrument(info_span!("perforator", virt_ip = CHAPPY_CONF.virtual_ip)),
-    )
-    .await;
-    close_tracing();
+        let binding_service = Arc::new(BindingService::new(QUIC_PORT));
+        let runnable = SrvRunnable {
+            binding_service: Arc::clone(&binding_service),
+        };
+        // Shutdown order: gracefull stops accepting and drains the tunnels,
+        // then the identities are withdrawn from the seed and the telemetry
+        // is flushed
+        let exit_code = meter(
+            async move {
+                gracefull(runnable, SHUTDOWN_GRACE_PERIOD).await;
+                if timeout(DEREGISTRATION_TIMEOUT, binding_service.deregister())
+                    .await
+                    .is_err()
+                {
+                    warn!("deregistration from the seed timed out");
+                }
+                if binding_service.rejection().is_some() {
+                    REJECTED_EXIT_CODE
+                } else if binding_service.abort().is_some() {
+                    ABORTED_EXIT_CODE
+                } else {
+                    0
+                }
+            }
+            .instrument(info_span!("perforator", virt_ip = %label)),
+        )
+        .await;
+        print_metrics();
+        close_tracing();
+        exit_code
+    });
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
 }
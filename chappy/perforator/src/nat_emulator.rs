@@ -0,0 +1,241 @@
+//! Userspace NAT emulation for QUIC endpoints, used to test hole punching
+//!
+//! The emulated NATs are plugged in as the endpoint sockets. Mappings are
+//! actual UDP sockets bound on localhost, so their port is the public address
+//! seen by the peers.
+use quinn::udp::{RecvMeta, Transmit, UdpState};
+use quinn::AsyncUdpSocket;
+use std::collections::HashSet;
+use std::io::{self, IoSliceMut};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+use tracing::trace;
+
+fn datagrams(transmit: &Transmit) -> Vec<&[u8]> {
+    match transmit.segment_size {
+        Some(segment_size) => transmit.contents.chunks(segment_size).collect(),
+        None => vec![&transmit.contents],
+    }
+}
+
+/// A UDP socket that can be polled for reception and written to right away
+#[derive(Debug)]
+struct Mapping {
+    recv: UdpSocket,
+    send: std::net::UdpSocket,
+}
+
+impl Mapping {
+    fn bind(port: u16) -> io::Result<Self> {
+        let send = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, port))?;
+        send.set_nonblocking(true)?;
+        let recv = UdpSocket::from_std(send.try_clone()?)?;
+        Ok(Self { recv, send })
+    }
+
+    fn send(&self, transmit: &Transmit) {
+        for datagram in datagrams(transmit) {
+            // like on a real network, datagrams might get lost
+            self.send.send_to(datagram, transmit.destination).ok();
+        }
+    }
+}
+
+/// Poll the socket until a datagram accepted by the filter is received
+fn poll_filtered_recv<F>(
+    socket: &UdpSocket,
+    cx: &mut Context,
+    buf: &mut IoSliceMut<'_>,
+    meta: &mut RecvMeta,
+    filter: F,
+) -> Poll<io::Result<usize>>
+where
+    F: Fn(&SocketAddr) -> bool,
+{
+    loop {
+        let mut read_buf = ReadBuf::new(&mut buf[..]);
+        match socket.poll_recv_from(cx, &mut read_buf) {
+            Poll::Ready(Ok(addr)) if filter(&addr) => {
+                let len = read_buf.filled().len();
+                *meta = RecvMeta {
+                    addr,
+                    len,
+                    stride: len,
+                    ecn: None,
+                    dst_ip: None,
+                };
+                return Poll::Ready(Ok(1));
+            }
+            Poll::Ready(Ok(addr)) => trace!(%addr, "inbound datagram filtered"),
+            // errors caused by ICMP messages are not relevant to the emulation
+            Poll::Ready(Err(_)) => continue,
+            Poll::Pending => return Poll::Pending,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PortRestrictedNatState {
+    mapping: Arc<Mapping>,
+    allowed: HashSet<SocketAddr>,
+    recv_waker: Option<Waker>,
+}
+
+/// A port restricted cone NAT: a single mapping is used for all destinations
+/// and only datagrams from addresses previously sent to are let in
+#[derive(Debug, Clone)]
+pub struct PortRestrictedNat(Arc<Mutex<PortRestrictedNatState>>);
+
+impl PortRestrictedNat {
+    pub fn bind(port: u16) -> Self {
+        Self(Arc::new(Mutex::new(PortRestrictedNatState {
+            mapping: Arc::new(Mapping::bind(port).unwrap()),
+            allowed: HashSet::new(),
+            recv_waker: None,
+        })))
+    }
+
+    /// Replace the mapping by a new one on another port, as NATs do when a
+    /// mapping expires
+    pub fn rebind(&self, port: u16) {
+        let mut state = self.0.lock().unwrap();
+        state.mapping = Arc::new(Mapping::bind(port).unwrap());
+        state.allowed.clear();
+        if let Some(waker) = state.recv_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl AsyncUdpSocket for PortRestrictedNat {
+    fn poll_send(
+        &self,
+        _state: &UdpState,
+        _cx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.0.lock().unwrap();
+        for transmit in transmits {
+            state.allowed.insert(transmit.destination);
+            state.mapping.send(transmit);
+        }
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.0.lock().unwrap();
+        let poll = poll_filtered_recv(
+            &state.mapping.recv,
+            cx,
+            &mut bufs[0],
+            &mut meta[0],
+            |addr| state.allowed.contains(addr),
+        );
+        if poll.is_pending() {
+            // a rebinding replaces the polled socket
+            state.recv_waker = Some(cx.waker().clone());
+        }
+        poll
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.0.lock().unwrap().mapping.recv.local_addr()
+    }
+}
+
+#[derive(Debug)]
+struct SymmetricNatState {
+    /// Destinations and their dedicated mapping, in allocation order
+    mappings: Vec<(SocketAddr, Arc<Mapping>)>,
+    next_port: u16,
+    recv_waker: Option<Waker>,
+}
+
+/// A symmetric NAT: each destination gets its own mapping, allocated
+/// sequentially from a base port, and each mapping only lets in datagrams
+/// from its destination
+#[derive(Debug, Clone)]
+pub struct SymmetricNat(Arc<Mutex<SymmetricNatState>>);
+
+impl SymmetricNat {
+    pub fn new(base_port: u16) -> Self {
+        Self(Arc::new(Mutex::new(SymmetricNatState {
+            mappings: vec![],
+            next_port: base_port,
+            recv_waker: None,
+        })))
+    }
+
+    fn mapping(&self, destination: SocketAddr) -> Arc<Mapping> {
+        let mut state = self.0.lock().unwrap();
+        if let Some((_, mapping)) = state.mappings.iter().find(|(d, _)| *d == destination) {
+            return Arc::clone(mapping);
+        }
+        // like real NATs, skip the ports that are already in use
+        let mapping = loop {
+            let port = state.next_port;
+            state.next_port += 1;
+            if let Ok(mapping) = Mapping::bind(port) {
+                break Arc::new(mapping);
+            }
+        };
+        trace!(%destination, port = state.next_port - 1, "new mapping");
+        state.mappings.push((destination, Arc::clone(&mapping)));
+        // the new mapping needs to be polled by the receiver
+        if let Some(waker) = state.recv_waker.take() {
+            waker.wake();
+        }
+        mapping
+    }
+
+    /// Send a datagram through the NAT outside of the QUIC endpoint
+    pub fn send_raw(&self, destination: SocketAddr, payload: &[u8]) {
+        let mapping = self.mapping(destination);
+        mapping.send.send_to(payload, destination).unwrap();
+    }
+}
+
+impl AsyncUdpSocket for SymmetricNat {
+    fn poll_send(
+        &self,
+        _state: &UdpState,
+        _cx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<io::Result<usize>> {
+        for transmit in transmits {
+            self.mapping(transmit.destination).send(transmit);
+        }
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.0.lock().unwrap();
+        for (destination, mapping) in &state.mappings {
+            let poll = poll_filtered_recv(&mapping.recv, cx, &mut bufs[0], &mut meta[0], |addr| {
+                addr == destination
+            });
+            if poll.is_ready() {
+                return poll;
+            }
+        }
+        state.recv_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+    }
+}
@@ -0,0 +1,314 @@
+use chappy_seed::nat_probe::{ProbeRequest, ProbeResponse};
+use chappy_seed::NatType;
+use futures::future::poll_fn;
+use quinn::udp::{RecvMeta, Transmit, UdpState};
+use quinn::AsyncUdpSocket;
+use std::io::{self, IoSliceMut};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{timeout_at, Instant};
+use tracing::{debug, instrument, warn};
+
+/// The NAT classification and the mappings observed by the seed
+#[derive(Clone, Debug, PartialEq)]
+pub struct NatProbe {
+    pub nat_type: NatType,
+    /// Consecutive mappings observed for the probed port, oldest first
+    pub mappings: Vec<SocketAddrV4>,
+}
+
+impl NatProbe {
+    pub fn unknown() -> Self {
+        Self {
+            nat_type: NatType::Unknown,
+            mappings: vec![],
+        }
+    }
+}
+
+const PROBE_ATTEMPTS: usize = 3;
+const PROBE_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Send a probe until a response with the matching transaction id is
+/// received, or until all attempts timed out
+async fn probe(sock: &UdpSocket, seed_addr: SocketAddr, change_port: bool) -> Option<SocketAddrV4> {
+    let req = ProbeRequest {
+        transaction_id: rand::random(),
+        change_port,
+    };
+    let mut buf = [0; 64];
+    for _ in 0..PROBE_ATTEMPTS {
+        if let Err(err) = sock.send_to(&req.encode(), seed_addr).await {
+            warn!(%err, %seed_addr, "failed to send probe");
+            return None;
+        }
+        let deadline = Instant::now() + PROBE_TIMEOUT;
+        while let Ok(recv_res) = timeout_at(deadline, sock.recv_from(&mut buf)).await {
+            let Ok((len, _)) = recv_res else { break };
+            match ProbeResponse::decode(&buf[..len]) {
+                Some(resp) if resp.transaction_id == req.transaction_id => {
+                    return Some(resp.observed_addr)
+                }
+                _ => debug!("discarding stale or invalid probe response"),
+            }
+        }
+    }
+    None
+}
+
+/// Probe responses received before the pending probes lag
+const PROBE_RESPONSES_CAPACITY: usize = 16;
+
+/// Socket of a forwarder endpoint that can also probe the current mapping of
+/// its port, e.g. after the NAT rebound it
+///
+/// The probes are sent from the endpoint socket itself, so that they go
+/// through the same mapping as the QUIC packets, and their responses are
+/// diverted from the endpoint to the pending probes.
+#[derive(Debug, Clone)]
+pub struct ProbingSocket {
+    socket: Arc<Mutex<Box<dyn AsyncUdpSocket>>>,
+    udp_state: Arc<UdpState>,
+    responses: broadcast::Sender<ProbeResponse>,
+}
+
+impl ProbingSocket {
+    pub fn new(socket: Box<dyn AsyncUdpSocket>) -> Self {
+        Self {
+            socket: Arc::new(Mutex::new(socket)),
+            udp_state: Arc::new(UdpState::new()),
+            responses: broadcast::channel(PROBE_RESPONSES_CAPACITY).0,
+        }
+    }
+
+    /// Wrap another socket bound to the same port, its probe responses being
+    /// delivered to the probes of this socket
+    pub fn sibling(&self, socket: Box<dyn AsyncUdpSocket>) -> Self {
+        Self {
+            socket: Arc::new(Mutex::new(socket)),
+            udp_state: Arc::clone(&self.udp_state),
+            responses: self.responses.clone(),
+        }
+    }
+
+    /// Send a probe to the seed until a response with the matching transaction
+    /// id is received, or until all attempts timed out
+    pub async fn probe_mapping(&self, seed_addr: SocketAddr) -> Option<SocketAddrV4> {
+        let req = ProbeRequest {
+            transaction_id: rand::random(),
+            change_port: false,
+        };
+        let transmit = Transmit {
+            destination: seed_addr,
+            ecn: None,
+            contents: req.encode().to_vec().into(),
+            segment_size: None,
+            src_ip: None,
+        };
+        let mut responses = self.responses.subscribe();
+        for _ in 0..PROBE_ATTEMPTS {
+            let send_res = poll_fn(|cx| {
+                self.socket.lock().unwrap().poll_send(
+                    &self.udp_state,
+                    cx,
+                    std::slice::from_ref(&transmit),
+                )
+            })
+            .await;
+            if let Err(err) = send_res {
+                warn!(%err, %seed_addr, "failed to send probe");
+                return None;
+            }
+            let deadline = Instant::now() + PROBE_TIMEOUT;
+            while let Ok(recv_res) = timeout_at(deadline, responses.recv()).await {
+                match recv_res {
+                    Ok(resp) if resp.transaction_id == req.transaction_id => {
+                        return Some(resp.observed_addr)
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => {
+                        debug!("discarding stale probe response")
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+        None
+    }
+}
+
+impl AsyncUdpSocket for ProbingSocket {
+    fn poll_send(
+        &self,
+        state: &UdpState,
+        cx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<io::Result<usize>> {
+        self.socket.lock().unwrap().poll_send(state, cx, transmits)
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let received = ready!(self.socket.lock().unwrap().poll_recv(cx, bufs, meta))?;
+            // keep the other datagrams contiguous for the endpoint
+            let mut kept = 0;
+            for i in 0..received {
+                let len = meta[i].len;
+                if meta[i].stride == len {
+                    if let Some(resp) = ProbeResponse::decode(&bufs[i][..len]) {
+                        self.responses.send(resp).ok();
+                        continue;
+                    }
+                }
+                if kept != i {
+                    let (head, tail) = bufs.split_at_mut(i);
+                    head[kept][..len].copy_from_slice(&tail[0][..len]);
+                    meta[kept] = meta[i];
+                }
+                kept += 1;
+            }
+            if kept > 0 {
+                return Poll::Ready(Ok(kept));
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.lock().unwrap().local_addr()
+    }
+
+    fn may_fragment(&self) -> bool {
+        self.socket.lock().unwrap().may_fragment()
+    }
+}
+
+/// Resolve the IPv4 address of a seed probe port
+pub async fn resolve_seed(seed_hostname: &str, port: u16) -> Option<SocketAddr> {
+    match tokio::net::lookup_host((seed_hostname, port))
+        .await
+        .map(|mut addrs| addrs.find(SocketAddr::is_ipv4))
+    {
+        Ok(Some(addr)) => Some(addr),
+        _ => {
+            warn!(seed_hostname, "seed hostname could not be resolved");
+            None
+        }
+    }
+}
+
+/// Classify the NAT from the mappings observed by the two seed ports
+///
+/// With a single seed IP, address restricted cones cannot be told apart from
+/// full cones and are reported as such. Nodes that are not behind a NAT are
+/// also reported as full cones.
+fn classify(
+    change_port_reply: bool,
+    primary_mapping: SocketAddrV4,
+    secondary_mapping: SocketAddrV4,
+) -> NatType {
+    if primary_mapping != secondary_mapping {
+        NatType::Symmetric
+    } else if change_port_reply {
+        NatType::FullCone
+    } else {
+        NatType::PortRestricted
+    }
+}
+
+/// Probe the two seed UDP ports from the provided local port to determine the
+/// NAT type and its successive mappings
+///
+/// The filtering test (reply from the other seed port) needs to run first,
+/// before the node sends anything to the secondary port and opens the
+/// corresponding filter.
+#[instrument(name = "nat_probe", skip(seed_hostname))]
+pub async fn probe_nat(
+    local_port: u16,
+    seed_hostname: &str,
+    primary_port: u16,
+    secondary_port: u16,
+) -> NatProbe {
+    let sock = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, None).unwrap();
+    sock.set_reuse_port(true).unwrap();
+    sock.set_nonblocking(true).unwrap();
+    let src_addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, local_port);
+    sock.bind(&src_addr.into()).unwrap();
+    let sock = UdpSocket::from_std(sock.into()).unwrap();
+
+    let Some(primary_addr) = resolve_seed(seed_hostname, primary_port).await else {
+        return NatProbe::unknown();
+    };
+    let seed_ip = primary_addr.ip();
+    let secondary_addr = SocketAddr::new(seed_ip, secondary_port);
+
+    let change_port_reply = probe(&sock, primary_addr, true).await.is_some();
+    let primary_mapping = probe(&sock, primary_addr, false).await;
+    let secondary_mapping = probe(&sock, secondary_addr, false).await;
+    let nat_probe = match (primary_mapping, secondary_mapping) {
+        (Some(primary), Some(secondary)) => NatProbe {
+            nat_type: classify(change_port_reply, primary, secondary),
+            mappings: vec![primary, secondary],
+        },
+        _ => {
+            warn!("seed did not answer NAT probes");
+            NatProbe::unknown()
+        }
+    };
+    debug!(
+        ?primary_mapping,
+        ?secondary_mapping,
+        change_port_reply,
+        nat_type = ?nat_probe.nat_type,
+        "NAT classified"
+    );
+    nat_probe
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chappy_seed::nat_probe::run_probe_server;
+    use chappy_util::test;
+
+    #[test]
+    fn test_classify() {
+        let mapping_1 = SocketAddrV4::new(Ipv4Addr::new(52, 1, 2, 3), 40000);
+        let mapping_2 = SocketAddrV4::new(Ipv4Addr::new(52, 1, 2, 3), 40001);
+        assert_eq!(classify(true, mapping_1, mapping_1), NatType::FullCone);
+        assert_eq!(
+            classify(false, mapping_1, mapping_1),
+            NatType::PortRestricted
+        );
+        assert_eq!(classify(false, mapping_1, mapping_2), NatType::Symmetric);
+        assert_eq!(classify(true, mapping_1, mapping_2), NatType::Symmetric);
+    }
+
+    #[tokio::test]
+    async fn test_detect_no_nat() {
+        let avail_ports = test::available_ports(3).await;
+        let srv_handle = tokio::spawn(run_probe_server(avail_ports[0], avail_ports[1]));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let nat_probe =
+            probe_nat(avail_ports[2], "localhost", avail_ports[0], avail_ports[1]).await;
+        assert_eq!(nat_probe.nat_type, NatType::FullCone);
+        let local_mapping = SocketAddrV4::new(Ipv4Addr::LOCALHOST, avail_ports[2]);
+        assert_eq!(nat_probe.mappings, vec![local_mapping, local_mapping]);
+        srv_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_detect_seed_unreachable() {
+        let avail_ports = test::available_ports(3).await;
+        let nat_probe =
+            probe_nat(avail_ports[2], "localhost", avail_ports[0], avail_ports[1]).await;
+        assert_eq!(nat_probe, NatProbe::unknown());
+    }
+}
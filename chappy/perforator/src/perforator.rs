@@ -1,21 +1,31 @@
-use crate::binding_service::NodeBindingHandle;
-use crate::spawn::spawn_task;
+use crate::identity::Identity;
+use crate::nat_probe::{resolve_seed, NatProbe};
+use crate::spawn::{spawn_task, spawn_tunnel_task};
 use crate::{
     binding_service::BindingService, forwarder::Forwarder, shutdown::Shutdown,
-    shutdown::ShutdownGuard,
+    shutdown::ShutdownGuard, CHAPPY_CONF,
 };
-use chappy_seed::{Address, AddressConv};
-use chappy_util::{awaitable_map::AwaitableMap, protocol::ParsedTcpStream};
-use futures::{StreamExt, TryStreamExt};
-use std::net::Ipv4Addr;
-use std::sync::Arc;
+use chappy_seed::{Address, AddressConv, ClientBindingResponse, PunchStatus};
+use chappy_util::awaitable_map::AwaitableMap;
+use chappy_util::optional_fields::OptionalFields;
+use chappy_util::protocol::{LifecycleEvent, ParsedTcpStream};
+use chappy_util::tunnel_error::TunnelError;
+use futures::future::BoxFuture;
+use futures::{FutureExt, TryStreamExt};
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
 use tokio::time::timeout;
+use tonic::Status;
 use tracing::{debug, debug_span, error, instrument, trace, warn};
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 struct TargetVirtualAddress {
+    /// Virtual IPs are only unique within a cluster
+    pub cluster_id: String,
     pub ip: Ipv4Addr,
     pub port: u16,
 }
@@ -25,18 +35,36 @@ struct TargetResolvedAddress {
     pub natted_address: Address,
     pub tgt_port: u16,
     pub certificate_der: Vec<u8>,
+    pub protocol_version: u8,
 }
 
+#[derive(Debug, Clone)]
+struct PortMapping {
+    pub target: TargetVirtualAddress,
+    /// Fields provided by the interceptor, relayed to the target
+    pub fields: OptionalFields,
+}
+
+/// Number of client bindings attempted until the punch is acknowledged
+const BIND_CLIENT_ATTEMPTS: usize = 3;
+
 /// Map source ports to target virtual addresses
-type PortMappings = Arc<AwaitableMap<u16, TargetVirtualAddress>>;
+type PortMappings = Arc<AwaitableMap<u16, PortMapping>>;
 
 /// Map virtual addresses to resolved ones
 type AddressMappings = Arc<AwaitableMap<TargetVirtualAddress, TargetResolvedAddress>>;
 
+/// Map the addresses of the bound targets to their virtual IP and the
+/// identity that bound them
+type BoundTargets = Arc<Mutex<HashMap<SocketAddr, (Identity, Ipv4Addr)>>>;
+
 #[derive(Clone)]
 pub struct Perforator {
     port_mappings: PortMappings,
     address_mappings: AddressMappings,
+    bound_targets: BoundTargets,
+    /// Targets currently being bound again
+    repunching: Arc<Mutex<HashSet<SocketAddr>>>,
     forwarder: Arc<Forwarder>,
     binding_service: Arc<BindingService>,
     tcp_port: u16,
@@ -51,38 +79,139 @@ impl Perforator {
         Self {
             port_mappings: Arc::new(AwaitableMap::new()),
             address_mappings: Arc::new(AwaitableMap::new()),
+            bound_targets: Arc::new(Mutex::new(HashMap::new())),
+            repunching: Arc::new(Mutex::new(HashSet::new())),
             binding_service,
             forwarder,
             tcp_port,
         }
     }
 
-    #[instrument(name = "reg_cli", skip(self))]
+    /// The identity served by this perforator that the interceptor registers
+    /// for, the fields of legacy interceptors pointing to the primary one
+    fn source_identity(fields: &OptionalFields) -> Result<&'static Identity, TunnelError> {
+        CHAPPY_CONF
+            .identities()
+            .find(fields.cluster_id.as_deref(), fields.source_virtual_ip)
+            .ok_or_else(|| {
+                error!(
+                    cluster_id = ?fields.cluster_id,
+                    virt_ip = ?fields.source_virtual_ip,
+                    "identity not served"
+                );
+                TunnelError::UnknownIdentity
+            })
+    }
+
+    /// Request the seed to punch a hole from the target, retrying until the
+    /// punch is acknowledged
+    ///
+    /// Without an observed mapping, the seed punches the address of the
+    /// binding request and the predicted mappings of this node.
+    async fn bind_target(
+        &self,
+        identity: &Identity,
+        tgt_virt: Ipv4Addr,
+        observed_mapping: Option<SocketAddrV4>,
+    ) -> Result<ClientBindingResponse, TunnelError> {
+        // TODO bind only once per target virtual IP
+        let mut punch_resp = self
+            .binding_service
+            .bind_client(identity, tgt_virt.to_string(), observed_mapping)
+            .await?;
+        for _ in 1..BIND_CLIENT_ATTEMPTS {
+            if !matches!(
+                punch_resp.punch_status(),
+                PunchStatus::Failed | PunchStatus::TimedOut
+            ) {
+                break;
+            }
+            warn!(status = ?punch_resp.punch_status(), "punch not acknowledged, retrying");
+            if let Some(addr) = &punch_resp.target_nated_addr {
+                // the NAT of this node drops the punch of the target until a
+                // packet was sent towards it
+                self.open_nat_towards(AddressConv(addr.clone()).into(), tgt_virt);
+            }
+            punch_resp = self
+                .binding_service
+                .bind_client(identity, tgt_virt.to_string(), observed_mapping)
+                .await?;
+        }
+        if punch_resp.failed_punch_request {
+            warn!("seed failed to send punch request");
+        }
+        if let Some(addr) = &punch_resp.target_nated_addr {
+            self.bound_targets.lock().unwrap().insert(
+                AddressConv(addr.clone()).into(),
+                (identity.clone(), tgt_virt),
+            );
+        }
+        Ok(punch_resp)
+    }
+
+    /// Punch from this node towards the target, so that the next punch of the
+    /// target gets through the NAT of this node
+    fn open_nat_towards(&self, nated_addr: SocketAddr, tgt_virt: Ipv4Addr) {
+        match self
+            .forwarder
+            .punch_hole(vec![nated_addr], tgt_virt.to_string())
+        {
+            Ok(punch_fut) => {
+                tokio::spawn(async move {
+                    if let Err(err) = punch_fut.await {
+                        debug!(%err, "punch did not reach the target");
+                    }
+                });
+            }
+            Err(err) => warn!(%err, "punch towards target could not be emitted"),
+        }
+    }
+
+    #[instrument(name = "reg_cli", skip(self, fields))]
     async fn register_client(
         &self,
         src_port: u16,
         tgt_virt: Ipv4Addr,
         tgt_port: u16,
-    ) -> anyhow::Result<()> {
+        mut fields: OptionalFields,
+    ) -> Result<(), TunnelError> {
         trace!("starting...");
         let start = Instant::now();
+        let identity = Self::source_identity(&fields)?;
         let virtual_addr = TargetVirtualAddress {
+            cluster_id: identity.cluster_id.clone(),
             ip: tgt_virt,
             port: tgt_port,
         };
-        self.port_mappings.insert(src_port, virtual_addr.clone());
-        // TODO bind only once per target virtual IP
-        let punch_resp = self.binding_service.bind_client(tgt_virt.to_string()).await;
-        if punch_resp.failed_punch_request {
-            warn!("seed failed to send punch request");
+        fields.source_virtual_ip = Some(identity.virtual_ip);
+        fields.cluster_id = Some(identity.cluster_id.clone());
+        self.port_mappings.insert(
+            src_port,
+            PortMapping {
+                target: virtual_addr.clone(),
+                fields,
+            },
+        );
+        if CHAPPY_CONF
+            .identities()
+            .contains(&identity.cluster_id, tgt_virt)
+        {
+            // probe the target like remote ones are, without involving the seed
+            self.forwarder.probe_local(tgt_port).await?;
+            debug!(duration = ?start.elapsed(), "completed locally");
+            return Ok(());
         }
+        let punch_resp = self.bind_target(identity, tgt_virt, None).await?;
         let natted_addr = punch_resp.target_nated_addr.unwrap();
+        // versions unknown to this perforator are downgraded when encoding
+        let protocol_version = u8::try_from(punch_resp.target_protocol_version).unwrap_or(u8::MAX);
         self.address_mappings.insert(
             virtual_addr,
             TargetResolvedAddress {
                 natted_address: natted_addr.clone(),
                 tgt_port,
                 certificate_der: punch_resp.server_certificate.clone(),
+                protocol_version,
             },
         );
         self.forwarder
@@ -90,28 +219,105 @@ impl Perforator {
                 AddressConv(natted_addr).into(),
                 tgt_port,
                 punch_resp.server_certificate,
+                protocol_version,
             )
             .await?;
         debug!(duration = ?start.elapsed(), "completed");
         Ok(())
     }
 
+    /// Wait for the barrier of the cluster of the source identity
+    #[instrument(name = "wait_clust", skip(self, fields))]
+    async fn wait_cluster(
+        &self,
+        stage: Option<String>,
+        fields: OptionalFields,
+    ) -> Result<(), TunnelError> {
+        trace!("starting...");
+        let identity = Self::source_identity(&fields)?;
+        self.binding_service.wait_cluster(identity, stage).await
+    }
+
+    /// Relay a lifecycle event of the app of the source identity to the seed
+    #[instrument(name = "status", skip(self, fields))]
+    async fn report_status(
+        &self,
+        event: Option<LifecycleEvent>,
+        fields: OptionalFields,
+    ) -> Result<(), TunnelError> {
+        trace!("starting...");
+        let event = event.ok_or(TunnelError::ProtocolVersionMismatch)?;
+        let identity = Self::source_identity(&fields)?;
+        self.binding_service.report_event(identity, event).await
+    }
+
+    /// Open a local relay for the datagrams towards the target
+    ///
+    /// Returns the relay port with a future that completes once the relay is
+    /// closed.
+    #[instrument(name = "reg_udp", skip(self, fields, relay_guard))]
+    async fn register_udp(
+        &self,
+        tgt_virt: Ipv4Addr,
+        tgt_port: u16,
+        fields: OptionalFields,
+        mut relay_guard: ShutdownGuard,
+    ) -> Result<(u16, BoxFuture<'static, ()>), TunnelError> {
+        trace!("starting...");
+        let identity = Self::source_identity(&fields)?;
+        if CHAPPY_CONF
+            .identities()
+            .contains(&identity.cluster_id, tgt_virt)
+        {
+            // the datagrams can be sent to the target directly
+            let closed = async move { relay_guard.wait_shutdown().await };
+            return Ok((tgt_port, closed.boxed()));
+        }
+        let punch_resp = self.bind_target(identity, tgt_virt, None).await?;
+        let protocol_version = u8::try_from(punch_resp.target_protocol_version).unwrap_or(u8::MAX);
+        let (relay_port, relay_handle) = self
+            .forwarder
+            .open_udp_relay(
+                relay_guard,
+                AddressConv(punch_resp.target_nated_addr.unwrap()).into(),
+                tgt_port,
+                punch_resp.server_certificate,
+                protocol_version,
+            )
+            .await?;
+        Ok((relay_port, relay_handle.map(|_| ()).boxed()))
+    }
+
     /// Forward a TCP stream from a registered port
     #[instrument(name = "fwd_conn", skip_all)]
     async fn forward_conn(&self, stream: TcpStream) {
         trace!("starting...");
         // TODO adjust timeout duration
         let src_port = stream.peer_addr().unwrap().port();
-        let target_virtual_address = timeout(
+        let port_mapping = timeout(
             Duration::from_secs(1),
             self.port_mappings.get(src_port, |_| false),
         )
         .await
         .unwrap();
+        let target = &port_mapping.target;
+        if CHAPPY_CONF
+            .identities()
+            .contains(&target.cluster_id, target.ip)
+        {
+            if let Err(err) = self
+                .forwarder
+                .splice_local(stream, port_mapping.target.port)
+                .await
+            {
+                error!(%err, "local target failed");
+            }
+            return;
+        }
         // TODO adjust timeout duration
         let target_address = timeout(
             Duration::from_secs(3),
-            self.address_mappings.get(target_virtual_address, |_| false),
+            self.address_mappings.get(port_mapping.target, |_| false),
         )
         .await
         .unwrap();
@@ -126,45 +332,195 @@ impl Perforator {
             target_nated_addr,
             target_address.tgt_port,
             target_address.certificate_der,
+            target_address.protocol_version,
+            port_mapping.fields,
         );
         fwd_fut.await;
     }
 
+    /// Bind all the identities served by this perforator, starting with the
+    /// primary one if its virtual IP is allocated by the seed
     #[instrument(name = "reg_node", skip_all)]
-    pub async fn bind_node(&self, punch_stream_shdn_guard: ShutdownGuard) -> NodeBindingHandle {
+    pub async fn bind_node(&self, shutdown: &Shutdown, nat_probe: NatProbe) -> Result<(), Status> {
+        let allocated = match &CHAPPY_CONF.allocation {
+            Some(allocation) => Some(
+                self.binding_service
+                    .allocate_node(allocation, nat_probe.clone())
+                    .await?,
+            ),
+            None => None,
+        };
+        for identity in CHAPPY_CONF.identities().iter() {
+            // the allocated identity is already bound as a node
+            let nat_probe = (allocated != Some(identity)).then(|| nat_probe.clone());
+            self.bind_identity(shutdown.create_guard(), identity, nat_probe)
+                .await;
+        }
+        Ok(())
+    }
+
+    /// Bind the identity as a node of its cluster unless no NAT probe is
+    /// provided, and serve the punch requests of the clients that target it
+    #[instrument(
+        name = "reg_id",
+        skip_all,
+        fields(clust = identity.cluster_id, virt = %identity.virtual_ip)
+    )]
+    async fn bind_identity(
+        &self,
+        punch_stream_shdn_guard: ShutdownGuard,
+        identity: &'static Identity,
+        nat_probe: Option<NatProbe>,
+    ) {
         trace!("starting...");
         let server_certificate = self.forwarder.server_certificate().to_owned();
         let binding_service = Arc::clone(&self.binding_service);
         let fwd_ref = Arc::clone(&self.forwarder);
-        let node_binding = binding_service.bind_node().await;
+        if let Some(nat_probe) = nat_probe {
+            binding_service.bind_node(identity, nat_probe).await;
+        }
         spawn_task(
             punch_stream_shdn_guard,
             tracing::Span::current(),
             async move {
-                let stream = binding_service.bind_server(server_certificate).await;
+                let stream = match binding_service
+                    .bind_server(identity, server_certificate)
+                    .await
+                {
+                    Ok(stream) => stream,
+                    Err(status) => {
+                        error!(%status, "server binding failed");
+                        return;
+                    }
+                };
                 // For each incoming server punch request, send a random packet to punch
-                // a hole in the NAT
+                // a hole in the NAT, also spraying the predicted client mappings if any,
+                // and report to the seed once the packets are emitted. The seed also
+                // notifies cluster aborts on this stream.
                 debug!("subscribe to hole punching requests");
                 let stream_res = stream
-                    .map(|punch_req| {
-                        let punch_req = punch_req.unwrap();
-                        let client_natted_addr = punch_req.client_nated_addr.unwrap();
-                        Ok(fwd_ref.punch_hole(
-                            AddressConv(client_natted_addr).into(),
-                            punch_req.client_virtual_ip,
-                        ))
+                    .map_ok(|punch_req| {
+                        let punch_res =
+                            match punch_req.abort {
+                                Some(abort) => {
+                                    warn!(
+                                        node = abort.virtual_ip,
+                                        reason = abort.reason,
+                                        "cluster aborted"
+                                    );
+                                    binding_service.record_abort(abort);
+                                    None
+                                }
+                                None => {
+                                    let client_natted_addrs = punch_req
+                                        .client_nated_addr
+                                        .into_iter()
+                                        .chain(punch_req.predicted_client_addrs)
+                                        .map(|addr| AddressConv(addr).into())
+                                        .collect();
+                                    Some(fwd_ref.punch_hole(
+                                        client_natted_addrs,
+                                        punch_req.client_virtual_ip,
+                                    ))
+                                }
+                            };
+                        let binding_service = Arc::clone(&binding_service);
+                        async move {
+                            let Some(punch_res) = punch_res else {
+                                return Ok(());
+                            };
+                            let punch_fut = match punch_res {
+                                Ok(punch_fut) => punch_fut,
+                                Err(err) => {
+                                    error!(%err, "punch could not be emitted");
+                                    binding_service
+                                        .report_punch(punch_req.punch_id, PunchStatus::Failed)
+                                        .await;
+                                    return Ok(());
+                                }
+                            };
+                            // the punch is only acknowledged once the client
+                            // answered it
+                            let status = match punch_fut.await {
+                                Ok(()) => PunchStatus::Punched,
+                                Err(err) => {
+                                    warn!(%err, "punch did not reach the client");
+                                    PunchStatus::Failed
+                                }
+                            };
+                            binding_service
+                                .report_punch(punch_req.punch_id, status)
+                                .await;
+                            Ok(())
+                        }
                     })
                     .try_for_each_concurrent(None, |f| f)
                     .await;
                 if let Err(err) = stream_res {
                     error!(%err, "subscription to hole punching closed early");
                 } else {
-                    error!("punch stream expected to last until cancelled");
+                    debug!("punch stream closed by the seed");
                 }
             },
         );
         debug!("completed");
-        node_binding
+    }
+
+    /// Bind the targets that the forwarder reports as silent again, so that
+    /// the seed requests them to punch a hole towards this node
+    ///
+    /// This restores the path after the NAT of the target dropped its state,
+    /// or after the mapping of this node was rebound. The seed does not see
+    /// the QUIC port through the gRPC connection, so the new mapping is probed
+    /// first when the seed answers NAT probes.
+    #[instrument(name = "repunch", skip_all)]
+    pub async fn run_repunch(&self, shutdown: &Shutdown) {
+        let mut silent_targets = self.forwarder.subscribe_silent_peers();
+        loop {
+            let nated_addr = match silent_targets.recv().await {
+                Ok(addr) => addr,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "silent targets skipped");
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            let bound_target = self.bound_targets.lock().unwrap().get(&nated_addr).cloned();
+            let Some((identity, tgt_virt)) = bound_target else {
+                warn!(tgt_nat = %nated_addr, "silent target was not bound");
+                continue;
+            };
+            // the tunnels towards a target share its path
+            if !self.repunching.lock().unwrap().insert(nated_addr) {
+                continue;
+            }
+            let perforator = self.clone();
+            spawn_task(
+                shutdown.create_guard(),
+                debug_span!("repunch_tgt", %tgt_virt, tgt_nat = %nated_addr),
+                async move {
+                    let observed_mapping = perforator.probe_mapping().await;
+                    let bind_res = perforator
+                        .bind_target(&identity, tgt_virt, observed_mapping)
+                        .await;
+                    if let Err(err) = bind_res {
+                        warn!(%err, "re-punch failed");
+                    }
+                    perforator.repunching.lock().unwrap().remove(&nated_addr);
+                },
+            );
+        }
+    }
+
+    /// Probe the current mapping of the forwarder port from the primary seed
+    /// probe port, if the seed answers NAT probes
+    async fn probe_mapping(&self) -> Option<SocketAddrV4> {
+        CHAPPY_CONF.seed_nat_probe_port?;
+        let seed_port = CHAPPY_CONF.seed_port.parse().ok()?;
+        let seed_addr = resolve_seed(&CHAPPY_CONF.seed_hostname, seed_port).await?;
+        let mapping = self.forwarder.probe_mapping(seed_addr).await;
+        debug!(?mapping, "mapping probed");
+        mapping
     }
 
     #[instrument(name = "tcp_srv", skip_all)]
@@ -177,28 +533,91 @@ impl Perforator {
             let src_port = stream.peer_addr().unwrap().port();
             let perforator = self.clone();
             let shutdown_guard = shutdown.create_guard();
-            spawn_task(
+            // the datagram relays outlive the registration connection
+            let relay_guard = shutdown.create_guard();
+            // forwarded connections are drained on shutdown
+            spawn_tunnel_task(
                 shutdown_guard,
                 debug_span!("tcp_conn", src_port),
                 async move {
-                    let parsed_stream = ParsedTcpStream::from(stream).await;
+                    let parsed_stream = match ParsedTcpStream::from(stream).await {
+                        Ok(parsed_stream) => parsed_stream,
+                        Err(err) => {
+                            warn!(%err, "malformed control message");
+                            return;
+                        }
+                    };
                     match parsed_stream {
                         ParsedTcpStream::ClientRegistration {
                             source_port,
                             target_virtual_ip,
                             target_port,
+                            fields,
                             response_writer,
+                            ..
                         } => {
                             let reg_fut = perforator.register_client(
                                 source_port,
                                 target_virtual_ip,
                                 target_port,
+                                fields,
                             );
                             match reg_fut.await {
                                 Ok(_) => response_writer.write_success().await,
-                                Err(_) => response_writer.write_failure().await,
+                                Err(err) => response_writer.write_failure(err).await,
+                            };
+                        }
+                        ParsedTcpStream::UdpRegistration {
+                            target_virtual_ip,
+                            target_port,
+                            fields,
+                            response_writer,
+                        } => {
+                            let reg_fut = perforator.register_udp(
+                                target_virtual_ip,
+                                target_port,
+                                fields,
+                                relay_guard,
+                            );
+                            match reg_fut.await {
+                                Ok((relay_port, relay_closed)) => {
+                                    // the interceptor forgets the relay port once
+                                    // the registration is closed
+                                    let registration =
+                                        response_writer.write_success(relay_port).await;
+                                    if registration.is_some() {
+                                        relay_closed.await;
+                                    }
+                                }
+                                Err(err) => response_writer.write_failure(err).await,
                             };
                         }
+                        ParsedTcpStream::ClusterWait {
+                            stage,
+                            fields,
+                            response_writer,
+                        } => {
+                            let res = perforator.wait_cluster(stage, fields).await;
+                            response_writer.write(res).await;
+                        }
+                        ParsedTcpStream::NodeStatus {
+                            event,
+                            fields,
+                            response_writer,
+                        } => {
+                            let res = perforator.report_status(event, fields).await;
+                            response_writer.write(res).await;
+                        }
+                        ParsedTcpStream::VirtualIpQuery {
+                            fields,
+                            response_writer,
+                        } => {
+                            let res = Self::source_identity(&fields).map(|id| id.virtual_ip);
+                            response_writer.write(res).await;
+                        }
+                        ParsedTcpStream::VersionQuery { response_writer } => {
+                            response_writer.write().await;
+                        }
                         ParsedTcpStream::Raw(stream) => {
                             perforator.forward_conn(stream).await;
                         }
@@ -1,39 +1,150 @@
 use crate::{CHAPPY_CONF, PUNCH_SERVER_NAME, SERVER_NAME};
 
-use quinn::{ClientConfig, Connection, Endpoint, ServerConfig, TransportConfig};
+use chappy_util::tunnel_error::TunnelError;
+use quinn::congestion::{BbrConfig, CubicConfig, NewRenoConfig};
+use quinn::{
+    ClientConfig, Connecting, Connection, ConnectionError, Endpoint, ServerConfig, TransportConfig,
+    VarInt, ZeroRttAccepted,
+};
+use quinn_proto::TransportError;
 use std::{
+    collections::HashMap,
     net::SocketAddr,
-    sync::Arc,
+    str::FromStr,
+    sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
-use tracing::{error, instrument, warn};
+use tracing::{error, instrument, trace, warn};
+
+/// Congestion control algorithm of the QUIC connections
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CongestionController {
+    Cubic,
+    NewReno,
+    Bbr,
+}
+
+impl FromStr for CongestionController {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "cubic" => Ok(Self::Cubic),
+            "newreno" => Ok(Self::NewReno),
+            "bbr" => Ok(Self::Bbr),
+            _ => Err(format!("unknown congestion controller {}", s)),
+        }
+    }
+}
+
+/// Transport parameters applied to both the server and the client side of
+/// the forwarder connections
+#[derive(Clone, Debug)]
+pub struct TransportProfile {
+    /// Bytes a peer can send on a stream before it is acknowledged
+    pub stream_receive_window: u32,
+    /// Bytes a peer can send on all the streams of a connection
+    pub receive_window: u64,
+    /// Bytes this endpoint can have in flight on a connection
+    pub send_window: u64,
+    pub congestion_controller: CongestionController,
+    pub mtu_discovery: bool,
+    pub keep_alive_ms: u64,
+    pub idle_timeout_ms: u32,
+    /// Time without receiving anything from the target after which the path
+    /// of a client connection is punched again, should be below the idle
+    /// timeout
+    pub silence_timeout_ms: u64,
+}
+
+impl Default for TransportProfile {
+    /// Quinn defaults, with a keepalive that holds NAT mappings open
+    fn default() -> Self {
+        Self {
+            stream_receive_window: 1_250_000,
+            receive_window: VarInt::MAX.into_inner(),
+            send_window: 10_000_000,
+            congestion_controller: CongestionController::Cubic,
+            mtu_discovery: true,
+            keep_alive_ms: 1000,
+            idle_timeout_ms: 5000,
+            silence_timeout_ms: 2000,
+        }
+    }
+}
+
+impl TransportProfile {
+    pub fn transport_config(&self) -> TransportConfig {
+        let mut transport = TransportConfig::default();
+        transport
+            .max_concurrent_uni_streams(0_u8.into())
+            .stream_receive_window(self.stream_receive_window.into())
+            .receive_window(VarInt::from_u64(self.receive_window).unwrap_or(VarInt::MAX))
+            .send_window(self.send_window)
+            .keep_alive_interval(Some(Duration::from_millis(self.keep_alive_ms)))
+            .max_idle_timeout(Some(VarInt::from_u32(self.idle_timeout_ms).into()));
+        if !self.mtu_discovery {
+            transport.mtu_discovery_config(None);
+        }
+        match self.congestion_controller {
+            CongestionController::Cubic => {
+                transport.congestion_controller_factory(Arc::new(CubicConfig::default()))
+            }
+            CongestionController::NewReno => {
+                transport.congestion_controller_factory(Arc::new(NewRenoConfig::default()))
+            }
+            CongestionController::Bbr => {
+                transport.congestion_controller_factory(Arc::new(BbrConfig::default()))
+            }
+        };
+        transport
+    }
+}
 
 /// Returns default server configuration.
-pub fn configure_server(certificate_der: Vec<u8>, private_key_der: Vec<u8>) -> ServerConfig {
+///
+/// Session tickets are stored by the server and taken when a client resumes
+/// with them, the stateless ticketer being disabled, so the 0-RTT data of a
+/// client is accepted at most once and cannot be replayed on this server.
+pub fn configure_server(
+    certificate_der: Vec<u8>,
+    private_key_der: Vec<u8>,
+    transport: Arc<TransportConfig>,
+) -> ServerConfig {
     let priv_key = rustls::PrivateKey(private_key_der);
     let cert_chain = vec![rustls::Certificate(certificate_der)];
 
     let mut server_config = ServerConfig::with_single_cert(cert_chain, priv_key).unwrap();
-    Arc::get_mut(&mut server_config.transport)
-        .unwrap()
-        .max_concurrent_uni_streams(0_u8.into())
-        .keep_alive_interval(Some(Duration::from_secs(1)))
-        .max_idle_timeout(Some(Duration::from_secs(5).try_into().unwrap()));
-
+    server_config.transport_config(transport);
     server_config
 }
 
 /// Builds quinn client config and trusts given certificates.
-fn configure_client(server_cert: Vec<u8>) -> ClientConfig {
+fn build_client(server_cert: Vec<u8>) -> ClientConfig {
     let mut certs = rustls::RootCertStore::empty();
     certs.add(&rustls::Certificate(server_cert)).unwrap();
+    ClientConfig::with_root_certificates(certs)
+}
 
-    let mut transport = TransportConfig::default();
-    transport.keep_alive_interval(Some(Duration::from_secs(1)));
-    transport.max_idle_timeout(Some(Duration::from_secs(5).try_into().unwrap()));
+lazy_static! {
+    /// Client configs by target certificate, each holding the session tickets
+    /// received from that target
+    ///
+    /// All targets share the same server name, so sharing a single ticket
+    /// store would offer a ticket to targets that cannot decrypt it.
+    static ref CLIENT_CONFIGS: Mutex<HashMap<Vec<u8>, ClientConfig>> = Mutex::new(HashMap::new());
+}
 
-    let mut cli = ClientConfig::with_root_certificates(certs);
-    cli.transport_config(Arc::new(transport));
+/// Get the client config of the target, reusing it across connections so that
+/// they can resume the TLS session
+fn configure_client(server_cert: Vec<u8>, transport: Arc<TransportConfig>) -> ClientConfig {
+    let mut cli = CLIENT_CONFIGS
+        .lock()
+        .unwrap()
+        .entry(server_cert)
+        .or_insert_with_key(|cert| build_client(cert.clone()))
+        .clone();
+    cli.transport_config(transport);
     cli
 }
 
@@ -58,35 +169,105 @@ pub fn configure_punch_client() -> ClientConfig {
     ClientConfig::with_root_certificates(certs)
 }
 
-#[instrument(name = "quic_conn_creation", skip_all)]
+/// TLS alerts are carried as transport errors in the crypto code range
+fn is_crypto_error(err: &ConnectionError) -> bool {
+    match err {
+        ConnectionError::TransportError(TransportError { code, .. }) => {
+            (0x100..0x200).contains(&u64::from(*code))
+        }
+        _ => false,
+    }
+}
+
+/// Open a connection that can send data in the first flight, if a session
+/// ticket was received from the target on a previous connection
+///
+/// The data is sent before the target is authenticated. It cannot be replayed
+/// as long as the target is a forwarder, whose server config stores the
+/// tickets and takes them on use (see `configure_server`), so non idempotent
+/// queries can be sent to forwarders only. If the target rejects the 0-RTT
+/// data (e.g. it was restarted or the ticket was already used), the
+/// `ZeroRttAccepted` resolves to false and the streams opened on the
+/// connection fail. Without a ticket, the handshake already started is
+/// returned so that it can be completed with [`handshake_with_retry`].
+pub fn connect_0rtt(
+    endpoint: &Endpoint,
+    target_server_addr: SocketAddr,
+    target_server_certificate_der: Vec<u8>,
+    transport: Arc<TransportConfig>,
+) -> Result<(Connection, ZeroRttAccepted), Connecting> {
+    let cli_conf = configure_client(target_server_certificate_der, transport);
+    let connecting = endpoint
+        .connect_with(cli_conf, target_server_addr, SERVER_NAME)
+        .unwrap();
+    connecting
+        .into_0rtt()
+        .inspect_err(|_| trace!("no session ticket for target"))
+}
+
+/// Open a connection with a full handshake, which authenticates the target
+/// before any data is sent
 pub async fn connect_with_retry(
     endpoint: &Endpoint,
     target_server_addr: SocketAddr,
     target_server_certificate_der: Vec<u8>,
-) -> Option<Connection> {
-    let cli_conf = configure_client(target_server_certificate_der);
+    transport: Arc<TransportConfig>,
+) -> Result<Connection, TunnelError> {
+    let cli_conf = configure_client(target_server_certificate_der.clone(), transport.clone());
+    let connecting = endpoint
+        .connect_with(cli_conf, target_server_addr, SERVER_NAME)
+        .unwrap();
+    handshake_with_retry(
+        connecting,
+        endpoint,
+        target_server_addr,
+        target_server_certificate_der,
+        transport,
+    )
+    .await
+}
+
+/// Complete a handshake that was already started, retrying with new
+/// connections if it times out
+#[instrument(name = "quic_conn_creation", skip_all)]
+pub async fn handshake_with_retry(
+    connecting: Connecting,
+    endpoint: &Endpoint,
+    target_server_addr: SocketAddr,
+    target_server_certificate_der: Vec<u8>,
+    transport: Arc<TransportConfig>,
+) -> Result<Connection, TunnelError> {
+    let cli_conf = configure_client(target_server_certificate_der, transport);
     let start = Instant::now();
-    let quic_con;
+    let mut endpoint_fut = connecting;
     // TODO: investigate whether this retry is necessary or whether
     // QUIC/Quinn is handling retries internally
     loop {
-        let endpoint_fut = endpoint
-            .connect_with(cli_conf.clone(), target_server_addr, SERVER_NAME)
-            .unwrap();
         let timed_endpoint_fut = tokio::time::timeout(Duration::from_millis(500), endpoint_fut);
-        if let Ok(endpoint_res) = timed_endpoint_fut.await {
-            quic_con = endpoint_res.unwrap();
-            break;
-        } else if start.elapsed() > Duration::from_millis(CHAPPY_CONF.connection_timeout_ms) {
-            error!(
-                elapsed=?start.elapsed(),
-                timeout=?Duration::from_millis(CHAPPY_CONF.connection_timeout_ms),
-                "connection timeout",
-            );
-            return None;
-        } else {
-            warn!("timeout, retrying...")
+        match timed_endpoint_fut.await {
+            Ok(Ok(quic_con)) => return Ok(quic_con),
+            Ok(Err(err)) if is_crypto_error(&err) => {
+                error!(%err, "target certificate rejected");
+                return Err(TunnelError::CertificateMismatch);
+            }
+            Ok(Err(err)) => {
+                error!(%err, "connection failed");
+                return Err(TunnelError::QuicConnectFailed);
+            }
+            Err(_)
+                if start.elapsed() > Duration::from_millis(CHAPPY_CONF.connection_timeout_ms) =>
+            {
+                error!(
+                    elapsed=?start.elapsed(),
+                    timeout=?Duration::from_millis(CHAPPY_CONF.connection_timeout_ms),
+                    "connection timeout",
+                );
+                return Err(TunnelError::QuicConnectFailed);
+            }
+            Err(_) => warn!("timeout, retrying..."),
         }
+        endpoint_fut = endpoint
+            .connect_with(cli_conf.clone(), target_server_addr, SERVER_NAME)
+            .unwrap();
     }
-    Some(quic_con)
 }
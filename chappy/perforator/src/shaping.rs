@@ -0,0 +1,129 @@
+use crate::conf::parse_port_map;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Share of a second of traffic that can be sent in a burst
+const BURST_DIVISOR: u64 = 10;
+
+#[derive(Debug)]
+struct BucketState {
+    /// Negative when the bucket is in debt
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Rate limiter shared by all the tunnels towards a port
+///
+/// Acquiring more tokens than available puts the bucket in debt, so that the
+/// waiting tunnels are served in the order they acquired their tokens.
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// Tokens per second, one token per byte
+    rate: f64,
+    burst: f64,
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        assert!(bytes_per_sec > 0, "rate limit should be positive");
+        let burst = (bytes_per_sec / BURST_DIVISOR).max(1) as f64;
+        Self {
+            rate: bytes_per_sec as f64,
+            burst,
+            state: Mutex::new(BucketState {
+                tokens: burst,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Wait until the bytes can be sent
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(state.updated).as_secs_f64() * self.rate;
+            state.tokens = (state.tokens + refill).min(self.burst) - bytes as f64;
+            state.updated = now;
+            if state.tokens >= 0. {
+                return;
+            }
+            Duration::from_secs_f64(-state.tokens / self.rate)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Priorities and rate limits of the tunneled streams according to their
+/// target port
+///
+/// Each side of a tunnel applies its own policy to the data it sends. The
+/// priority orders the streams that share a QUIC connection, the higher being
+/// sent first. The rate limit of a port is shared by all its tunnels.
+#[derive(Clone, Debug, Default)]
+pub struct ShapingPolicy {
+    priorities: HashMap<u16, i32>,
+    rate_limits: HashMap<u16, Arc<TokenBucket>>,
+}
+
+impl ShapingPolicy {
+    /// Parse priorities and rate limits in bytes per second, both formatted
+    /// as `port=value,port=value`
+    pub fn with_ports(priorities: &str, rate_limits: &str) -> Result<Self, String> {
+        let rate_limits = parse_port_map::<u64>(rate_limits)?
+            .into_iter()
+            .map(|(port, rate)| match rate {
+                0 => Err(format!("rate limit of port {} should be positive", port)),
+                rate => Ok((port, Arc::new(TokenBucket::new(rate)))),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            priorities: parse_port_map(priorities)?,
+            rate_limits,
+        })
+    }
+
+    /// Priority of the streams towards the port, 0 by default
+    pub fn priority(&self, port: u16) -> i32 {
+        self.priorities.get(&port).copied().unwrap_or(0)
+    }
+
+    pub fn rate_limit(&self, port: u16) -> Option<Arc<TokenBucket>> {
+        self.rate_limits.get(&port).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_token_bucket() {
+        let bucket = TokenBucket::new(10_000_000);
+        let start = Instant::now();
+        // the burst is served immediately
+        bucket.acquire(1_000_000).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+        for _ in 0..20 {
+            bucket.acquire(100_000).await;
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(190), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = ShapingPolicy::with_ports("8080=10, 9000=-1", "9000=1000000").unwrap();
+        assert_eq!(policy.priority(8080), 10);
+        assert_eq!(policy.priority(9000), -1);
+        assert_eq!(policy.priority(80), 0);
+        assert!(policy.rate_limit(9000).is_some());
+        assert!(policy.rate_limit(8080).is_none());
+        ShapingPolicy::with_ports("", "9000=0").unwrap_err();
+        ShapingPolicy::with_ports("8080=high", "").unwrap_err();
+    }
+}
@@ -0,0 +1,130 @@
+use nix::libc::{
+    setsockopt, sock_filter, sock_fprog, socklen_t, SOL_SOCKET, SO_ATTACH_REUSEPORT_CBPF,
+};
+use quinn_proto::{ConnectionId, ConnectionIdGenerator};
+use rand::Rng;
+use std::io::{Error as IoError, Result as IoResult};
+use std::os::fd::AsRawFd;
+use std::time::Duration;
+
+/// Length of the connection IDs issued by the forwarder endpoints
+const CID_LENGTH: usize = 8;
+/// The shard is encoded in the first byte of the connection IDs
+pub const MAX_SHARDS: usize = 256;
+
+/// Issue random connection IDs whose first byte identifies the shard
+///
+/// Peers use the connection IDs issued by an endpoint as destination of the
+/// packets they send to it, which enables the reuseport filter to route them
+/// to the endpoint that owns the connection.
+pub struct ShardedCidGenerator {
+    shard: usize,
+    shards: usize,
+}
+
+impl ShardedCidGenerator {
+    pub fn new(shard: usize, shards: usize) -> Self {
+        assert!(shard < shards && shards <= MAX_SHARDS, "invalid shard");
+        Self { shard, shards }
+    }
+}
+
+impl ConnectionIdGenerator for ShardedCidGenerator {
+    fn generate_cid(&mut self) -> ConnectionId {
+        let mut rng = rand::thread_rng();
+        let mut bytes = [0; CID_LENGTH];
+        rng.fill(&mut bytes[..]);
+        // largest multiple of the shard count that keeps the byte in range
+        let slots = (255 - self.shard) / self.shards + 1;
+        bytes[0] = (rng.gen_range(0..slots) * self.shards + self.shard) as u8;
+        ConnectionId::new(&bytes)
+    }
+
+    fn cid_len(&self) -> usize {
+        CID_LENGTH
+    }
+
+    fn cid_lifetime(&self) -> Option<Duration> {
+        None
+    }
+}
+
+const fn bpf_stmt(code: u16, k: u32) -> sock_filter {
+    sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+const fn bpf_jump(code: u16, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter { code, jt, jf, k }
+}
+
+// classic BPF opcodes, see linux/filter.h
+const BPF_LD_B_ABS: u16 = 0x30;
+const BPF_JMP_JSET_K: u16 = 0x45;
+const BPF_JMP_JA: u16 = 0x05;
+const BPF_ALU_MOD_K: u16 = 0x94;
+const BPF_RET_A: u16 = 0x16;
+
+/// Route the datagrams received by the reuseport group of the socket to the
+/// socket at the index of the shard encoded in the destination connection ID
+///
+/// The index of a socket is its position in the bind order. The program runs
+/// on the UDP payload, i.e. the QUIC packet. The destination connection ID
+/// starts at byte 1 of short header packets and at byte 6 of long header
+/// packets. The first packets of a client carry a connection ID it picked
+/// randomly, so they are consistently routed to an arbitrary shard, which then
+/// issues its own connection IDs.
+pub fn attach_reuseport_filter(socket: &impl AsRawFd, shards: usize) -> IoResult<()> {
+    let mut program = [
+        bpf_stmt(BPF_LD_B_ABS, 0),
+        // long header bit
+        bpf_jump(BPF_JMP_JSET_K, 0x80, 2, 0),
+        bpf_stmt(BPF_LD_B_ABS, 1),
+        bpf_stmt(BPF_JMP_JA, 1),
+        bpf_stmt(BPF_LD_B_ABS, 6),
+        bpf_stmt(BPF_ALU_MOD_K, shards as u32),
+        bpf_stmt(BPF_RET_A, 0),
+    ];
+    let prog = sock_fprog {
+        len: program.len() as u16,
+        filter: program.as_mut_ptr(),
+    };
+    // SAFETY: the program outlives the call, the kernel copies it
+    let res = unsafe {
+        setsockopt(
+            socket.as_raw_fd(),
+            SOL_SOCKET,
+            SO_ATTACH_REUSEPORT_CBPF,
+            std::ptr::addr_of!(prog).cast(),
+            std::mem::size_of::<sock_fprog>() as socklen_t,
+        )
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(IoError::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cid_shard() {
+        for shards in [1, 3, 4, 256] {
+            for shard in 0..shards {
+                let mut gen = ShardedCidGenerator::new(shard, shards);
+                for _ in 0..100 {
+                    let cid = gen.generate_cid();
+                    assert_eq!(cid.len(), CID_LENGTH);
+                    assert_eq!(cid[0] as usize % shards, shard);
+                }
+            }
+        }
+    }
+}
@@ -5,18 +5,52 @@ use std::time::Duration;
 use tokio::task::JoinHandle;
 use tracing::{Instrument, Span};
 
+/// Grace period of the tasks that don't carry any transfer
+const TASK_GRACE_PERIOD: Duration = Duration::from_millis(50);
+
+/// Grace period for the tunnels to complete their transfers once the shutdown
+/// started, shorter than the grace period of the whole process so that they
+/// are drained before the identities are withdrawn from the seed
+pub const TUNNEL_DRAIN_PERIOD: Duration = Duration::from_millis(800);
+
 pub fn spawn_task<T>(
     shutdown_guard: ShutdownGuard,
     span: Span,
     future: T,
 ) -> JoinHandle<Result<T::Output, Cancelled>>
+where
+    T: Future + Send + 'static,
+    T::Output: Send + 'static,
+{
+    spawn_cancellable(shutdown_guard, span, future, TASK_GRACE_PERIOD)
+}
+
+/// Spawn a task that carries tunnels, which is drained on shutdown
+pub fn spawn_tunnel_task<T>(
+    shutdown_guard: ShutdownGuard,
+    span: Span,
+    future: T,
+) -> JoinHandle<Result<T::Output, Cancelled>>
+where
+    T: Future + Send + 'static,
+    T::Output: Send + 'static,
+{
+    spawn_cancellable(shutdown_guard, span, future, TUNNEL_DRAIN_PERIOD)
+}
+
+fn spawn_cancellable<T>(
+    shutdown_guard: ShutdownGuard,
+    span: Span,
+    future: T,
+    grace_period: Duration,
+) -> JoinHandle<Result<T::Output, Cancelled>>
 where
     T: Future + Send + 'static,
     T::Output: Send + 'static,
 {
     tokio::spawn(meter(
         shutdown_guard
-            .run_cancellable(future, Duration::from_millis(50))
+            .run_cancellable(future, grace_period)
             .instrument(span),
     ))
 }
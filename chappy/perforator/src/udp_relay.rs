@@ -0,0 +1,229 @@
+use crate::fwd_protocol::DatagramHeader;
+use quinn::{Connection, SendDatagramError};
+use std::collections::{HashMap, HashSet};
+use std::io::Result as IoResult;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::{interval, Instant, MissedTickBehavior};
+use tracing::{debug, error, trace, warn};
+
+/// Larger than any payload that fits in a QUIC datagram
+const RECV_BUFFER_SIZE: usize = 65536;
+
+/// Flows without datagrams in either direction for this long are evicted
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Send the payload on the connection, returns false if it was lost
+fn send_datagram(conn: &Connection, header: DatagramHeader, payload: &[u8]) -> bool {
+    match conn.send_datagram(header.encode(payload).into()) {
+        Ok(()) => true,
+        Err(SendDatagramError::TooLarge) => {
+            // like on a network with a small MTU, oversized datagrams are dropped
+            warn!(len = payload.len(), "datagram too large, dropped");
+            true
+        }
+        Err(err) => {
+            debug!(%err, "datagram relay closed");
+            false
+        }
+    }
+}
+
+/// Relay sockets of the flows of a client connection, that its replies are
+/// sent back through
+pub type ReplyRoutes = Arc<Mutex<HashMap<DatagramHeader, Arc<UdpSocket>>>>;
+
+/// Relay the datagrams sent to the local relay socket to the target port of
+/// the peer
+///
+/// The replies are sent back by [`route_replies`], which should run on the
+/// connection with the same routes. Runs until the QUIC connection is closed.
+pub async fn relay_client(
+    conn: Connection,
+    relay_sock: Arc<UdpSocket>,
+    target_port: u16,
+    routes: ReplyRoutes,
+) {
+    let mut buf = vec![0; RECV_BUFFER_SIZE];
+    loop {
+        let recv = tokio::select! {
+            recv = relay_sock.recv_from(&mut buf) => recv,
+            reason = conn.closed() => {
+                debug!(%reason, "datagram relay closed");
+                break;
+            }
+        };
+        let (len, src) = match recv {
+            Ok(recv) => recv,
+            Err(err) => {
+                error!(%err, "relay socket failed");
+                break;
+            }
+        };
+        if !src.ip().is_loopback() {
+            warn!(%src, "datagram from remote address dropped");
+            continue;
+        }
+        let header = DatagramHeader {
+            src_port: src.port(),
+            target_port,
+        };
+        routes
+            .lock()
+            .unwrap()
+            .entry(header)
+            .or_insert_with(|| Arc::clone(&relay_sock));
+        if !send_datagram(&conn, header, &buf[..len]) {
+            break;
+        }
+    }
+    routes
+        .lock()
+        .unwrap()
+        .retain(|_, sock| !Arc::ptr_eq(sock, &relay_sock));
+}
+
+/// Send the replies received on a client connection back to their source
+/// port, through the relay socket of their flow
+///
+/// A single task reads the datagrams of a connection shared by multiple
+/// relays. Runs until the QUIC connection is closed.
+pub async fn route_replies(conn: Connection, routes: ReplyRoutes) {
+    loop {
+        let datagram = match conn.read_datagram().await {
+            Ok(datagram) => datagram,
+            Err(err) => {
+                trace!(%err, "no more replies on connection");
+                return;
+            }
+        };
+        let Some((header, payload)) = DatagramHeader::decode(&datagram) else {
+            warn!("truncated datagram dropped");
+            continue;
+        };
+        let Some(relay_sock) = routes.lock().unwrap().get(&header).cloned() else {
+            debug!(?header, "reply without relay dropped");
+            continue;
+        };
+        trace!(?header, len = payload.len(), "reply datagram");
+        relay_sock
+            .send_to(payload, (Ipv4Addr::LOCALHOST, header.src_port))
+            .await
+            .ok();
+    }
+}
+
+/// A client socket relayed to a local target
+struct Flow {
+    sock: Arc<UdpSocket>,
+    last_active: Arc<Mutex<Instant>>,
+    replies: AbortHandle,
+}
+
+async fn open_flow_socket(target_port: u16) -> IoResult<UdpSocket> {
+    let sock = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    sock.connect((Ipv4Addr::LOCALHOST, target_port)).await?;
+    Ok(sock)
+}
+
+/// Relay the replies of the target to the peer
+async fn relay_replies(
+    conn: Connection,
+    sock: Arc<UdpSocket>,
+    header: DatagramHeader,
+    last_active: Arc<Mutex<Instant>>,
+) {
+    let mut buf = vec![0; RECV_BUFFER_SIZE];
+    loop {
+        let len = match sock.recv(&mut buf).await {
+            Ok(len) => len,
+            Err(err) => {
+                // e.g. ICMP port unreachable if nothing listens on the target
+                debug!(%err, ?header, "target socket error");
+                continue;
+            }
+        };
+        *last_active.lock().unwrap() = Instant::now();
+        if !send_datagram(&conn, header, &buf[..len]) {
+            return;
+        }
+    }
+}
+
+/// Deliver the datagrams received on the connection to their target port on
+/// localhost
+///
+/// Each flow gets its own local socket so that the target sees a distinct
+/// source for each client socket. Runs until the QUIC connection is closed,
+/// the flows being evicted once idle.
+pub async fn relay_server(conn: Connection, exposed_ports: Option<Arc<HashSet<u16>>>) {
+    let mut flows: HashMap<DatagramHeader, Flow> = HashMap::new();
+    let mut reply_tasks = JoinSet::new();
+    let mut eviction = interval(FLOW_IDLE_TIMEOUT / 2);
+    eviction.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        let datagram = tokio::select! {
+            datagram = conn.read_datagram() => match datagram {
+                Ok(datagram) => datagram,
+                Err(err) => {
+                    trace!(%err, "no more datagrams on connection");
+                    return;
+                }
+            },
+            _ = eviction.tick() => {
+                flows.retain(|header, flow| {
+                    let idle = flow.last_active.lock().unwrap().elapsed() >= FLOW_IDLE_TIMEOUT;
+                    if idle {
+                        debug!(?header, "idle datagram flow evicted");
+                        flow.replies.abort();
+                    }
+                    !idle
+                });
+                continue;
+            }
+            // reap the reply tasks that ended
+            Some(_) = reply_tasks.join_next(), if !reply_tasks.is_empty() => continue,
+        };
+        let Some((header, payload)) = DatagramHeader::decode(&datagram) else {
+            warn!("truncated datagram dropped");
+            continue;
+        };
+        let flow = match flows.get(&header) {
+            Some(flow) => flow,
+            None => {
+                if exposed_ports
+                    .as_ref()
+                    .is_some_and(|p| !p.contains(&header.target_port))
+                {
+                    warn!(?header, "target port not exposed, datagram dropped");
+                    continue;
+                }
+                let sock = match open_flow_socket(header.target_port).await {
+                    Ok(sock) => Arc::new(sock),
+                    Err(err) => {
+                        error!(%err, ?header, "flow socket could not be opened, datagram dropped");
+                        continue;
+                    }
+                };
+                debug!(?header, "new datagram flow");
+                let last_active = Arc::new(Mutex::new(Instant::now()));
+                let replies = reply_tasks.spawn(relay_replies(
+                    conn.clone(),
+                    Arc::clone(&sock),
+                    header,
+                    Arc::clone(&last_active),
+                ));
+                flows.entry(header).or_insert(Flow {
+                    sock,
+                    last_active,
+                    replies,
+                })
+            }
+        };
+        *flow.last_active.lock().unwrap() = Instant::now();
+        flow.sock.send(payload).await.ok();
+    }
+}
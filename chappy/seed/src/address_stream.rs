@@ -15,12 +15,19 @@ pub struct PunchRequestStream {
 }
 
 impl PunchRequestStream {
+    #[allow(clippy::result_large_err)]
     pub fn new(recv: UnboundedReceiver<ServerPunchRequest>, parent_span: Span) -> Self {
         let span = parent_span.clone();
         let inner = UnboundedReceiverStream::new(recv)
             .map(move |preq| {
-                let addr = AddressConv(preq.client_nated_addr.as_ref().unwrap().clone());
-                debug!(parent: &span, tgt_nat=%addr, "forwarding punch request");
+                match &preq.client_nated_addr {
+                    Some(addr) => {
+                        let addr = AddressConv(addr.clone());
+                        debug!(parent: &span, tgt_nat=%addr, "forwarding punch request");
+                    }
+                    // cluster aborts carry no client address
+                    None => debug!(parent: &span, "forwarding notification"),
+                }
                 Ok(preq)
             })
             .boxed();
@@ -1,36 +1,77 @@
 use super::message::*;
 use super::state::*;
 use super::summary::*;
+use crate::gc::{GcStats, Retention};
 use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
+use tokio::time::{interval, MissedTickBehavior};
 use tracing::{debug, error, warn};
 
 pub struct ClusterManager {
     tx: mpsc::UnboundedSender<(String, Message)>,
 }
 
-struct ClusterMap(HashMap<String, TracedClusterState>);
+struct ClusterMap {
+    clusters: HashMap<String, TracedClusterState>,
+    /// Watchers of clusters that were not created yet
+    pending_watchers: HashMap<String, Vec<mpsc::UnboundedSender<MembershipEvent>>>,
+}
 
 impl ClusterMap {
     fn forward(&mut self, cluster_id: String, message: Message) {
-        if let Message::BindNodeStart { cluster_size, .. } = &message {
+        if let Message::ListSummaries { tx } = message {
+            let mut summaries: Vec<_> = self
+                .clusters
+                .iter()
+                .map(|(cluster_id, state)| (cluster_id.clone(), state.summary()))
+                .collect();
+            summaries.sort_by(|a, b| a.0.cmp(&b.0));
+            if tx.send(summaries).is_err() {
+                error!("caller dropped before getting the summaries")
+            }
+        } else if let Message::BindNodeStart {
+            cluster_size,
+            resolution_timeout,
+            ..
+        } = &message
+        {
             let state = self
-                .0
+                .clusters
                 .entry(cluster_id.to_owned())
                 .and_modify(|cluster_state| {
                     if cluster_state.finished() {
                         warn!(cluster_id, "Cluster already exists, replacing it...");
-                        *cluster_state = TracedClusterState::new(&cluster_id, *cluster_size);
+                        *cluster_state = TracedClusterState::new(
+                            &cluster_id,
+                            *cluster_size,
+                            *resolution_timeout,
+                        );
                     }
                 })
                 .or_insert_with(|| {
                     debug!(cluster_id, "Creating new cluster");
-                    TracedClusterState::new(&cluster_id, *cluster_size)
+                    TracedClusterState::new(&cluster_id, *cluster_size, *resolution_timeout)
                 });
+            // the pending watchers are notified of the first node joining
+            for tx in self
+                .pending_watchers
+                .remove(&cluster_id)
+                .unwrap_or_default()
+            {
+                state.update(Message::WatchCluster { tx });
+            }
             state.update(message);
-        } else if let Some(state) = self.0.get_mut(&cluster_id) {
+        } else if let Some(state) = self.clusters.get_mut(&cluster_id) {
             state.update(message);
+        } else if let Message::WatchCluster { tx } = message {
+            debug!(cluster_id, "Watching cluster before its creation");
+            self.pending_watchers
+                .entry(cluster_id)
+                .or_default()
+                .push(tx);
         } else {
             error!(
                 cluster_id,
@@ -39,6 +80,24 @@ impl ClusterMap {
             );
         }
     }
+
+    /// Evict the clusters that finished, aborted or went stale for longer
+    /// than the retention, and the pending watchers that stopped watching
+    fn sweep(&mut self, retention: &Retention, stats: &GcStats) {
+        let before = self.clusters.len();
+        self.clusters.retain(|cluster_id, state| {
+            let expired = state.expired(retention);
+            if expired {
+                debug!(cluster_id, "Evicting finished or stale cluster");
+            }
+            !expired
+        });
+        stats.record_clusters(self.clusters.len(), before - self.clusters.len());
+        self.pending_watchers.retain(|_, watchers| {
+            watchers.retain(|tx| !tx.is_closed());
+            !watchers.is_empty()
+        });
+    }
 }
 
 pub struct ClusterManagerTask(JoinHandle<()>);
@@ -51,10 +110,27 @@ impl ClusterManagerTask {
 }
 
 impl ClusterManager {
-    async fn event_loop(mut rx: mpsc::UnboundedReceiver<(String, Message)>) {
-        let mut clusters = ClusterMap(HashMap::new());
-        while let Some((cluster_id, msg)) = rx.recv().await {
-            clusters.forward(cluster_id, msg);
+    async fn event_loop(
+        mut rx: mpsc::UnboundedReceiver<(String, Message)>,
+        retention: Retention,
+        stats: Arc<GcStats>,
+    ) {
+        let mut clusters = ClusterMap {
+            clusters: HashMap::new(),
+            pending_watchers: HashMap::new(),
+        };
+        let mut sweeps = interval(retention.sweep_interval);
+        sweeps.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            // sweep once the queued messages are processed
+            tokio::select! {
+                biased;
+                msg = rx.recv() => match msg {
+                    Some((cluster_id, msg)) => clusters.forward(cluster_id, msg),
+                    None => break,
+                },
+                _ = sweeps.tick() => clusters.sweep(&retention, &stats),
+            }
         }
     }
 
@@ -62,15 +138,87 @@ impl ClusterManager {
         self.tx.send((cluster_id, message)).unwrap();
     }
 
-    pub async fn get_summary(&self, cluster_id: String) -> Summary {
+    /// Summary of the cluster, None if it is unknown or was evicted
+    pub async fn get_summary(&self, cluster_id: String) -> Option<Summary> {
         let (msg, rx) = Message::get_summary();
         self.send(cluster_id, msg);
-        rx.await.unwrap()
+        rx.await.ok()
+    }
+
+    /// Summaries of the clusters that were not evicted yet, sorted by id
+    pub async fn list_summaries(&self) -> Vec<(String, Summary)> {
+        let (msg, rx) = Message::list_summaries();
+        // the message is not forwarded to a specific cluster
+        self.send(String::new(), msg);
+        rx.await.unwrap_or_default()
+    }
+
+    /// Stream the membership changes of the cluster to the sender, starting
+    /// with its current members
+    pub fn watch_cluster(&self, cluster_id: String, tx: mpsc::UnboundedSender<MembershipEvent>) {
+        self.send(cluster_id, Message::WatchCluster { tx });
     }
 
-    pub fn new() -> (Self, ClusterManagerTask) {
+    /// Predicted next mappings of the node, empty if no prediction can be made
+    pub async fn get_predicted_mappings(
+        &self,
+        cluster_id: String,
+        virt_ip: String,
+    ) -> Vec<SocketAddr> {
+        let (msg, rx) = Message::get_predicted_mappings(virt_ip);
+        self.send(cluster_id, msg);
+        rx.await.unwrap_or_default()
+    }
+
+    /// Wait for the barrier of the cluster, None if the cluster is unknown or
+    /// was evicted
+    pub async fn wait_cluster(
+        &self,
+        cluster_id: String,
+        virt_ip: String,
+        stage: Option<String>,
+    ) -> Option<Result<(), BarrierError>> {
+        let (msg, rx) = Message::wait_cluster(virt_ip, stage);
+        self.send(cluster_id, msg);
+        rx.await.ok()
+    }
+
+    /// Status of the target node, None if the cluster is unknown or was
+    /// evicted
+    pub async fn get_target_status(
+        &self,
+        cluster_id: String,
+        virt_ip: String,
+    ) -> Option<TargetStatus> {
+        let (msg, rx) = Message::get_target_status(virt_ip);
+        self.send(cluster_id, msg);
+        rx.await.ok()
+    }
+
+    /// Wait until the target node cannot be resolved anymore, because it
+    /// left, the cluster is complete without it or was aborted. None if the
+    /// cluster is unknown or was evicted
+    pub async fn wait_target_lost(
+        &self,
+        cluster_id: String,
+        virt_ip: String,
+    ) -> Option<TargetStatus> {
+        let (msg, rx) = Message::wait_target_lost(virt_ip);
+        self.send(cluster_id, msg);
+        rx.await.ok()
+    }
+
+    /// Mark the cluster aborted, returns whether it was not aborted yet or
+    /// None if the cluster is unknown or was evicted
+    pub async fn abort_cluster(&self, cluster_id: String, cause: AbortCause) -> Option<bool> {
+        let (msg, rx) = Message::abort_cluster(cause);
+        self.send(cluster_id, msg);
+        rx.await.ok()
+    }
+
+    pub fn new(retention: Retention, stats: Arc<GcStats>) -> (Self, ClusterManagerTask) {
         let (tx, rx) = mpsc::unbounded_channel();
-        let task = ClusterManagerTask(tokio::spawn(Self::event_loop(rx)));
+        let task = ClusterManagerTask(tokio::spawn(Self::event_loop(rx, retention, stats)));
         (Self { tx }, task)
     }
 }
@@ -78,26 +226,48 @@ impl ClusterManager {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::NatType;
     use chrono::{TimeZone, Utc};
+    use std::time::Duration;
+    use tokio::sync::oneshot;
 
     #[tokio::test]
     async fn test_manager() {
         let instant_1 = Utc.with_ymd_and_hms(2023, 5, 17, 16, 15, 30).unwrap();
         let instant_2 = Utc.with_ymd_and_hms(2023, 5, 17, 16, 15, 31).unwrap();
-        let (manager, manager_task) = ClusterManager::new();
+        let stats = Arc::new(GcStats::default());
+        let retention = Retention {
+            clusters: Duration::ZERO,
+            stale_clusters: Duration::from_secs(60),
+            endpoints: Duration::ZERO,
+            sweep_interval: Duration::from_millis(10),
+        };
+        let (manager, manager_task) = ClusterManager::new(retention, Arc::clone(&stats));
         let cluster_id = "cluster_id_1";
 
         let msg = Message::BindNodeStart {
             cluster_size: 2,
             time: instant_1,
-            virt_ip: String::from("192.168.0.1"),
+            virt_ip: VirtualIpRequest::Static(String::from("192.168.0.1")),
+            nat_type: NatType::PortRestricted,
+            observed_mappings: vec![],
+            port_prediction_window: 0,
+            resolution_timeout: Duration::ZERO,
+            name: String::new(),
+            tx: oneshot::channel().0,
         };
         manager.send(cluster_id.to_owned(), msg);
 
         let msg = Message::BindNodeStart {
             cluster_size: 2,
             time: instant_1,
-            virt_ip: String::from("192.168.0.2"),
+            virt_ip: VirtualIpRequest::Static(String::from("192.168.0.2")),
+            nat_type: NatType::PortRestricted,
+            observed_mappings: vec![],
+            port_prediction_window: 0,
+            resolution_timeout: Duration::ZERO,
+            name: String::new(),
+            tx: oneshot::channel().0,
         };
         manager.send(cluster_id.to_owned(), msg);
 
@@ -113,9 +283,62 @@ mod tests {
         };
         manager.send(cluster_id.to_owned(), msg);
 
-        let Summary { node, interval } = manager.get_summary(cluster_id.to_owned()).await;
+        let Summary {
+            node,
+            interval,
+            nat,
+        } = manager.get_summary(cluster_id.to_owned()).await.unwrap();
         assert_eq!(&format!("{:?}", interval), "starts: 0ns, ends: 0ns");
         assert_eq!(&format!("{:?}", node), "2 expected, 2 started, 2 ended");
+        assert_eq!(nat.port_restricted, 2);
+
+        let listed = manager.list_summaries().await;
+        assert_eq!(listed.len(), 1);
+        let (listed_id, listed_summary) = listed.into_iter().next().unwrap();
+        let proto = listed_summary.into_proto(listed_id);
+        assert_eq!(proto.cluster_id, cluster_id);
+        let interval = proto.interval.unwrap();
+        assert_eq!(interval.first_node_start_us, instant_1.timestamp_micros());
+        assert_eq!(
+            interval.last_node_end_us,
+            Some(instant_2.timestamp_micros())
+        );
+        assert_eq!(proto.node.unwrap().finished_nodes, 2);
+
+        // the finished cluster is evicted by the next sweep
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(manager.get_summary(cluster_id.to_owned()).await.is_none());
+        assert!(manager.list_summaries().await.is_empty());
+        let counts = stats.counts();
+        assert_eq!((counts.live_clusters, counts.evicted_clusters), (0, 1));
+
+        drop(manager);
+        manager_task.wait().await;
+    }
+
+    #[tokio::test]
+    async fn test_watch_before_creation() {
+        let (manager, manager_task) =
+            ClusterManager::new(Retention::default(), Arc::new(GcStats::default()));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        manager.watch_cluster(String::from("cluster_id_1"), tx);
+        let msg = Message::BindNodeStart {
+            cluster_size: 1,
+            time: Utc::now(),
+            virt_ip: VirtualIpRequest::Static(String::from("192.168.0.1")),
+            nat_type: NatType::Unknown,
+            observed_mappings: vec![],
+            port_prediction_window: 0,
+            resolution_timeout: Duration::ZERO,
+            name: String::from("node-1"),
+            tx: oneshot::channel().0,
+        };
+        manager.send(String::from("cluster_id_1"), msg);
+
+        let event = rx.recv().await.unwrap();
+        assert_eq!(event.kind, MembershipKind::Joined);
+        assert_eq!(event.name, "node-1");
+        assert!(!event.snapshot);
 
         drop(manager);
         manager_task.wait().await;
@@ -1,19 +1,41 @@
 use chrono::{DateTime, Utc};
-use tokio::sync::oneshot;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
 
+use super::state::NodeLifecycle;
 use super::summary::*;
+use crate::NatType;
 
 #[derive(Debug)]
 pub enum Message {
     BindNodeStart {
         time: DateTime<Utc>,
         cluster_size: u32,
-        virt_ip: String,
+        virt_ip: VirtualIpRequest,
+        nat_type: NatType,
+        observed_mappings: Vec<SocketAddr>,
+        port_prediction_window: u32,
+        resolution_timeout: Duration,
+        name: String,
+        /// Answered with the virtual IP of the node
+        tx: oneshot::Sender<Result<String, NodeRejection>>,
     },
     BindNodeEnd {
         time: DateTime<Utc>,
         virt_ip: String,
     },
+    /// The node stopped sending heartbeats or its binding broke
+    BindNodeFailed {
+        time: DateTime<Utc>,
+        virt_ip: String,
+    },
+    /// Event reported by the node, None for heartbeats
+    NodeEvent {
+        time: DateTime<Utc>,
+        virt_ip: String,
+        lifecycle: Option<NodeLifecycle>,
+    },
     BindServerStart {
         virt_ip: String,
     },
@@ -32,6 +54,38 @@ pub enum Message {
     GetSummary {
         tx: oneshot::Sender<Summary>,
     },
+    /// Handled by the manager for all the clusters, sorted by id
+    ListSummaries {
+        tx: oneshot::Sender<Vec<(String, Summary)>>,
+    },
+    GetPredictedMappings {
+        virt_ip: String,
+        tx: oneshot::Sender<Vec<SocketAddr>>,
+    },
+    GetTargetStatus {
+        virt_ip: String,
+        tx: oneshot::Sender<TargetStatus>,
+    },
+    /// Answered once the target cannot be resolved anymore, i.e. with a
+    /// status other than `Expected`
+    WaitTargetLost {
+        virt_ip: String,
+        tx: oneshot::Sender<TargetStatus>,
+    },
+    WaitCluster {
+        virt_ip: String,
+        stage: Option<String>,
+        tx: oneshot::Sender<Result<(), BarrierError>>,
+    },
+    /// Handled by the manager until the cluster is created
+    WatchCluster {
+        tx: mpsc::UnboundedSender<MembershipEvent>,
+    },
+    /// Answers whether the cluster was not aborted yet
+    AbortCluster {
+        cause: AbortCause,
+        tx: oneshot::Sender<bool>,
+    },
 }
 
 impl Message {
@@ -43,4 +97,39 @@ impl Message {
         let (tx, rx) = oneshot::channel();
         (Message::GetSummary { tx }, rx)
     }
+
+    pub fn list_summaries() -> (Message, oneshot::Receiver<Vec<(String, Summary)>>) {
+        let (tx, rx) = oneshot::channel();
+        (Message::ListSummaries { tx }, rx)
+    }
+
+    pub fn get_predicted_mappings(
+        virt_ip: String,
+    ) -> (Message, oneshot::Receiver<Vec<SocketAddr>>) {
+        let (tx, rx) = oneshot::channel();
+        (Message::GetPredictedMappings { virt_ip, tx }, rx)
+    }
+
+    pub fn wait_cluster(
+        virt_ip: String,
+        stage: Option<String>,
+    ) -> (Message, oneshot::Receiver<Result<(), BarrierError>>) {
+        let (tx, rx) = oneshot::channel();
+        (Message::WaitCluster { virt_ip, stage, tx }, rx)
+    }
+
+    pub fn abort_cluster(cause: AbortCause) -> (Message, oneshot::Receiver<bool>) {
+        let (tx, rx) = oneshot::channel();
+        (Message::AbortCluster { cause, tx }, rx)
+    }
+
+    pub fn get_target_status(virt_ip: String) -> (Message, oneshot::Receiver<TargetStatus>) {
+        let (tx, rx) = oneshot::channel();
+        (Message::GetTargetStatus { virt_ip, tx }, rx)
+    }
+
+    pub fn wait_target_lost(virt_ip: String) -> (Message, oneshot::Receiver<TargetStatus>) {
+        let (tx, rx) = oneshot::channel();
+        (Message::WaitTargetLost { virt_ip, tx }, rx)
+    }
 }
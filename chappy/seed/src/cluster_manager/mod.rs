@@ -5,3 +5,5 @@ mod summary;
 
 pub use manager::{ClusterManager, ClusterManagerTask};
 pub use message::Message;
+pub use state::{NodeLifecycle, DEFAULT_RESOLUTION_TIMEOUT};
+pub use summary::{AbortCause, BarrierError, NodeRejection, TargetStatus, VirtualIpRequest};
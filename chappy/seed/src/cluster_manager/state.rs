@@ -1,14 +1,45 @@
 use super::message::*;
 use super::summary::*;
+use crate::gc::Retention;
+use crate::nat_probe::predict_mappings;
+use crate::NatType;
 use chrono::{DateTime, Utc};
-use std::collections::HashMap;
+use ipnet::Ipv4Net;
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
 use tracing::{debug_span, error, info, Span};
 
 type UtcTime = DateTime<Utc>;
 
+/// Resolution timeout of the clusters that do not specify one
+pub const DEFAULT_RESOLUTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Last lifecycle stage reported by the node
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeLifecycle {
+    Bound,
+    AppStarted,
+    AppExited(i32),
+    ShuttingDown,
+    /// Heartbeats stopped before the node ended its binding
+    Failed,
+}
+
 pub struct NodeState {
+    /// Name reported by the node, possibly empty
+    pub name: String,
     pub start_time: UtcTime,
     pub end_time: Option<UtcTime>,
+    pub lifecycle: NodeLifecycle,
+    /// Last message received from the node
+    pub last_seen: UtcTime,
+    pub nat_type: NatType,
+    /// Consecutive mappings reported by the node, oldest first
+    pub observed_mappings: Vec<SocketAddr>,
+    pub port_prediction_window: u32,
 }
 
 pub struct TracedNodeState {
@@ -18,57 +49,199 @@ pub struct TracedNodeState {
 
 pub struct ClusterState {
     pub expected_size: u32,
+    /// How long clients wait for targets to register
+    pub resolution_timeout: Duration,
     pub nodes: HashMap<String, TracedNodeState>,
     pub finished_nodes: u32,
+    /// Nodes that reached each readiness stage
+    pub stages: HashMap<String, HashSet<String>>,
+    waiters: Vec<BarrierWaiter>,
+    target_waiters: Vec<TargetWaiter>,
+    /// Set once, the nodes are expected to give up
+    pub aborted: Option<AbortCause>,
+    /// Streams of membership changes, closed once the cluster finished
+    watchers: Vec<mpsc::UnboundedSender<MembershipEvent>>,
+}
+
+/// Caller released once all the nodes are bound, or once they all reached the
+/// stage if one is provided
+struct BarrierWaiter {
+    stage: Option<String>,
+    tx: oneshot::Sender<Result<(), BarrierError>>,
+}
+
+/// Caller released once the target cannot be resolved anymore
+struct TargetWaiter {
+    virt_ip: String,
+    tx: oneshot::Sender<TargetStatus>,
 }
 
 pub struct TracedClusterState {
     state: ClusterState,
     span: Option<Span>,
+    /// When the last node of the cluster ended
+    finished_at: Option<Instant>,
+    /// When the cluster was aborted
+    aborted_at: Option<Instant>,
+    /// When the cluster last received a message
+    updated_at: Instant,
 }
 
 impl TracedClusterState {
-    pub fn new(cluster_id: &str, cluster_size: u32) -> Self {
+    pub fn new(cluster_id: &str, cluster_size: u32, resolution_timeout: Duration) -> Self {
+        let resolution_timeout = if resolution_timeout.is_zero() {
+            DEFAULT_RESOLUTION_TIMEOUT
+        } else {
+            resolution_timeout
+        };
         Self {
             state: ClusterState {
                 expected_size: cluster_size,
+                resolution_timeout,
                 nodes: HashMap::new(),
                 finished_nodes: 0,
+                stages: HashMap::new(),
+                waiters: Vec::new(),
+                target_waiters: Vec::new(),
+                aborted: None,
+                watchers: Vec::new(),
             },
             span: Some(debug_span!("cluster", cluster_id)),
+            finished_at: None,
+            aborted_at: None,
+            updated_at: Instant::now(),
         }
     }
 
     pub fn update(&mut self, message: Message) {
         let _cluster_enter = self.span.as_ref().map(|s| s.enter());
+        self.updated_at = Instant::now();
         match message {
             Message::BindNodeStart {
                 cluster_size,
                 virt_ip,
                 time,
+                nat_type,
+                observed_mappings,
+                port_prediction_window,
+                resolution_timeout: _,
+                name,
+                tx,
             } => {
-                if cluster_size != self.state.expected_size {
-                    error!("Declared cluster sizes diverge between member nodes");
-                }
-                self.state.add_traced_node(
-                    &virt_ip,
+                let res = self.state.add_traced_node(
+                    cluster_size,
+                    virt_ip,
                     NodeState {
+                        name: name.clone(),
                         start_time: time,
                         end_time: None,
+                        lifecycle: NodeLifecycle::Bound,
+                        last_seen: time,
+                        nat_type,
+                        observed_mappings,
+                        port_prediction_window,
                     },
-                )
+                );
+                match &res {
+                    Ok(virt_ip) => {
+                        self.state
+                            .notify(MembershipKind::Joined, virt_ip.clone(), name, time)
+                    }
+                    Err(rejection) => error!(%rejection, "node rejected"),
+                }
+                if let Err(res) = tx.send(res) {
+                    error!(
+                        "caller dropped before getting its binding result: {:?}",
+                        res
+                    )
+                }
             }
             Message::BindNodeEnd { virt_ip, time } => {
+                let mut name = String::new();
                 self.state.edit_node(&virt_ip, "BindNodeEnd", true, |n| {
                     n.end_time = Some(time);
+                    name = n.name.clone();
                 });
                 self.state.finished_nodes += 1;
+                self.state.notify(MembershipKind::Left, virt_ip, name, time);
             }
+            Message::BindNodeFailed { virt_ip, time } => {
+                let mut name = String::new();
+                self.state.edit_node(&virt_ip, "BindNodeFailed", true, |n| {
+                    error!(last_seen = %n.last_seen, "node failed");
+                    n.end_time = Some(time);
+                    n.lifecycle = NodeLifecycle::Failed;
+                    name = n.name.clone();
+                });
+                self.state.finished_nodes += 1;
+                self.state
+                    .notify(MembershipKind::Failed, virt_ip, name, time);
+            }
+            Message::NodeEvent {
+                virt_ip,
+                time,
+                lifecycle,
+            } => self.state.edit_node(&virt_ip, "NodeEvent", false, |n| {
+                n.last_seen = time;
+                if let Some(lifecycle) = lifecycle {
+                    info!(?lifecycle, "node event");
+                    n.lifecycle = lifecycle;
+                }
+            }),
             Message::GetSummary { tx } => {
                 if let Err(err) = tx.send(self.state.summary()) {
                     error!("caller dropped before getting its summary: {:?}", err)
                 }
             }
+            Message::GetPredictedMappings { virt_ip, tx } => {
+                let predicted = self
+                    .state
+                    .nodes
+                    .get(&virt_ip)
+                    .map(|n| {
+                        predict_mappings(&n.state.observed_mappings, n.state.port_prediction_window)
+                    })
+                    .unwrap_or_default();
+                if let Err(err) = tx.send(predicted) {
+                    error!("caller dropped before getting its prediction: {:?}", err)
+                }
+            }
+            Message::GetTargetStatus { virt_ip, tx } => {
+                if let Err(err) = tx.send(self.state.target_status(&virt_ip)) {
+                    error!("caller dropped before getting the target status: {:?}", err)
+                }
+            }
+            Message::WaitTargetLost { virt_ip, tx } => {
+                self.state.target_waiters.push(TargetWaiter { virt_ip, tx })
+            }
+            Message::WaitCluster { virt_ip, stage, tx } => {
+                if !self.state.nodes.contains_key(&virt_ip) {
+                    error!(virt_ip, "WaitCluster failed");
+                    tx.send(Err(BarrierError::UnknownNode)).ok();
+                } else {
+                    if let Some(stage) = &stage {
+                        self.state
+                            .stages
+                            .entry(stage.clone())
+                            .or_default()
+                            .insert(virt_ip);
+                    }
+                    self.state.waiters.push(BarrierWaiter { stage, tx });
+                }
+            }
+            Message::ListSummaries { .. } => {
+                error!("ListSummaries should be handled by the manager")
+            }
+            Message::WatchCluster { tx } => self.state.watch(tx),
+            Message::AbortCluster { cause, tx } => {
+                let first = self.state.aborted.is_none();
+                if first {
+                    error!(%cause, "cluster aborted");
+                    self.state.aborted = Some(cause);
+                    self.aborted_at = Some(Instant::now());
+                }
+                tx.send(first).ok();
+            }
             Message::BindClientStart {
                 src_virt_ip,
                 tgt_virt_ip,
@@ -99,27 +272,90 @@ impl TracedClusterState {
                     })
             }
         }
+        self.state.release_waiters();
         if self.finished() {
             // drop the cluster span to close it
             drop(_cluster_enter);
             self.span.take();
+            self.finished_at.get_or_insert_with(Instant::now);
+            // end the membership streams
+            self.state.watchers.clear();
         }
     }
 
     pub fn finished(&self) -> bool {
         self.state.finished()
     }
+
+    pub fn summary(&self) -> Summary {
+        self.state.summary()
+    }
+
+    /// Whether the cluster finished or aborted for longer than the
+    /// retention, or received no message for longer than the stale retention
+    pub fn expired(&self, retention: &Retention) -> bool {
+        let ended_at = match (self.finished_at, self.aborted_at) {
+            (Some(finished_at), Some(aborted_at)) => Some(finished_at.min(aborted_at)),
+            (ended_at, None) | (None, ended_at) => ended_at,
+        };
+        ended_at.is_some_and(|ended_at| ended_at.elapsed() >= retention.clusters)
+            || self.updated_at.elapsed() >= retention.stale_clusters
+    }
 }
 
 impl ClusterState {
-    pub fn add_traced_node(&mut self, virt_ip: &str, state: NodeState) {
-        self.nodes
-            .entry(virt_ip.to_owned())
-            .and_modify(|_| error!(virt_ip, "Ip already bound"))
-            .or_insert(TracedNodeState {
+    /// Add the node unless it is inconsistent with the cluster, returns its
+    /// virtual IP
+    pub fn add_traced_node(
+        &mut self,
+        cluster_size: u32,
+        virt_ip: VirtualIpRequest,
+        state: NodeState,
+    ) -> Result<String, NodeRejection> {
+        if cluster_size != self.expected_size {
+            return Err(NodeRejection::SizeMismatch {
+                expected: self.expected_size,
+                declared: cluster_size,
+            });
+        }
+        if let VirtualIpRequest::Static(virt_ip) = &virt_ip {
+            if self.nodes.contains_key(virt_ip) {
+                return Err(NodeRejection::IpAlreadyBound);
+            }
+        }
+        if self.nodes.len() as u32 >= self.expected_size {
+            return Err(NodeRejection::ClusterFull);
+        }
+        let virt_ip = match virt_ip {
+            VirtualIpRequest::Static(virt_ip) => virt_ip,
+            VirtualIpRequest::Allocate { subnet, hint } => self
+                .allocate(&subnet, hint)
+                .ok_or(NodeRejection::SubnetExhausted)?,
+        };
+        self.nodes.insert(
+            virt_ip.clone(),
+            TracedNodeState {
                 state,
                 span: Some(debug_span!("node", virt_ip)),
-            });
+            },
+        );
+        Ok(virt_ip)
+    }
+
+    /// The hint if it is a free host address of the subnet, otherwise the
+    /// lowest free one
+    ///
+    /// Addresses of nodes that left are not reallocated within the cluster.
+    fn allocate(&self, subnet: &Ipv4Net, hint: Option<Ipv4Addr>) -> Option<String> {
+        let is_free = |ip: &Ipv4Addr| !self.nodes.contains_key(&ip.to_string());
+        let is_host = |ip: &Ipv4Addr| {
+            subnet.contains(ip)
+                && (subnet.prefix_len() >= 31
+                    || (*ip != subnet.network() && *ip != subnet.broadcast()))
+        };
+        hint.filter(|ip| is_host(ip) && is_free(ip))
+            .or_else(|| subnet.hosts().find(is_free))
+            .map(|ip| ip.to_string())
     }
 
     pub fn edit_node<F>(&mut self, virt_ip: &str, err_msg: &str, close: bool, f: F)
@@ -143,6 +379,102 @@ impl ClusterState {
         self.finished_nodes == self.expected_size
     }
 
+    /// Whether the barrier is reached, None if it can still be
+    pub fn barrier_status(&self, stage: Option<&str>) -> Option<Result<(), BarrierError>> {
+        let Some(stage) = stage else {
+            if self.nodes.len() as u32 >= self.expected_size {
+                return Some(Ok(()));
+            }
+            return self.aborted.is_some().then_some(Err(BarrierError::Broken));
+        };
+        let reached = self.stages.get(stage);
+        let reached_count = reached.map_or(0, |nodes| nodes.len());
+        if reached_count as u32 >= self.expected_size {
+            return Some(Ok(()));
+        }
+        if self.aborted.is_some() {
+            return Some(Err(BarrierError::Broken));
+        }
+        let broken = self.nodes.iter().any(|(virt_ip, node)| {
+            node.state.end_time.is_some() && reached.is_none_or(|nodes| !nodes.contains(virt_ip))
+        });
+        broken.then_some(Err(BarrierError::Broken))
+    }
+
+    /// Answer the waiters whose barrier is settled or whose target is lost,
+    /// and forget the ones that stopped waiting
+    fn release_waiters(&mut self) {
+        for waiter in std::mem::take(&mut self.waiters) {
+            match self.barrier_status(waiter.stage.as_deref()) {
+                Some(res) => {
+                    // the caller might have given up waiting
+                    waiter.tx.send(res).ok();
+                }
+                None if waiter.tx.is_closed() => {}
+                None => self.waiters.push(waiter),
+            }
+        }
+        for waiter in std::mem::take(&mut self.target_waiters) {
+            match self.target_status(&waiter.virt_ip) {
+                TargetStatus::Expected(_) if waiter.tx.is_closed() => {}
+                TargetStatus::Expected(_) => self.target_waiters.push(waiter),
+                status => {
+                    waiter.tx.send(status).ok();
+                }
+            }
+        }
+    }
+
+    /// Send the current members to the watcher, then keep it to notify the
+    /// membership changes
+    fn watch(&mut self, tx: mpsc::UnboundedSender<MembershipEvent>) {
+        let mut members: Vec<_> = self
+            .nodes
+            .iter()
+            .filter(|(_, node)| node.state.end_time.is_none())
+            .map(|(virt_ip, node)| MembershipEvent {
+                kind: MembershipKind::Joined,
+                virt_ip: virt_ip.clone(),
+                name: node.state.name.clone(),
+                time: node.state.start_time,
+                snapshot: true,
+            })
+            .collect();
+        members.sort_by_key(|event| event.time);
+        for event in members {
+            if tx.send(event).is_err() {
+                return;
+            }
+        }
+        self.watchers.push(tx);
+    }
+
+    /// Send the membership change to the watchers, forgetting the ones that
+    /// stopped watching
+    fn notify(&mut self, kind: MembershipKind, virt_ip: String, name: String, time: UtcTime) {
+        let event = MembershipEvent {
+            kind,
+            virt_ip,
+            name,
+            time,
+            snapshot: false,
+        };
+        self.watchers
+            .retain(|watcher| watcher.send(event.clone()).is_ok());
+    }
+
+    pub fn target_status(&self, virt_ip: &str) -> TargetStatus {
+        if let Some(cause) = &self.aborted {
+            return TargetStatus::Aborted(cause.clone());
+        }
+        match self.nodes.get(virt_ip) {
+            Some(node) if node.state.end_time.is_some() => TargetStatus::Left,
+            Some(_) => TargetStatus::Expected(self.resolution_timeout),
+            None if self.nodes.len() as u32 >= self.expected_size => TargetStatus::Absent,
+            None => TargetStatus::Expected(self.resolution_timeout),
+        }
+    }
+
     fn comp<F, T>(op: F, a: Option<T>, b: Option<T>) -> Option<T>
     where
         F: FnOnce(Option<T>, Option<T>) -> Option<T>,
@@ -185,13 +517,32 @@ impl ClusterState {
             expected_size: self.expected_size,
             nodes: self.nodes.len() as u32,
             finished_nodes: self.finished_nodes,
+            failed_nodes: self
+                .nodes
+                .values()
+                .filter(|n| n.state.lifecycle == NodeLifecycle::Failed)
+                .count() as u32,
         }
     }
 
+    pub fn nat_summary(&self) -> NatSummary {
+        let mut summary = NatSummary::default();
+        for node in self.nodes.values() {
+            match node.state.nat_type {
+                NatType::Unknown => summary.unknown += 1,
+                NatType::FullCone => summary.full_cone += 1,
+                NatType::PortRestricted => summary.port_restricted += 1,
+                NatType::Symmetric => summary.symmetric += 1,
+            }
+        }
+        summary
+    }
+
     pub fn summary(&self) -> Summary {
         Summary {
             interval: self.interval_summary(),
             node: self.node_summary(),
+            nat: self.nat_summary(),
         }
     }
 }
@@ -199,23 +550,63 @@ impl ClusterState {
 #[cfg(test)]
 mod tests {
     use chrono::TimeZone;
+    use tokio::sync::{mpsc, oneshot};
 
     use super::*;
 
+    fn bind_request(
+        state: &mut TracedClusterState,
+        cluster_size: u32,
+        virt_ip: VirtualIpRequest,
+        name: String,
+    ) -> Result<String, NodeRejection> {
+        let (tx, mut rx) = oneshot::channel();
+        state.update(Message::BindNodeStart {
+            time: Utc::now(),
+            cluster_size,
+            virt_ip,
+            nat_type: NatType::Unknown,
+            observed_mappings: vec![],
+            port_prediction_window: 0,
+            resolution_timeout: Duration::ZERO,
+            name,
+            tx,
+        });
+        rx.try_recv().unwrap()
+    }
+
+    fn bind_node(
+        state: &mut TracedClusterState,
+        cluster_size: u32,
+        virt_ip: &str,
+    ) -> Result<(), NodeRejection> {
+        let name = format!("node {}", virt_ip);
+        let virt_ip = VirtualIpRequest::Static(virt_ip.to_owned());
+        bind_request(state, cluster_size, virt_ip, name).map(|_| ())
+    }
+
     #[tokio::test]
     async fn test_state_updates() {
         chappy_util::init_tracing("test_state_update");
-        let mut state = TracedClusterState::new("cluster_id", 2);
+        let mut state = TracedClusterState::new("cluster_id", 2, Duration::ZERO);
         let instant_1 = Utc.with_ymd_and_hms(2023, 5, 12, 10, 15, 30).unwrap();
         let instant_2 = Utc.with_ymd_and_hms(2023, 5, 12, 10, 15, 31).unwrap();
         let instant_3 = Utc.with_ymd_and_hms(2023, 5, 12, 10, 15, 32).unwrap();
         let instant_4 = Utc.with_ymd_and_hms(2023, 5, 12, 10, 15, 33).unwrap();
 
+        let (tx, mut rx) = oneshot::channel();
         state.update(Message::BindNodeStart {
             time: instant_1,
             cluster_size: 2,
-            virt_ip: String::from("192.68.0.1"),
+            virt_ip: VirtualIpRequest::Static(String::from("192.68.0.1")),
+            nat_type: NatType::FullCone,
+            observed_mappings: vec![],
+            port_prediction_window: 0,
+            resolution_timeout: Duration::ZERO,
+            name: String::new(),
+            tx,
         });
+        assert_eq!(rx.try_recv().unwrap(), Ok(String::from("192.68.0.1")));
 
         let node = state
             .state
@@ -233,11 +624,35 @@ mod tests {
             &format!("{:?}", state.state.node_summary()),
             "2 expected, 1 started, 0 ended"
         );
+        assert_eq!(
+            state.state.target_status("192.68.0.2"),
+            TargetStatus::Expected(DEFAULT_RESOLUTION_TIMEOUT)
+        );
+        assert_eq!(
+            bind_node(&mut state, 3, "192.68.0.2"),
+            Err(NodeRejection::SizeMismatch {
+                expected: 2,
+                declared: 3
+            })
+        );
+        assert_eq!(
+            bind_node(&mut state, 2, "192.68.0.1"),
+            Err(NodeRejection::IpAlreadyBound)
+        );
 
         state.update(Message::BindNodeStart {
             time: instant_2,
             cluster_size: 2,
-            virt_ip: String::from("192.68.0.2"),
+            virt_ip: VirtualIpRequest::Static(String::from("192.68.0.2")),
+            nat_type: NatType::Symmetric,
+            observed_mappings: vec![
+                "52.1.2.3:4000".parse().unwrap(),
+                "52.1.2.3:4001".parse().unwrap(),
+            ],
+            port_prediction_window: 2,
+            resolution_timeout: Duration::from_secs(1),
+            name: String::new(),
+            tx: oneshot::channel().0,
         });
 
         let node = state
@@ -256,6 +671,28 @@ mod tests {
             &format!("{:?}", state.state.node_summary()),
             "2 expected, 2 started, 0 ended"
         );
+        assert_eq!(
+            &format!("{:?}", state.state.nat_summary()),
+            "nat: 1 full-cone, 0 port-restricted, 1 symmetric, 0 unknown"
+        );
+        // the cluster is complete
+        assert_eq!(
+            state.state.target_status("192.68.0.3"),
+            TargetStatus::Absent
+        );
+        assert_eq!(
+            bind_node(&mut state, 2, "192.68.0.3"),
+            Err(NodeRejection::ClusterFull)
+        );
+        let (msg, mut rx) = Message::get_predicted_mappings(String::from("192.68.0.2"));
+        state.update(msg);
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            vec![
+                "52.1.2.3:4002".parse::<SocketAddr>().unwrap(),
+                "52.1.2.3:4003".parse().unwrap()
+            ]
+        );
 
         state.update(Message::BindNodeEnd {
             time: instant_3,
@@ -279,6 +716,9 @@ mod tests {
             &format!("{:?}", state.state.node_summary()),
             "2 expected, 2 started, 1 ended"
         );
+        let (msg, mut rx) = Message::get_target_status(String::from("192.68.0.2"));
+        state.update(msg);
+        assert_eq!(rx.try_recv().unwrap(), TargetStatus::Left);
 
         state.update(Message::BindNodeEnd {
             time: instant_4,
@@ -302,4 +742,236 @@ mod tests {
             "2 expected, 2 started, 2 ended"
         );
     }
+
+    #[tokio::test]
+    async fn test_barriers() {
+        let mut state = TracedClusterState::new("cluster_id", 2, Duration::ZERO);
+        let wait = |state: &mut TracedClusterState, virt_ip: &str, stage: Option<&str>| {
+            let (msg, rx) = Message::wait_cluster(virt_ip.to_owned(), stage.map(str::to_owned));
+            state.update(msg);
+            rx
+        };
+        bind_node(&mut state, 2, "192.68.0.1").unwrap();
+        let mut bound_rx = wait(&mut state, "192.68.0.1", None);
+        let mut loaded_rx_1 = wait(&mut state, "192.68.0.1", Some("loaded"));
+        assert!(bound_rx.try_recv().is_err());
+        assert!(loaded_rx_1.try_recv().is_err());
+        let mut unknown_rx = wait(&mut state, "192.68.0.9", None);
+        assert_eq!(unknown_rx.try_recv(), Ok(Err(BarrierError::UnknownNode)));
+
+        bind_node(&mut state, 2, "192.68.0.2").unwrap();
+        assert_eq!(bound_rx.try_recv(), Ok(Ok(())));
+        assert!(loaded_rx_1.try_recv().is_err());
+        let mut loaded_rx_2 = wait(&mut state, "192.68.0.2", Some("loaded"));
+        assert_eq!(loaded_rx_1.try_recv(), Ok(Ok(())));
+        assert_eq!(loaded_rx_2.try_recv(), Ok(Ok(())));
+
+        // the barrier cannot be reached once a node left without reaching it
+        let mut ready_rx = wait(&mut state, "192.68.0.1", Some("ready"));
+        state.update(Message::BindNodeEnd {
+            time: Utc::now(),
+            virt_ip: String::from("192.68.0.2"),
+        });
+        assert_eq!(ready_rx.try_recv(), Ok(Err(BarrierError::Broken)));
+        assert!(state.state.waiters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let mut state = TracedClusterState::new("cluster_id", 2, Duration::ZERO);
+        bind_node(&mut state, 2, "192.68.0.1").unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        state.update(Message::WatchCluster { tx });
+
+        // the snapshot lists the current members
+        let event = rx.try_recv().unwrap();
+        assert_eq!(
+            (event.kind, event.virt_ip.as_str(), event.name.as_str()),
+            (MembershipKind::Joined, "192.68.0.1", "node 192.68.0.1")
+        );
+        assert!(event.snapshot);
+        assert!(rx.try_recv().is_err());
+
+        bind_node(&mut state, 2, "192.68.0.2").unwrap();
+        let event = rx.try_recv().unwrap();
+        assert_eq!(
+            (event.kind, event.snapshot),
+            (MembershipKind::Joined, false)
+        );
+        state.update(Message::BindNodeFailed {
+            time: Utc::now(),
+            virt_ip: String::from("192.68.0.1"),
+        });
+        let event = rx.try_recv().unwrap();
+        assert_eq!(
+            (event.kind, event.name.as_str()),
+            (MembershipKind::Failed, "node 192.68.0.1")
+        );
+
+        // the stream ends with the cluster
+        state.update(Message::BindNodeEnd {
+            time: Utc::now(),
+            virt_ip: String::from("192.68.0.2"),
+        });
+        assert_eq!(rx.try_recv().unwrap().kind, MembershipKind::Left);
+        assert_eq!(rx.try_recv(), Err(mpsc::error::TryRecvError::Disconnected));
+    }
+
+    #[tokio::test]
+    async fn test_abort() {
+        let mut state = TracedClusterState::new("cluster_id", 3, Duration::ZERO);
+        bind_node(&mut state, 3, "192.68.0.1").unwrap();
+        bind_node(&mut state, 3, "192.68.0.2").unwrap();
+        let (msg, mut bound_rx) = Message::wait_cluster(String::from("192.68.0.1"), None);
+        state.update(msg);
+        assert!(bound_rx.try_recv().is_err());
+
+        let cause = AbortCause {
+            virt_ip: Some(String::from("192.68.0.2")),
+            reason: String::from("app exited with code 1"),
+        };
+        let (msg, mut first_rx) = Message::abort_cluster(cause.clone());
+        state.update(msg);
+        assert_eq!(first_rx.try_recv(), Ok(true));
+        assert_eq!(bound_rx.try_recv(), Ok(Err(BarrierError::Broken)));
+        assert_eq!(
+            state.state.target_status("192.68.0.3"),
+            TargetStatus::Aborted(cause.clone())
+        );
+
+        // the first cause is kept
+        let (msg, mut second_rx) = Message::abort_cluster(AbortCause {
+            virt_ip: None,
+            reason: String::from("requested"),
+        });
+        state.update(msg);
+        assert_eq!(second_rx.try_recv(), Ok(false));
+        assert_eq!(state.state.aborted, Some(cause));
+    }
+
+    #[tokio::test]
+    async fn test_target_lost() {
+        let mut state = TracedClusterState::new("cluster_id", 3, Duration::ZERO);
+        bind_node(&mut state, 3, "192.68.0.1").unwrap();
+        let (msg, mut joined_rx) = Message::wait_target_lost(String::from("192.68.0.1"));
+        state.update(msg);
+        let (msg, mut absent_rx) = Message::wait_target_lost(String::from("192.68.0.4"));
+        state.update(msg);
+        let (msg, mut aborted_rx) = Message::wait_target_lost(String::from("192.68.0.2"));
+        state.update(msg);
+        assert!(joined_rx.try_recv().is_err());
+        assert!(absent_rx.try_recv().is_err());
+
+        state.update(Message::BindNodeEnd {
+            time: Utc::now(),
+            virt_ip: String::from("192.68.0.1"),
+        });
+        assert_eq!(joined_rx.try_recv(), Ok(TargetStatus::Left));
+        bind_node(&mut state, 3, "192.68.0.2").unwrap();
+        bind_node(&mut state, 3, "192.68.0.3").unwrap();
+        assert_eq!(absent_rx.try_recv(), Ok(TargetStatus::Absent));
+        assert!(aborted_rx.try_recv().is_err());
+
+        let cause = AbortCause {
+            virt_ip: None,
+            reason: String::from("requested"),
+        };
+        let (msg, _) = Message::abort_cluster(cause.clone());
+        state.update(msg);
+        assert_eq!(aborted_rx.try_recv(), Ok(TargetStatus::Aborted(cause)));
+    }
+
+    #[tokio::test]
+    async fn test_node_events() {
+        let mut state = TracedClusterState::new("cluster_id", 2, Duration::ZERO);
+        bind_node(&mut state, 2, "192.68.0.1").unwrap();
+        bind_node(&mut state, 2, "192.68.0.2").unwrap();
+        let lifecycle = |state: &TracedClusterState, virt_ip: &str| {
+            state.state.nodes.get(virt_ip).unwrap().state.lifecycle
+        };
+        assert_eq!(lifecycle(&state, "192.68.0.1"), NodeLifecycle::Bound);
+
+        let seen = Utc::now() + chrono::Duration::seconds(1);
+        state.update(Message::NodeEvent {
+            time: seen,
+            virt_ip: String::from("192.68.0.1"),
+            lifecycle: Some(NodeLifecycle::AppExited(3)),
+        });
+        state.update(Message::NodeEvent {
+            time: seen,
+            virt_ip: String::from("192.68.0.2"),
+            lifecycle: None,
+        });
+        assert_eq!(lifecycle(&state, "192.68.0.1"), NodeLifecycle::AppExited(3));
+        assert_eq!(lifecycle(&state, "192.68.0.2"), NodeLifecycle::Bound);
+        assert_eq!(
+            state.state.nodes.get("192.68.0.2").unwrap().state.last_seen,
+            seen
+        );
+
+        state.update(Message::BindNodeFailed {
+            time: seen,
+            virt_ip: String::from("192.68.0.2"),
+        });
+        assert_eq!(lifecycle(&state, "192.68.0.2"), NodeLifecycle::Failed);
+        assert_eq!(state.state.target_status("192.68.0.2"), TargetStatus::Left);
+        assert_eq!(
+            &format!("{:?}", state.state.node_summary()),
+            "2 expected, 2 started, 1 ended (1 failed)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_allocation() {
+        let mut state = TracedClusterState::new("cluster_id", 4, Duration::ZERO);
+        let subnet: Ipv4Net = "172.20.0.0/30".parse().unwrap();
+        let allocate = |state: &mut TracedClusterState, hint: Option<&str>| {
+            let virt_ip = VirtualIpRequest::Allocate {
+                subnet,
+                hint: hint.map(|hint| hint.parse().unwrap()),
+            };
+            bind_request(state, 4, virt_ip, String::new())
+        };
+
+        // the hint is honored if it is a free host address of the subnet
+        assert_eq!(
+            allocate(&mut state, Some("172.20.0.2")),
+            Ok(String::from("172.20.0.2"))
+        );
+        // otherwise the lowest free address is assigned
+        assert_eq!(
+            allocate(&mut state, Some("172.20.0.2")),
+            Ok(String::from("172.20.0.1"))
+        );
+        bind_node(&mut state, 4, "10.0.0.1").unwrap();
+        assert_eq!(
+            allocate(&mut state, Some("172.20.0.3")),
+            Err(NodeRejection::SubnetExhausted)
+        );
+        assert_eq!(state.state.nodes.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_expiry() {
+        let retention = |clusters, stale_clusters| Retention {
+            clusters,
+            stale_clusters,
+            ..Default::default()
+        };
+        let hour = Duration::from_secs(3600);
+        let mut state = TracedClusterState::new("cluster_id", 2, Duration::ZERO);
+        bind_node(&mut state, 2, "192.68.0.1").unwrap();
+        assert!(!state.expired(&retention(Duration::ZERO, hour)));
+        // the second node never joins
+        assert!(state.expired(&retention(hour, Duration::ZERO)));
+
+        let (msg, _rx) = Message::abort_cluster(AbortCause {
+            virt_ip: None,
+            reason: String::from("requested"),
+        });
+        state.update(msg);
+        assert!(!state.finished());
+        assert!(state.expired(&retention(Duration::ZERO, hour)));
+        assert!(!state.expired(&retention(hour, hour)));
+    }
 }
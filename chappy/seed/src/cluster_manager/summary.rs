@@ -1,5 +1,137 @@
 use chrono::{DateTime, Utc};
+use ipnet::Ipv4Net;
 use std::fmt;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+/// Whether a client can expect the target node to register
+#[derive(Debug, PartialEq, Eq)]
+pub enum TargetStatus {
+    /// The target node ended its binding
+    Left,
+    /// All the expected nodes joined without the target
+    Absent,
+    /// The target joined or may still join, its endpoint is awaited for the
+    /// resolution timeout of the cluster
+    Expected(Duration),
+    /// The cluster was aborted
+    Aborted(AbortCause),
+}
+
+/// Why the cluster was aborted
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AbortCause {
+    /// Node that failed, None if the abort was requested
+    pub virt_ip: Option<String>,
+    pub reason: String,
+}
+
+/// Change in the membership of a cluster
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MembershipKind {
+    Joined,
+    /// The node ended its binding
+    Left,
+    /// The node stopped sending heartbeats or its binding broke
+    Failed,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MembershipEvent {
+    pub kind: MembershipKind,
+    pub virt_ip: String,
+    /// Name reported by the node, possibly empty
+    pub name: String,
+    pub time: DateTime<Utc>,
+    /// Whether the event describes a member present when the watch started
+    pub snapshot: bool,
+}
+
+impl From<MembershipEvent> for crate::MembershipEvent {
+    fn from(event: MembershipEvent) -> Self {
+        let kind = match event.kind {
+            MembershipKind::Joined => crate::MembershipEventKind::Joined,
+            MembershipKind::Left => crate::MembershipEventKind::Left,
+            MembershipKind::Failed => crate::MembershipEventKind::Failed,
+        };
+        Self {
+            kind: kind.into(),
+            virtual_ip: event.virt_ip,
+            node_name: event.name,
+            timestamp_us: event.time.timestamp_micros(),
+            snapshot: event.snapshot,
+        }
+    }
+}
+
+/// Virtual IP requested by a node when binding
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VirtualIpRequest {
+    /// Configured on the node
+    Static(String),
+    /// Allocated by the seed from the subnet, preferring the hint if it is
+    /// available, e.g. the address assigned in a previous binding
+    Allocate {
+        subnet: Ipv4Net,
+        hint: Option<Ipv4Addr>,
+    },
+}
+
+/// Why a node could not join its cluster
+#[derive(Debug, PartialEq, Eq)]
+pub enum NodeRejection {
+    /// Another node of the cluster is bound to the virtual IP
+    IpAlreadyBound,
+    /// The node declared another cluster size than the first node
+    SizeMismatch { expected: u32, declared: u32 },
+    /// All the expected nodes already joined
+    ClusterFull,
+    /// No virtual IP of the subnet is left to allocate
+    SubnetExhausted,
+}
+
+/// Why a barrier cannot be reached
+#[derive(Debug, PartialEq, Eq)]
+pub enum BarrierError {
+    /// The waiting node is not part of the cluster
+    UnknownNode,
+    /// A node left the cluster before reaching the stage, or the cluster was
+    /// aborted
+    Broken,
+}
+
+impl fmt::Display for BarrierError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownNode => f.write_str("node not bound in the cluster"),
+            Self::Broken => f.write_str("the cluster broke before reaching the stage"),
+        }
+    }
+}
+
+impl fmt::Display for AbortCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.virt_ip {
+            Some(virt_ip) => write!(f, "node {} failed: {}", virt_ip, self.reason),
+            None => write!(f, "aborted: {}", self.reason),
+        }
+    }
+}
+
+impl fmt::Display for NodeRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IpAlreadyBound => f.write_str("virtual IP already bound in the cluster"),
+            Self::SizeMismatch { expected, declared } => write!(
+                f,
+                "declared cluster size {} differs from the expected {}",
+                declared, expected
+            ),
+            Self::ClusterFull => f.write_str("all the expected nodes already joined"),
+            Self::SubnetExhausted => f.write_str("no virtual IP left in the subnet"),
+        }
+    }
+}
 
 pub enum IntervalSummary {
     Some {
@@ -15,11 +147,76 @@ pub struct NodeSummary {
     pub expected_size: u32,
     pub nodes: u32,
     pub finished_nodes: u32,
+    /// Nodes that ended because their heartbeats stopped
+    pub failed_nodes: u32,
+}
+
+/// Number of nodes per NAT type reported at bind time
+#[derive(Default)]
+pub struct NatSummary {
+    pub full_cone: u32,
+    pub port_restricted: u32,
+    pub symmetric: u32,
+    pub unknown: u32,
 }
 
 pub struct Summary {
     pub interval: IntervalSummary,
     pub node: NodeSummary,
+    pub nat: NatSummary,
+}
+
+impl Summary {
+    /// Protobuf version of the summary of the cluster
+    pub fn into_proto(self, cluster_id: String) -> crate::Summary {
+        crate::Summary {
+            cluster_id,
+            interval: self.interval.into(),
+            node: Some(self.node.into()),
+            nat: Some(self.nat.into()),
+        }
+    }
+}
+
+impl From<IntervalSummary> for Option<crate::IntervalSummary> {
+    fn from(interval: IntervalSummary) -> Self {
+        match interval {
+            IntervalSummary::Some {
+                first_node_start,
+                last_node_start,
+                first_node_end,
+                last_node_end,
+            } => Some(crate::IntervalSummary {
+                first_node_start_us: first_node_start.timestamp_micros(),
+                last_node_start_us: last_node_start.timestamp_micros(),
+                first_node_end_us: first_node_end.map(|t| t.timestamp_micros()),
+                last_node_end_us: last_node_end.map(|t| t.timestamp_micros()),
+            }),
+            IntervalSummary::Empty => None,
+        }
+    }
+}
+
+impl From<NodeSummary> for crate::NodeSummary {
+    fn from(node: NodeSummary) -> Self {
+        Self {
+            expected_size: node.expected_size,
+            nodes: node.nodes,
+            finished_nodes: node.finished_nodes,
+            failed_nodes: node.failed_nodes,
+        }
+    }
+}
+
+impl From<NatSummary> for crate::NatSummary {
+    fn from(nat: NatSummary) -> Self {
+        Self {
+            full_cone: nat.full_cone,
+            port_restricted: nat.port_restricted,
+            symmetric: nat.symmetric,
+            unknown: nat.unknown,
+        }
+    }
 }
 
 impl fmt::Debug for IntervalSummary {
@@ -55,12 +252,26 @@ impl fmt::Debug for NodeSummary {
             f,
             "{} expected, {} started, {} ended",
             self.expected_size, self.nodes, self.finished_nodes
+        )?;
+        if self.failed_nodes > 0 {
+            write!(f, " ({} failed)", self.failed_nodes)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for NatSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "nat: {} full-cone, {} port-restricted, {} symmetric, {} unknown",
+            self.full_cone, self.port_restricted, self.symmetric, self.unknown
         )
     }
 }
 
 impl fmt::Debug for Summary {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        write!(f, "{:?} ({:?})", self.interval, self.node)
+        write!(f, "{:?} ({:?}, {:?})", self.interval, self.node, self.nat)
     }
 }
@@ -0,0 +1,93 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::info;
+
+/// How long the seed keeps state that is not used anymore
+#[derive(Clone, Copy, Debug)]
+pub struct Retention {
+    /// Finished and aborted clusters are kept so that their summary can
+    /// still be queried
+    pub clusters: Duration,
+    /// Clusters that received no message for this long are evicted even if
+    /// they did not finish, e.g. because some nodes never joined
+    pub stale_clusters: Duration,
+    /// Endpoints are kept after their punch request stream closed so that
+    /// clients racing with a restarting server get a clear failure
+    pub endpoints: Duration,
+    /// Period of the eviction sweeps
+    pub sweep_interval: Duration,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            clusters: Duration::from_secs(600),
+            stale_clusters: Duration::from_secs(3600),
+            endpoints: Duration::from_secs(60),
+            sweep_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Counts of live and evicted entries
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GcCounts {
+    pub live_clusters: u64,
+    pub evicted_clusters: u64,
+    pub live_endpoints: u64,
+    pub evicted_endpoints: u64,
+}
+
+/// Counts updated by the eviction sweeps, live counts are as of the last
+/// sweep and evicted counts are cumulative
+#[derive(Debug, Default)]
+pub struct GcStats {
+    live_clusters: AtomicU64,
+    evicted_clusters: AtomicU64,
+    live_endpoints: AtomicU64,
+    evicted_endpoints: AtomicU64,
+}
+
+impl GcStats {
+    pub(crate) fn record_clusters(&self, live: usize, evicted: usize) {
+        self.live_clusters.store(live as u64, Ordering::Relaxed);
+        let total = self
+            .evicted_clusters
+            .fetch_add(evicted as u64, Ordering::Relaxed)
+            + evicted as u64;
+        if evicted > 0 {
+            info!(live, evicted, total, "evicted finished or stale clusters");
+        }
+    }
+
+    pub(crate) fn record_endpoints(&self, live: usize, evicted: usize) {
+        self.live_endpoints.store(live as u64, Ordering::Relaxed);
+        let total = self
+            .evicted_endpoints
+            .fetch_add(evicted as u64, Ordering::Relaxed)
+            + evicted as u64;
+        if evicted > 0 {
+            info!(live, evicted, total, "evicted closed endpoints");
+        }
+    }
+
+    pub fn counts(&self) -> GcCounts {
+        GcCounts {
+            live_clusters: self.live_clusters.load(Ordering::Relaxed),
+            evicted_clusters: self.evicted_clusters.load(Ordering::Relaxed),
+            live_endpoints: self.live_endpoints.load(Ordering::Relaxed),
+            evicted_endpoints: self.evicted_endpoints.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl From<GcCounts> for crate::GcCounts {
+    fn from(counts: GcCounts) -> Self {
+        Self {
+            live_clusters: counts.live_clusters,
+            evicted_clusters: counts.evicted_clusters,
+            live_endpoints: counts.live_endpoints,
+            evicted_endpoints: counts.evicted_endpoints,
+        }
+    }
+}
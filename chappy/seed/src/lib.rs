@@ -5,17 +5,30 @@ mod seed {
 pub use seed::*;
 mod address_stream;
 mod cluster_manager;
+pub mod gc;
+pub mod nat_probe;
+mod punch_acks;
 mod registered_endpoints;
 pub mod seed_service;
 
-use std::{net::SocketAddr, str::FromStr};
+use std::{
+    net::{AddrParseError, SocketAddr},
+    str::FromStr,
+};
 
 /// Address conversion newtype
 pub struct AddressConv(pub Address);
 
+impl AddressConv {
+    /// Fallible conversion for addresses provided by untrusted peers
+    pub fn parse(&self) -> Result<SocketAddr, AddrParseError> {
+        SocketAddr::from_str(&format!("{}", self))
+    }
+}
+
 impl From<AddressConv> for SocketAddr {
     fn from(addr: AddressConv) -> Self {
-        SocketAddr::from_str(&format!("{}", addr)).unwrap()
+        addr.parse().unwrap()
     }
 }
 
@@ -24,3 +37,24 @@ impl std::fmt::Display for AddressConv {
         write!(f, "{}:{}", self.0.ip, self.0.port)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address_parse() {
+        let addr = |ip: &str, port| {
+            AddressConv(Address {
+                ip: ip.to_owned(),
+                port,
+            })
+        };
+        assert_eq!(
+            addr("52.1.2.3", 4000).parse(),
+            Ok("52.1.2.3:4000".parse().unwrap())
+        );
+        assert!(addr("52.1.2.3", 70000).parse().is_err());
+        assert!(addr("not an ip", 4000).parse().is_err());
+    }
+}
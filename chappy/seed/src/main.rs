@@ -1,18 +1,45 @@
-use chappy_seed::{seed_server::SeedServer, seed_service::SeedService};
+use chappy_seed::{
+    gc::Retention, nat_probe::run_probe_server, seed_server::SeedServer, seed_service::SeedService,
+};
 use chappy_util::init_tracing;
 use std::env;
 use std::time::Duration;
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::time::timeout;
 use tonic::{transport::Server, Result};
-use tracing::{debug, info, warn};
+use tracing::{debug, error, info, warn};
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     init_tracing("seed");
     let port = env::var("PORT").unwrap();
     debug!("Starting seed on port {}...", port);
-    let (service, task) = SeedService::new();
+    if let Ok(probe_port) = env::var("NAT_PROBE_PORT") {
+        let primary_port = port.parse()?;
+        let secondary_port = probe_port.parse()?;
+        tokio::spawn(async move {
+            if let Err(err) = run_probe_server(primary_port, secondary_port).await {
+                error!(%err, "NAT probe server failed");
+            }
+        });
+    }
+    let default_retention = Retention::default();
+    let retention_var = |key: &str, default: Duration| {
+        env::var(key)
+            .map(|secs| Duration::from_secs(secs.parse().unwrap()))
+            .unwrap_or(default)
+    };
+    let retention = Retention {
+        clusters: retention_var("CLUSTER_RETENTION_SECS", default_retention.clusters),
+        stale_clusters: retention_var(
+            "STALE_CLUSTER_RETENTION_SECS",
+            default_retention.stale_clusters,
+        ),
+        endpoints: retention_var("ENDPOINT_RETENTION_SECS", default_retention.endpoints),
+        sweep_interval: retention_var("GC_INTERVAL_SECS", default_retention.sweep_interval),
+    };
+    debug!(?retention);
+    let (service, task) = SeedService::with_retention(retention);
     Server::builder()
         .add_service(SeedServer::new(service))
         .serve_with_shutdown(format!("0.0.0.0:{}", port).parse()?, async {
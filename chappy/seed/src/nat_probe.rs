@@ -0,0 +1,211 @@
+//! UDP probes used by the nodes to classify their NAT
+//!
+//! The seed listens on two UDP ports (the primary one has the same number as
+//! the gRPC port). Each probe is answered with the source address observed by
+//! the seed, either from the port that received it or from the other one.
+use std::io::Result as IoResult;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::net::UdpSocket;
+use tracing::{debug, instrument, warn};
+
+const PROBE_HEADER_LENGTH: usize = 12;
+const PROBE_HEADER_BYTES: [u8; PROBE_HEADER_LENGTH] = *b"chappy_probe";
+const PROBE_REQUEST_LENGTH: usize = PROBE_HEADER_LENGTH + 5;
+const PROBE_RESPONSE_LENGTH: usize = PROBE_HEADER_LENGTH + 10;
+
+/// A probe sent by a node to one of the seed probe ports
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProbeRequest {
+    /// Echoed in the response to match it with its request
+    pub transaction_id: u32,
+    /// Ask the seed to answer from the port that did not receive the probe
+    pub change_port: bool,
+}
+
+impl ProbeRequest {
+    pub fn encode(&self) -> [u8; PROBE_REQUEST_LENGTH] {
+        let mut buf = [0; PROBE_REQUEST_LENGTH];
+        buf[..PROBE_HEADER_LENGTH].copy_from_slice(&PROBE_HEADER_BYTES);
+        buf[PROBE_HEADER_LENGTH..PROBE_HEADER_LENGTH + 4]
+            .copy_from_slice(&self.transaction_id.to_be_bytes());
+        buf[PROBE_HEADER_LENGTH + 4] = u8::from(self.change_port);
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() != PROBE_REQUEST_LENGTH || buf[..PROBE_HEADER_LENGTH] != PROBE_HEADER_BYTES {
+            return None;
+        }
+        let id_bytes: [u8; 4] = buf[PROBE_HEADER_LENGTH..PROBE_HEADER_LENGTH + 4]
+            .try_into()
+            .unwrap();
+        let change_port = match buf[PROBE_HEADER_LENGTH + 4] {
+            0 => false,
+            1 => true,
+            _ => return None,
+        };
+        Some(Self {
+            transaction_id: u32::from_be_bytes(id_bytes),
+            change_port,
+        })
+    }
+}
+
+/// The answer of the seed, containing the mapping it observed
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProbeResponse {
+    pub transaction_id: u32,
+    pub observed_addr: SocketAddrV4,
+}
+
+impl ProbeResponse {
+    pub fn encode(&self) -> [u8; PROBE_RESPONSE_LENGTH] {
+        let mut buf = [0; PROBE_RESPONSE_LENGTH];
+        buf[..PROBE_HEADER_LENGTH].copy_from_slice(&PROBE_HEADER_BYTES);
+        buf[PROBE_HEADER_LENGTH..PROBE_HEADER_LENGTH + 4]
+            .copy_from_slice(&self.transaction_id.to_be_bytes());
+        buf[PROBE_HEADER_LENGTH + 4..PROBE_HEADER_LENGTH + 8]
+            .copy_from_slice(&self.observed_addr.ip().octets());
+        buf[PROBE_HEADER_LENGTH + 8..].copy_from_slice(&self.observed_addr.port().to_be_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() != PROBE_RESPONSE_LENGTH || buf[..PROBE_HEADER_LENGTH] != PROBE_HEADER_BYTES {
+            return None;
+        }
+        let id_bytes: [u8; 4] = buf[PROBE_HEADER_LENGTH..PROBE_HEADER_LENGTH + 4]
+            .try_into()
+            .unwrap();
+        let ip_bytes: [u8; 4] = buf[PROBE_HEADER_LENGTH + 4..PROBE_HEADER_LENGTH + 8]
+            .try_into()
+            .unwrap();
+        let port_bytes: [u8; 2] = buf[PROBE_HEADER_LENGTH + 8..].try_into().unwrap();
+        Some(Self {
+            transaction_id: u32::from_be_bytes(id_bytes),
+            observed_addr: SocketAddrV4::new(
+                Ipv4Addr::from(ip_bytes),
+                u16::from_be_bytes(port_bytes),
+            ),
+        })
+    }
+}
+
+/// Predict the next mappings of a NAT that allocates ports sequentially
+///
+/// The allocation step is inferred from the last two observed mappings.
+/// Returns no prediction if the mappings are stable (cone NAT) or if they don't
+/// share the same public IP.
+pub fn predict_mappings(observed: &[SocketAddr], window: u32) -> Vec<SocketAddr> {
+    let (prev, last) = match observed {
+        [.., prev, last] if prev.ip() == last.ip() => (prev, last),
+        _ => return vec![],
+    };
+    let delta = i32::from(last.port()) - i32::from(prev.port());
+    if delta == 0 {
+        return vec![];
+    }
+    (1..=window as i32)
+        .map_while(|k| u16::try_from(i32::from(last.port()) + delta * k).ok())
+        .filter(|port| *port != 0)
+        .map(|port| SocketAddr::new(last.ip(), port))
+        .collect()
+}
+
+async fn respond(
+    recv_sock: &UdpSocket,
+    other_sock: &UdpSocket,
+    buf: &[u8],
+    src: SocketAddr,
+) -> IoResult<()> {
+    let (req, src_v4) = match (ProbeRequest::decode(buf), src) {
+        (Some(req), SocketAddr::V4(src_v4)) => (req, src_v4),
+        _ => {
+            warn!(%src, "invalid probe dropped");
+            return Ok(());
+        }
+    };
+    debug!(%src, change_port = req.change_port, "probe received");
+    let resp = ProbeResponse {
+        transaction_id: req.transaction_id,
+        observed_addr: src_v4,
+    }
+    .encode();
+    if req.change_port {
+        other_sock.send_to(&resp, src).await?;
+    } else {
+        recv_sock.send_to(&resp, src).await?;
+    }
+    Ok(())
+}
+
+/// Answer the probes received on the two provided UDP ports
+///
+/// The returned future never completes unless one of the sockets fails.
+#[instrument(name = "nat_probe_srv")]
+pub async fn run_probe_server(primary_port: u16, secondary_port: u16) -> IoResult<()> {
+    let primary = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, primary_port)).await?;
+    let secondary = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, secondary_port)).await?;
+    debug!("listening for NAT probes");
+    let mut primary_buf = [0; PROBE_REQUEST_LENGTH + 1];
+    let mut secondary_buf = [0; PROBE_REQUEST_LENGTH + 1];
+    loop {
+        tokio::select! {
+            res = primary.recv_from(&mut primary_buf) => {
+                let (len, src) = res?;
+                if let Err(err) = respond(&primary, &secondary, &primary_buf[..len], src).await {
+                    warn!(%err, %src, "failed to answer probe");
+                }
+            }
+            res = secondary.recv_from(&mut secondary_buf) => {
+                let (len, src) = res?;
+                if let Err(err) = respond(&secondary, &primary, &secondary_buf[..len], src).await {
+                    warn!(%err, %src, "failed to answer probe");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_roundtrip() {
+        let original = ProbeRequest {
+            transaction_id: 42,
+            change_port: true,
+        };
+        let result = ProbeRequest::decode(&original.encode()).unwrap();
+        assert_eq!(original, result);
+        assert_eq!(ProbeRequest::decode(b"chappy_client"), None);
+    }
+
+    #[test]
+    fn test_predict_mappings() {
+        let addr = |port| SocketAddr::new(Ipv4Addr::new(52, 1, 2, 3).into(), port);
+        assert_eq!(
+            predict_mappings(&[addr(4000), addr(4002)], 3),
+            vec![addr(4004), addr(4006), addr(4008)]
+        );
+        assert_eq!(predict_mappings(&[addr(4000), addr(4000)], 3), vec![]);
+        assert_eq!(predict_mappings(&[addr(4000)], 3), vec![]);
+        assert_eq!(
+            predict_mappings(&[addr(65533), addr(65534)], 3),
+            vec![addr(65535)]
+        );
+        let other_ip = SocketAddr::new(Ipv4Addr::new(52, 1, 2, 4).into(), 4001);
+        assert_eq!(predict_mappings(&[addr(4000), other_ip], 3), vec![]);
+    }
+
+    #[test]
+    fn response_roundtrip() {
+        let original = ProbeResponse {
+            transaction_id: 42,
+            observed_addr: SocketAddrV4::new(Ipv4Addr::new(52, 1, 2, 3), 40123),
+        };
+        let result = ProbeResponse::decode(&original.encode()).unwrap();
+        assert_eq!(original, result);
+    }
+}
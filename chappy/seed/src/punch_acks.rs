@@ -0,0 +1,93 @@
+use crate::PunchStatus;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+/// Punch requests waiting for the server to report their result
+///
+/// The ids are random, so that a node cannot acknowledge the punches that
+/// were requested from other nodes, e.g. in other clusters.
+pub struct PunchAcks {
+    pending: Mutex<HashMap<u64, oneshot::Sender<PunchStatus>>>,
+}
+
+/// A registered punch, dropping it discards the pending entry
+pub struct PendingPunch<'a> {
+    pub id: u64,
+    acks: &'a PunchAcks,
+    rx: oneshot::Receiver<PunchStatus>,
+}
+
+impl PendingPunch<'_> {
+    /// Wait for the punch result, reported as timed out past the deadline
+    pub async fn wait(mut self, deadline: Duration) -> PunchStatus {
+        match timeout(deadline, &mut self.rx).await {
+            Ok(Ok(status)) => status,
+            Ok(Err(_)) => PunchStatus::Failed,
+            Err(_) => PunchStatus::TimedOut,
+        }
+    }
+}
+
+impl Drop for PendingPunch<'_> {
+    fn drop(&mut self) {
+        self.acks.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl PunchAcks {
+    pub fn new() -> Self {
+        Self {
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn register(&self) -> PendingPunch<'_> {
+        let (tx, rx) = oneshot::channel();
+        let mut pending = self.pending.lock().unwrap();
+        let id = loop {
+            let id = rand::random();
+            if !pending.contains_key(&id) {
+                break id;
+            }
+        };
+        pending.insert(id, tx);
+        drop(pending);
+        PendingPunch { id, acks: self, rx }
+    }
+
+    /// Forward the reported status to the waiting request, returns false if
+    /// it already returned
+    pub fn report(&self, id: u64, status: PunchStatus) -> bool {
+        match self.pending.lock().unwrap().remove(&id) {
+            Some(tx) => tx.send(status).is_ok(),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reported() {
+        let acks = PunchAcks::new();
+        let pending = acks.register();
+        assert!(acks.report(pending.id, PunchStatus::Punched));
+        let status = pending.wait(Duration::from_millis(50)).await;
+        assert_eq!(status, PunchStatus::Punched);
+    }
+
+    #[tokio::test]
+    async fn test_timed_out() {
+        let acks = PunchAcks::new();
+        let pending = acks.register();
+        let id = pending.id;
+        let status = pending.wait(Duration::from_millis(10)).await;
+        assert_eq!(status, PunchStatus::TimedOut);
+        assert!(!acks.report(id, PunchStatus::Punched));
+    }
+}
@@ -1,7 +1,11 @@
+use crate::gc::GcStats;
 use crate::ServerPunchRequest;
 use chappy_util::awaitable_map::AwaitableMap;
+use std::collections::HashMap;
+use std::sync::Mutex;
 use std::{net::SocketAddr, time::Duration};
 use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::Instant;
 use tokio::{sync::mpsc, time::timeout};
 use tonic::{Result, Status};
 use tracing::{error, info};
@@ -17,33 +21,44 @@ pub struct ResolvedTarget {
     pub natted_address: SocketAddr,
     pub punch_req_stream: mpsc::UnboundedSender<ServerPunchRequest>,
     pub server_certificate: Vec<u8>,
+    pub protocol_version: u32,
 }
 
 /// Map virtual addresses to the NATed endpoint and punch request stream
-/// TODO: a cleanup mechanism of old endpoints
-pub struct RegisteredEndpoints(AwaitableMap<VirtualTarget, ResolvedTarget>);
+///
+/// Endpoints are evicted by `sweep` once their punch request stream has been
+/// closed for longer than the retention.
+pub struct RegisteredEndpoints {
+    endpoints: AwaitableMap<VirtualTarget, ResolvedTarget>,
+    /// When the sweeps first found the punch request streams closed
+    closed_since: Mutex<HashMap<VirtualTarget, Instant>>,
+}
 
 impl RegisteredEndpoints {
     pub fn new() -> Self {
-        Self(AwaitableMap::new())
+        Self {
+            endpoints: AwaitableMap::new(),
+            closed_since: Mutex::new(HashMap::new()),
+        }
     }
 
+    /// Wait for the target to be registered for at most `wait`
     pub async fn get(
         &self,
         tgt_ip: &str,
         cluster_id: &str,
+        wait: Duration,
     ) -> Result<ResolvedTarget, tonic::Status> {
         let virtual_target_key = VirtualTarget {
             ip: tgt_ip.to_owned(),
             cluster_id: cluster_id.to_owned(),
         };
 
-        // TODO adjust timeout duration
         let resolved_target_timeout = timeout(
-            Duration::from_secs(10),
+            wait,
             // Assume the value does not need to be reset because we use a
             // different cluster each time
-            self.0.get(virtual_target_key, |_| false),
+            self.endpoints.get(virtual_target_key, |_| false),
         )
         .await;
 
@@ -52,45 +67,207 @@ impl RegisteredEndpoints {
         } else {
             let msg = "Target ip could not be resolved";
             error!(msg);
-            return Err(Status::not_found(msg));
+            return Err(Status::deadline_exceeded(msg));
         };
 
         Ok(resolved_target)
     }
 
+    /// Withdraw the target if it is still registered from the provided
+    /// address, returns whether it was
+    ///
+    /// Clients resolving the target from then on wait for it to be registered
+    /// again.
+    pub fn remove(&self, tgt_ip: &str, cluster_id: &str, server_nated_addr: SocketAddr) -> bool {
+        let virtual_target_key = VirtualTarget {
+            ip: tgt_ip.to_owned(),
+            cluster_id: cluster_id.to_owned(),
+        };
+        self.endpoints
+            .remove_if(&virtual_target_key, |tgt| {
+                tgt.natted_address == server_nated_addr
+            })
+            .is_some()
+    }
+
+    /// Register the target, fails if another endpoint registered it and its
+    /// punch request stream is still open
+    #[allow(clippy::result_large_err)]
     pub fn insert(
         &self,
         server_nated_addr: SocketAddr,
         req_tx: UnboundedSender<ServerPunchRequest>,
         server_certificate: &[u8],
+        protocol_version: u32,
         registered_ip: &str,
         cluster_id: &str,
-    ) {
+    ) -> Result<(), Status> {
         let resolved_target = ResolvedTarget {
             natted_address: server_nated_addr,
             punch_req_stream: req_tx,
             server_certificate: server_certificate.to_vec(),
+            protocol_version,
         };
         let virtual_target_key = VirtualTarget {
             ip: registered_ip.to_owned(),
             cluster_id: cluster_id.to_owned(),
         };
 
-        // replace the new target in the registered endpoint map
-        if let Some(prev_tgt) = self.0.insert(virtual_target_key.clone(), resolved_target) {
-            if prev_tgt.punch_req_stream.is_closed() {
-                info!(
-                    ip = virtual_target_key.ip,
-                    cluster = virtual_target_key.cluster_id,
-                    "replaced closed target"
-                );
-            } else {
+        // replace the new target in the registered endpoint map, unless it
+        // is still served by another endpoint
+        let res =
+            self.endpoints
+                .try_insert(virtual_target_key.clone(), resolved_target, |prev_tgt| {
+                    prev_tgt.punch_req_stream.is_closed()
+                        || prev_tgt.natted_address == server_nated_addr
+                });
+        match res {
+            Ok(Some(prev_tgt)) if prev_tgt.punch_req_stream.is_closed() => info!(
+                ip = virtual_target_key.ip,
+                cluster = virtual_target_key.cluster_id,
+                "replaced closed target"
+            ),
+            Ok(Some(_)) => info!(
+                ip = virtual_target_key.ip,
+                cluster = virtual_target_key.cluster_id,
+                "replaced target registered from the same address"
+            ),
+            Ok(None) => {}
+            Err(_) => {
+                let msg = "Virtual IP already registered by another endpoint";
                 error!(
                     ip = virtual_target_key.ip,
                     cluster = virtual_target_key.cluster_id,
-                    "replaced unclosed target"
+                    msg
                 );
+                return Err(Status::already_exists(msg));
             }
         }
+        Ok(())
+    }
+
+    /// Send the request on the open punch request streams of the cluster,
+    /// returns the number of endpoints it was sent to
+    pub fn broadcast(&self, cluster_id: &str, req: &ServerPunchRequest) -> usize {
+        let mut sent = 0;
+        self.endpoints.for_each(|key, target| {
+            if key.cluster_id == cluster_id && target.punch_req_stream.send(req.clone()).is_ok() {
+                sent += 1;
+            }
+        });
+        sent
+    }
+
+    /// Evict the endpoints whose punch request stream has been closed for
+    /// longer than the retention
+    pub fn sweep(&self, retention: Duration, stats: &GcStats) {
+        let now = Instant::now();
+        let mut closed_since = self.closed_since.lock().unwrap();
+        let mut live = 0;
+        let evicted = self.endpoints.retain(|key, target| {
+            if !target.punch_req_stream.is_closed() {
+                closed_since.remove(key);
+                live += 1;
+                return true;
+            }
+            let since = *closed_since.entry(key.clone()).or_insert(now);
+            let keep = now.duration_since(since) < retention;
+            if keep {
+                live += 1;
+            } else {
+                closed_since.remove(key);
+            }
+            keep
+        });
+        stats.record_endpoints(live, evicted);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sweep() {
+        let endpoints = RegisteredEndpoints::new();
+        let stats = GcStats::default();
+        let addr = "52.1.2.3:4000".parse().unwrap();
+        let (open_tx, _open_rx) = mpsc::unbounded_channel();
+        endpoints
+            .insert(addr, open_tx, &[], 1, "172.28.0.1", "c1")
+            .unwrap();
+        let (closed_tx, _) = mpsc::unbounded_channel();
+        endpoints
+            .insert(addr, closed_tx, &[], 1, "172.28.0.2", "c1")
+            .unwrap();
+
+        // the closed endpoint is retained until the retention elapses
+        endpoints.sweep(Duration::from_secs(60), &stats);
+        assert_eq!(stats.counts().live_endpoints, 2);
+        endpoints.sweep(Duration::ZERO, &stats);
+        let counts = stats.counts();
+        assert_eq!((counts.live_endpoints, counts.evicted_endpoints), (1, 1));
+        let wait = Duration::from_millis(10);
+        assert!(endpoints.get("172.28.0.1", "c1", wait).await.is_ok());
+        let res = endpoints.get("172.28.0.2", "c1", wait).await;
+        assert!(matches!(res, Err(err) if err.code() == tonic::Code::DeadlineExceeded));
+
+        // withdrawn endpoints are dropped without being counted as evicted
+        assert!(endpoints.remove("172.28.0.1", "c1", addr));
+        endpoints.sweep(Duration::ZERO, &stats);
+        let counts = stats.counts();
+        assert_eq!((counts.live_endpoints, counts.evicted_endpoints), (0, 1));
+    }
+
+    #[test]
+    fn test_insert_conflicts() {
+        let endpoints = RegisteredEndpoints::new();
+        let addr = "52.1.2.3:4000".parse().unwrap();
+        let other_addr = "52.1.2.4:4000".parse().unwrap();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        endpoints
+            .insert(addr, tx.clone(), &[], 1, "172.28.0.1", "c1")
+            .unwrap();
+        // the same endpoint can register again
+        endpoints
+            .insert(addr, tx.clone(), &[], 1, "172.28.0.1", "c1")
+            .unwrap();
+        let res = endpoints.insert(other_addr, tx, &[], 1, "172.28.0.1", "c1");
+        assert_eq!(res.unwrap_err().code(), tonic::Code::AlreadyExists);
+        // but the target of a closed stream can be taken over
+        let (closed_tx, _) = mpsc::unbounded_channel();
+        endpoints
+            .insert(addr, closed_tx, &[], 1, "172.28.0.2", "c1")
+            .unwrap();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        endpoints
+            .insert(other_addr, tx, &[], 1, "172.28.0.2", "c1")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let endpoints = RegisteredEndpoints::new();
+        let addr = "52.1.2.3:4000".parse().unwrap();
+        let (tx_1, mut rx_1) = mpsc::unbounded_channel();
+        endpoints
+            .insert(addr, tx_1, &[], 1, "172.28.0.1", "c1")
+            .unwrap();
+        let (closed_tx, _) = mpsc::unbounded_channel();
+        endpoints
+            .insert(addr, closed_tx, &[], 1, "172.28.0.2", "c1")
+            .unwrap();
+        let (other_tx, mut other_rx) = mpsc::unbounded_channel();
+        endpoints
+            .insert(addr, other_tx, &[], 1, "172.28.0.1", "c2")
+            .unwrap();
+
+        let req = ServerPunchRequest {
+            punch_id: 42,
+            ..Default::default()
+        };
+        assert_eq!(endpoints.broadcast("c1", &req), 1);
+        assert_eq!(rx_1.try_recv().unwrap().punch_id, 42);
+        assert!(other_rx.try_recv().is_err());
     }
 }
@@ -1,38 +1,307 @@
 use crate::address_stream::PunchRequestStream;
 use crate::cluster_manager::*;
+use crate::gc::{GcCounts, GcStats, Retention};
+use crate::punch_acks::PunchAcks;
 use crate::registered_endpoints::RegisteredEndpoints;
 use crate::{
-    seed_server::Seed, Address, ClientBindingRequest, ClientBindingResponse, NodeBindingRequest,
-    NodeBindingResponse, ServerBindingRequest, ServerPunchRequest,
+    seed_server::Seed, AbortClusterRequest, AbortClusterResponse, Address, AddressConv,
+    ClientBindingRequest, ClientBindingResponse, ClusterAbort, GetClusterSummaryRequest,
+    ListClustersRequest, ListClustersResponse, NodeBindingRequest, NodeBindingResponse,
+    NodeEventKind, PunchReport, PunchReportResponse, PunchStatus, ServerBindingRequest,
+    ServerPunchRequest, UnbindRequest, UnbindResponse, WaitClusterRequest, WaitClusterResponse,
+    WatchClusterRequest,
 };
 use futures::stream::{Stream, StreamExt};
-use std::{pin::Pin, sync::Arc};
-use tokio::sync::mpsc;
+use std::{pin::Pin, sync::Arc, time::Duration};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{interval, timeout, MissedTickBehavior};
+use tokio_stream::wrappers::UnboundedReceiverStream;
 use tonic::{Request, Response, Result, Status, Streaming};
-use tracing::{debug, error, field::Empty as EmptyField, instrument};
+use tracing::{debug, error, field::Empty as EmptyField, instrument, warn, Instrument, Span};
 
+/// Heartbeats a node can miss before being marked failed
+const MISSED_HEARTBEATS: u32 = 3;
+/// Bound on the predicted mappings of a node that its targets punch
+const MAX_PORT_PREDICTION_WINDOW: u32 = 64;
+
+/// Punch request notifying a node that its cluster was aborted
+fn abort_request(cause: &AbortCause) -> ServerPunchRequest {
+    ServerPunchRequest {
+        abort: Some(ClusterAbort {
+            virtual_ip: cause.virt_ip.clone().unwrap_or_default(),
+            reason: cause.reason.clone(),
+        }),
+        ..Default::default()
+    }
+}
+
+/// Error answered to the clients of a target that cannot be resolved
+fn lost_target_status(status: TargetStatus) -> Status {
+    match status {
+        TargetStatus::Left => {
+            let msg = "Target node left the cluster";
+            warn!(msg);
+            Status::failed_precondition(msg)
+        }
+        TargetStatus::Absent => {
+            let msg = "Target is not a node of the complete cluster";
+            warn!(msg);
+            Status::not_found(msg)
+        }
+        TargetStatus::Aborted(cause) => {
+            warn!(%cause, "cluster aborted");
+            Status::aborted(cause.to_string())
+        }
+        TargetStatus::Expected(_) => Status::internal("Target is still expected"),
+    }
+}
+
+#[derive(Clone)]
 pub struct SeedService {
     registered_endpoints: Arc<RegisteredEndpoints>,
     cluster_manager: Arc<ClusterManager>,
+    punch_acks: Arc<PunchAcks>,
+    gc_stats: Arc<GcStats>,
 }
 
 #[allow(clippy::new_without_default)]
 impl SeedService {
     pub fn new() -> (Self, ClusterManagerTask) {
-        let (cluster_manager, task) = ClusterManager::new();
+        Self::with_retention(Retention::default())
+    }
+
+    /// Evict finished clusters and closed endpoints once they have been
+    /// retained for the provided durations
+    pub fn with_retention(retention: Retention) -> (Self, ClusterManagerTask) {
+        let gc_stats = Arc::new(GcStats::default());
+        let (cluster_manager, task) = ClusterManager::new(retention, Arc::clone(&gc_stats));
+        let registered_endpoints = Arc::new(RegisteredEndpoints::new());
+        // the sweeps stop once the service is dropped
+        let endpoints_ref = Arc::downgrade(&registered_endpoints);
+        let stats_ref = Arc::clone(&gc_stats);
+        tokio::spawn(async move {
+            let mut sweeps = interval(retention.sweep_interval);
+            sweeps.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                sweeps.tick().await;
+                match endpoints_ref.upgrade() {
+                    Some(endpoints) => endpoints.sweep(retention.endpoints, &stats_ref),
+                    None => break,
+                }
+            }
+        });
         (
             Self {
-                registered_endpoints: Arc::new(RegisteredEndpoints::new()),
+                registered_endpoints,
                 cluster_manager: Arc::new(cluster_manager),
+                punch_acks: Arc::new(PunchAcks::new()),
+                gc_stats,
             },
             task,
         )
     }
+
+    /// Counts of live and evicted clusters and endpoints
+    pub fn gc_counts(&self) -> GcCounts {
+        self.gc_stats.counts()
+    }
+
+    /// Abort the cluster and notify its nodes through their punch request
+    /// streams, returns whether it was not aborted yet or None if the cluster
+    /// is unknown or was evicted
+    async fn broadcast_abort(&self, cluster_id: &str, cause: AbortCause) -> Option<bool> {
+        let req = abort_request(&cause);
+        let first = self
+            .cluster_manager
+            .abort_cluster(cluster_id.to_owned(), cause)
+            .await;
+        if first == Some(true) {
+            let notified = self.registered_endpoints.broadcast(cluster_id, &req);
+            warn!(notified, "cluster abort broadcast");
+        }
+        first
+    }
+
+    /// Bind the node with the first request of the stream, returns the
+    /// request with the virtual IP of the node
+    async fn start_node_binding(
+        &self,
+        stream: &mut Streaming<NodeBindingRequest>,
+    ) -> Result<(NodeBindingRequest, String), Status> {
+        let bind_req = match stream.next().await {
+            Some(Ok(res)) => {
+                tracing::Span::current().record("clust", &res.cluster_id);
+                debug!(virt=%res.source_virtual_ip, nat_type=?res.nat_type(), "new request");
+                res
+            }
+            Some(Err(err)) => {
+                let msg = "Unexpected error in stream";
+                error!(%err, msg);
+                return Err(Status::invalid_argument(msg));
+            }
+            None => {
+                let msg = "Expected one binding request";
+                error!(msg);
+                return Err(Status::invalid_argument(msg));
+            }
+        };
+        // without a static virtual IP, one is allocated from the subnet
+        let virt_ip = if bind_req.source_virtual_ip.is_empty() {
+            let subnet = bind_req.virtual_subnet.parse().map_err(|_| {
+                Status::invalid_argument("Expected a virtual IP or a valid virtual subnet")
+            })?;
+            VirtualIpRequest::Allocate {
+                subnet,
+                hint: bind_req.virtual_ip_hint.parse().ok(),
+            }
+        } else {
+            VirtualIpRequest::Static(bind_req.source_virtual_ip.clone())
+        };
+        let observed_mappings = bind_req
+            .observed_mappings
+            .iter()
+            .cloned()
+            .map(|addr| AddressConv(addr).parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| Status::invalid_argument("Invalid observed mapping"))?;
+        let (tx, rx) = oneshot::channel();
+        self.cluster_manager.send(
+            bind_req.cluster_id.clone(),
+            Message::BindNodeStart {
+                cluster_size: bind_req.cluster_size,
+                virt_ip,
+                time: Message::now(),
+                nat_type: bind_req.nat_type(),
+                observed_mappings,
+                // each prediction is punched by the targets of the node
+                port_prediction_window: bind_req
+                    .port_prediction_window
+                    .min(MAX_PORT_PREDICTION_WINDOW),
+                resolution_timeout: Duration::from_millis(
+                    bind_req.target_resolution_timeout_ms.into(),
+                ),
+                name: bind_req.node_name.clone(),
+                tx,
+            },
+        );
+        let virt_ip = match rx.await {
+            Ok(Ok(virt_ip)) => {
+                debug!(virt_ip, "node bound");
+                virt_ip
+            }
+            Ok(Err(rejection)) => {
+                let msg = rejection.to_string();
+                return Err(match rejection {
+                    NodeRejection::IpAlreadyBound => Status::already_exists(msg),
+                    NodeRejection::SubnetExhausted => Status::resource_exhausted(msg),
+                    NodeRejection::SizeMismatch { .. } | NodeRejection::ClusterFull => {
+                        Status::failed_precondition(msg)
+                    }
+                });
+            }
+            Err(_) => return Err(Status::internal("Cluster manager dropped the binding")),
+        };
+        Ok((bind_req, virt_ip))
+    }
+
+    /// Track the events of a bound node until its request stream ends
+    async fn follow_node(
+        &self,
+        mut stream: Streaming<NodeBindingRequest>,
+        bind_req: NodeBindingRequest,
+        virt_ip: String,
+    ) -> Result<(), Status> {
+        // the node is failed once it misses a few heartbeats
+        let heartbeat_timeout = (bind_req.heartbeat_interval_ms > 0).then(|| {
+            Duration::from_millis(bind_req.heartbeat_interval_ms.into()) * MISSED_HEARTBEATS
+        });
+        loop {
+            let next = match heartbeat_timeout {
+                Some(heartbeat_timeout) => timeout(heartbeat_timeout, stream.next())
+                    .await
+                    .unwrap_or_else(|_| Some(Err(Status::deadline_exceeded("Heartbeats stopped")))),
+                None => stream.next().await,
+            };
+            let next = match next {
+                Some(Ok(NodeBindingRequest {
+                    event: Some(event), ..
+                })) => Ok(event),
+                Some(Ok(_)) => Err(Status::invalid_argument(
+                    "Expected only one binding request",
+                )),
+                Some(Err(status)) => Err(status),
+                None => break,
+            };
+            // the node must end in the cluster state for it to finish
+            let event = match next {
+                Ok(event) => event,
+                Err(status) => {
+                    error!(%status, "node binding broken");
+                    self.cluster_manager.send(
+                        bind_req.cluster_id.clone(),
+                        Message::BindNodeFailed {
+                            virt_ip: virt_ip.clone(),
+                            time: Message::now(),
+                        },
+                    );
+                    let cause = AbortCause {
+                        virt_ip: Some(virt_ip),
+                        reason: status.message().to_owned(),
+                    };
+                    self.broadcast_abort(&bind_req.cluster_id, cause).await;
+                    return Err(status);
+                }
+            };
+            let lifecycle = match event.kind() {
+                NodeEventKind::Heartbeat | NodeEventKind::Unspecified => None,
+                NodeEventKind::AppStarted => Some(NodeLifecycle::AppStarted),
+                NodeEventKind::AppExited => Some(NodeLifecycle::AppExited(event.exit_code)),
+                NodeEventKind::ShuttingDown => Some(NodeLifecycle::ShuttingDown),
+            };
+            self.cluster_manager.send(
+                bind_req.cluster_id.clone(),
+                Message::NodeEvent {
+                    virt_ip: virt_ip.clone(),
+                    time: Message::now(),
+                    lifecycle,
+                },
+            );
+            // a crashed app fails the whole cluster, negative codes being
+            // reported for signals and launcher timeouts
+            match lifecycle {
+                Some(NodeLifecycle::AppExited(code)) if code != 0 => {
+                    let cause = AbortCause {
+                        virt_ip: Some(virt_ip.clone()),
+                        reason: format!("app exited with code {}", code),
+                    };
+                    self.broadcast_abort(&bind_req.cluster_id, cause).await;
+                }
+                _ => {}
+            }
+        }
+        self.cluster_manager.send(
+            bind_req.cluster_id.clone(),
+            Message::BindNodeEnd {
+                virt_ip,
+                time: Message::now(),
+            },
+        );
+        debug!(
+            "{:?}",
+            self.cluster_manager
+                .get_summary(bind_req.cluster_id.clone())
+                .await
+        );
+        Ok(())
+    }
 }
 
 #[tonic::async_trait]
 impl Seed for SeedService {
     type BindServerStream = Pin<Box<dyn Stream<Item = Result<ServerPunchRequest, Status>> + Send>>;
+    type BindNodeV2Stream = Pin<Box<dyn Stream<Item = Result<NodeBindingResponse, Status>> + Send>>;
+    type WatchClusterStream =
+        Pin<Box<dyn Stream<Item = Result<crate::MembershipEvent, Status>> + Send>>;
 
     #[instrument(
         name = "bind_cli",
@@ -52,7 +321,34 @@ impl Seed for SeedService {
         let tgt_ip = &req.get_ref().target_virtual_ip;
         let src_ip = &req.get_ref().source_virtual_ip;
         let cluster_id = &req.get_ref().cluster_id;
-        let src_nated_addr = req.remote_addr().unwrap();
+        let src_nated_addr = match req.get_ref().observed_client_addr.clone() {
+            Some(addr) => {
+                let observed_addr = AddressConv(addr)
+                    .parse()
+                    .map_err(|_| Status::invalid_argument("Invalid observed client address"))?;
+                // only the port of the client is expected to change, so that
+                // punches cannot be redirected to arbitrary hosts
+                if observed_addr.ip() != req.remote_addr().unwrap().ip() {
+                    let msg = "Observed client address does not match the client IP";
+                    warn!(%observed_addr, msg);
+                    return Err(Status::invalid_argument(msg));
+                }
+                debug!(%observed_addr, "client reported its mapping");
+                observed_addr
+            }
+            None => req.remote_addr().unwrap(),
+        };
+
+        // fail fast if the target cannot register anymore
+        let wait = match self
+            .cluster_manager
+            .get_target_status(cluster_id.clone(), tgt_ip.clone())
+            .await
+        {
+            Some(TargetStatus::Expected(wait)) => wait,
+            Some(status) => return Err(lost_target_status(status)),
+            None => DEFAULT_RESOLUTION_TIMEOUT,
+        };
 
         self.cluster_manager.send(
             cluster_id.clone(),
@@ -62,21 +358,53 @@ impl Seed for SeedService {
             },
         );
 
-        let resolved_target = self.registered_endpoints.get(tgt_ip, cluster_id).await?;
+        // the target might also leave or the cluster abort while waiting
+        let resolved_target = tokio::select! {
+            res = self.registered_endpoints.get(tgt_ip, cluster_id, wait) => res?,
+            Some(status) = self
+                .cluster_manager
+                .wait_target_lost(cluster_id.clone(), tgt_ip.clone()) => {
+                return Err(lost_target_status(status));
+            }
+        };
 
         debug!(tgt_nat=%resolved_target.natted_address);
+        let predicted_client_addrs = self
+            .cluster_manager
+            .get_predicted_mappings(cluster_id.clone(), src_ip.clone())
+            .await;
+        if !predicted_client_addrs.is_empty() {
+            debug!(predicted = predicted_client_addrs.len(), "port prediction");
+        }
+        let pending_punch = self.punch_acks.register();
         let punch_req_res = resolved_target.punch_req_stream.send(ServerPunchRequest {
             client_nated_addr: Some(Address {
                 ip: src_nated_addr.ip().to_string(),
-                port: src_nated_addr.port().try_into().unwrap(),
+                port: src_nated_addr.port().into(),
             }),
             client_virtual_ip: src_ip.clone(),
+            predicted_client_addrs: predicted_client_addrs
+                .into_iter()
+                .map(|addr| Address {
+                    ip: addr.ip().to_string(),
+                    port: addr.port().into(),
+                })
+                .collect(),
+            punch_id: pending_punch.id,
+            abort: None,
         });
-        let failed_punch_request = if let Err(err) = punch_req_res {
+        let ack_timeout_ms = req.get_ref().punch_ack_timeout_ms;
+        let (failed_punch_request, punch_status) = if let Err(err) = punch_req_res {
             error!(%err, "failed to send punch request");
-            true
+            (true, PunchStatus::Failed)
+        } else if ack_timeout_ms == 0 {
+            (false, PunchStatus::Unspecified)
         } else {
-            false
+            let status = pending_punch
+                .wait(Duration::from_millis(ack_timeout_ms.into()))
+                .await;
+            debug!(?status, "punch acknowledgement");
+            (false, status)
         };
 
         self.cluster_manager.send(
@@ -91,13 +419,27 @@ impl Seed for SeedService {
         Ok(Response::new(ClientBindingResponse {
             target_nated_addr: Some(Address {
                 ip: resolved_target.natted_address.ip().to_string(),
-                port: resolved_target.natted_address.port().try_into().unwrap(),
+                port: resolved_target.natted_address.port().into(),
             }),
             server_certificate: resolved_target.server_certificate,
             failed_punch_request,
+            punch_status: punch_status.into(),
+            target_protocol_version: resolved_target.protocol_version,
         }))
     }
 
+    #[instrument(name = "report_punch", skip_all, fields(id = req.get_ref().punch_id))]
+    async fn report_punch(
+        &self,
+        req: Request<PunchReport>,
+    ) -> Result<Response<PunchReportResponse>, Status> {
+        let report = req.into_inner();
+        if !self.punch_acks.report(report.punch_id, report.status()) {
+            debug!("client binding not waiting for this punch anymore");
+        }
+        Ok(Response::new(PunchReportResponse {}))
+    }
+
     #[instrument(
         name = "bind_srv",
         skip_all,
@@ -111,23 +453,35 @@ impl Seed for SeedService {
         let server_nated_addr = req.remote_addr().unwrap();
         let registered_ip = &req.get_ref().virtual_ip;
         let cluster_id = &req.get_ref().cluster_id;
-        self.cluster_manager.send(
-            cluster_id.clone(),
-            Message::BindServerStart {
-                virt_ip: registered_ip.clone(),
-            },
-        );
 
         let (req_tx, req_rx) = mpsc::unbounded_channel();
+        let abort_tx = req_tx.clone();
 
+        // rejected registrations are not reported to the cluster manager
         self.registered_endpoints.insert(
             server_nated_addr,
             req_tx,
             &req.get_ref().server_certificate,
+            req.get_ref().protocol_version,
             registered_ip,
             cluster_id,
+        )?;
+        self.cluster_manager.send(
+            cluster_id.clone(),
+            Message::BindServerStart {
+                virt_ip: registered_ip.clone(),
+            },
         );
 
+        // nodes binding after the abort broadcast are notified right away
+        if let Some(TargetStatus::Aborted(cause)) = self
+            .cluster_manager
+            .get_target_status(cluster_id.clone(), registered_ip.clone())
+            .await
+        {
+            abort_tx.send(abort_request(&cause)).ok();
+        }
+
         debug!("request returning");
         self.cluster_manager.send(
             cluster_id.clone(),
@@ -140,6 +494,138 @@ impl Seed for SeedService {
         ))
     }
 
+    #[instrument(
+        name = "unbind",
+        skip_all,
+        fields(clust=%req.get_ref().cluster_id,virt=%req.get_ref().virtual_ip, nat=%req.remote_addr().unwrap())
+    )]
+    async fn unbind(
+        &self,
+        req: Request<UnbindRequest>,
+    ) -> Result<Response<UnbindResponse>, Status> {
+        let server_nated_addr = req.remote_addr().unwrap();
+        let UnbindRequest {
+            cluster_id,
+            virtual_ip,
+        } = req.into_inner();
+        // dropping the registered endpoint closes its punch request stream
+        if self
+            .registered_endpoints
+            .remove(&virtual_ip, &cluster_id, server_nated_addr)
+        {
+            debug!("endpoint withdrawn");
+            Ok(Response::new(UnbindResponse {}))
+        } else {
+            let msg = "No endpoint registered from this address";
+            error!(msg);
+            Err(Status::not_found(msg))
+        }
+    }
+
+    #[instrument(
+        name = "wait_clust",
+        skip_all,
+        fields(clust=%req.get_ref().cluster_id, virt=%req.get_ref().virtual_ip, stage=%req.get_ref().stage)
+    )]
+    async fn wait_cluster(
+        &self,
+        req: Request<WaitClusterRequest>,
+    ) -> Result<Response<WaitClusterResponse>, Status> {
+        let WaitClusterRequest {
+            cluster_id,
+            virtual_ip,
+            stage,
+        } = req.into_inner();
+        let stage = (!stage.is_empty()).then_some(stage);
+        let res = self
+            .cluster_manager
+            .wait_cluster(cluster_id, virtual_ip, stage)
+            .await;
+        match res {
+            Some(Ok(())) => {
+                debug!("barrier reached");
+                Ok(Response::new(WaitClusterResponse {}))
+            }
+            Some(Err(err @ BarrierError::UnknownNode)) => Err(Status::not_found(err.to_string())),
+            Some(Err(err @ BarrierError::Broken)) => {
+                warn!(%err);
+                Err(Status::aborted(err.to_string()))
+            }
+            None => Err(Status::not_found("Cluster not found")),
+        }
+    }
+
+    #[instrument(
+        name = "abort_clust",
+        skip_all,
+        fields(clust=%req.get_ref().cluster_id, reason=%req.get_ref().reason)
+    )]
+    async fn abort_cluster(
+        &self,
+        req: Request<AbortClusterRequest>,
+    ) -> Result<Response<AbortClusterResponse>, Status> {
+        let AbortClusterRequest { cluster_id, reason } = req.into_inner();
+        let cause = AbortCause {
+            virt_ip: None,
+            reason,
+        };
+        match self.broadcast_abort(&cluster_id, cause).await {
+            Some(true) => Ok(Response::new(AbortClusterResponse {})),
+            Some(false) => {
+                debug!("cluster already aborted");
+                Ok(Response::new(AbortClusterResponse {}))
+            }
+            None => Err(Status::not_found("Cluster not found")),
+        }
+    }
+
+    #[instrument(name = "get_summary", skip_all, fields(clust=%req.get_ref().cluster_id))]
+    async fn get_cluster_summary(
+        &self,
+        req: Request<GetClusterSummaryRequest>,
+    ) -> Result<Response<crate::Summary>, Status> {
+        let cluster_id = req.into_inner().cluster_id;
+        match self.cluster_manager.get_summary(cluster_id.clone()).await {
+            Some(summary) => Ok(Response::new(summary.into_proto(cluster_id))),
+            None => Err(Status::not_found("Cluster not found")),
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    #[instrument(name = "watch_clust", skip_all, fields(clust=%req.get_ref().cluster_id))]
+    async fn watch_cluster(
+        &self,
+        req: Request<WatchClusterRequest>,
+    ) -> Result<Response<Self::WatchClusterStream>, Status> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.cluster_manager
+            .watch_cluster(req.into_inner().cluster_id, tx);
+        debug!("watching membership");
+        Ok(Response::new(
+            UnboundedReceiverStream::new(rx)
+                .map(|event| Ok(event.into()))
+                .boxed(),
+        ))
+    }
+
+    #[instrument(name = "list_clusters", skip_all)]
+    async fn list_clusters(
+        &self,
+        _req: Request<ListClustersRequest>,
+    ) -> Result<Response<ListClustersResponse>, Status> {
+        let clusters = self
+            .cluster_manager
+            .list_summaries()
+            .await
+            .into_iter()
+            .map(|(cluster_id, summary)| summary.into_proto(cluster_id))
+            .collect();
+        Ok(Response::new(ListClustersResponse {
+            clusters,
+            gc_counts: Some(self.gc_counts().into()),
+        }))
+    }
+
     #[instrument(
         name = "bind_node",
         skip_all,
@@ -150,51 +636,40 @@ impl Seed for SeedService {
         req: Request<Streaming<NodeBindingRequest>>,
     ) -> Result<Response<NodeBindingResponse>, Status> {
         let mut stream = req.into_inner();
-        let bind_req = match stream.next().await {
-            Some(Ok(res)) => {
-                tracing::Span::current().record("clust", &res.cluster_id);
-                debug!(virt=%res.source_virtual_ip, "new request");
-                res
-            }
-            Some(Err(err)) => {
-                let msg = "Unexpected error in stream";
-                error!(%err, msg);
-                return Err(Status::invalid_argument(msg));
-            }
-            None => {
-                let msg = "Expected one binding request";
-                error!(msg);
-                return Err(Status::invalid_argument(msg));
-            }
+        let (bind_req, virt_ip) = self.start_node_binding(&mut stream).await?;
+        // answered once the binding ends, as expected by legacy perforators
+        self.follow_node(stream, bind_req, virt_ip.clone()).await?;
+        Ok(Response::new(NodeBindingResponse {
+            assigned_virtual_ip: virt_ip,
+        }))
+    }
+
+    #[instrument(
+        name = "bind_node",
+        skip_all,
+        fields(clust = EmptyField,src_nat=%req.remote_addr().unwrap())
+    )]
+    async fn bind_node_v2(
+        &self,
+        req: Request<Streaming<NodeBindingRequest>>,
+    ) -> Result<Response<Self::BindNodeV2Stream>, Status> {
+        let mut stream = req.into_inner();
+        let (bind_req, virt_ip) = self.start_node_binding(&mut stream).await?;
+        let (resp_tx, resp_rx) = mpsc::unbounded_channel();
+        let assigned = NodeBindingResponse {
+            assigned_virtual_ip: virt_ip.clone(),
         };
-        self.cluster_manager.send(
-            bind_req.cluster_id.clone(),
-            Message::BindNodeStart {
-                cluster_size: bind_req.cluster_size,
-                virt_ip: bind_req.source_virtual_ip.clone(),
-                time: Message::now(),
-            },
-        );
-        // debug!("waiting for bind node stream to close");
-        if stream.next().await.is_some() {
-            return Err(Status::invalid_argument(
-                "Expected only one binding request",
-            ));
-        }
-        // debug!("bind node stream closed, recording node end");
-        self.cluster_manager.send(
-            bind_req.cluster_id.clone(),
-            Message::BindNodeEnd {
-                virt_ip: bind_req.source_virtual_ip,
-                time: Message::now(),
-            },
-        );
-        debug!(
-            "{:?}",
-            self.cluster_manager
-                .get_summary(bind_req.cluster_id.clone())
-                .await
+        resp_tx.send(Ok(assigned)).unwrap();
+        // the binding is followed until the node closes its request stream
+        let service = self.clone();
+        tokio::spawn(
+            async move {
+                if let Err(status) = service.follow_node(stream, bind_req, virt_ip).await {
+                    resp_tx.send(Err(status)).ok();
+                }
+            }
+            .instrument(Span::current()),
         );
-        Ok(Response::new(NodeBindingResponse {}))
+        Ok(Response::new(UnboundedReceiverStream::new(resp_rx).boxed()))
     }
 }
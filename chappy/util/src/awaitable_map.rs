@@ -64,6 +64,103 @@ where
             }
         })
     }
+
+    /// Insert the key/value pair unless a value is in place and `replace`
+    /// returns false for it, in which case the value is given back
+    pub fn try_insert<F>(&self, key: K, value: V, replace: F) -> Result<Option<V>, V>
+    where
+        F: FnOnce(&V) -> bool,
+    {
+        trace_span!("lock", src = "AwaitableMap.try_insert").in_scope(|| {
+            let mut guard = self.inner.lock().unwrap();
+            if let Some(target_tx) = guard.get(&key) {
+                if target_tx.borrow().as_ref().is_some_and(|v| !replace(v)) {
+                    return Err(value);
+                }
+                Ok(target_tx.send_replace(Some(value)))
+            } else {
+                let (tx, _rx) = watch::channel(Some(value));
+                guard.insert(key, tx);
+                Ok(None)
+            }
+        })
+    }
+
+    /// Reset the value for the key if it satisfies the predicate, and return
+    /// the removed value
+    ///
+    /// The key is kept in the map so that pending and future calls to `get`
+    /// wait for a new value to be inserted.
+    pub fn remove_if<F>(&self, key: &K, predicate: F) -> Option<V>
+    where
+        F: FnOnce(&V) -> bool,
+    {
+        trace_span!("lock", src = "AwaitableMap.remove_if").in_scope(|| {
+            let guard = self.inner.lock().unwrap();
+            let value_tx = guard.get(key)?;
+            let matches = value_tx.borrow().as_ref().is_some_and(predicate);
+            if matches {
+                value_tx.send_replace(None)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Keep the values for which the predicate returns true, and return the
+    /// number of removed values
+    ///
+    /// Keys without value are dropped as well unless a `get` is waiting for
+    /// them, in which case they are kept without value.
+    pub fn retain<F>(&self, mut predicate: F) -> usize
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        trace_span!("lock", src = "AwaitableMap.retain").in_scope(|| {
+            let mut guard = self.inner.lock().unwrap();
+            let mut removed = 0;
+            guard.retain(|key, value_tx| {
+                let keep_value = match value_tx.borrow().as_ref() {
+                    Some(value) => predicate(key, value),
+                    None => false,
+                };
+                if keep_value {
+                    return true;
+                }
+                if value_tx.borrow().is_some() {
+                    removed += 1;
+                    value_tx.send_replace(None);
+                }
+                value_tx.receiver_count() > 0
+            });
+            removed
+        })
+    }
+
+    /// Call `f` on each key that has a value
+    pub fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&K, &V),
+    {
+        trace_span!("lock", src = "AwaitableMap.for_each").in_scope(|| {
+            let guard = self.inner.lock().unwrap();
+            for (key, value_tx) in guard.iter() {
+                if let Some(value) = value_tx.borrow().as_ref() {
+                    f(key, value);
+                }
+            }
+        })
+    }
+}
+
+impl<K, V> Default for AwaitableMap<K, V>
+where
+    K: Eq + Hash,
+    V: Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[cfg(test)]
@@ -126,13 +223,75 @@ mod tests {
         task.await.unwrap();
     }
 
+    #[tokio::test]
+    async fn test_awaitable_map_remove() {
+        let map = Arc::new(AwaitableMap::new());
+        assert_eq!(map.insert(1, "first"), None);
+        assert_eq!(map.remove_if(&1, |v| *v == "other"), None);
+        assert_eq!(map.remove_if(&1, |v| *v == "first"), Some("first"));
+        assert_eq!(map.remove_if(&1, |_| true), None);
+        assert_eq!(map.remove_if(&2, |_| true), None);
+        // `get` waits for the removed value to be inserted again
+        let map_ref = Arc::clone(&map);
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(map_ref.insert(1, "second"), None);
+        });
+        let get_fut = map.get(1, |_| panic!("Should not be called because value removed"));
+        let timed_get_fut = tokio::time::timeout(Duration::from_millis(100), get_fut);
+        assert_eq!(timed_get_fut.await.unwrap(), "second");
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_awaitable_map_try_insert() {
+        let map = AwaitableMap::new();
+        assert_eq!(map.try_insert(1, "first", |_| false), Ok(None));
+        assert_eq!(map.try_insert(1, "second", |_| false), Err("second"));
+        assert_eq!(
+            map.try_insert(1, "second", |v| *v == "first"),
+            Ok(Some("first"))
+        );
+        assert_eq!(map.remove_if(&1, |_| true), Some("second"));
+        assert_eq!(map.try_insert(1, "third", |_| false), Ok(None));
+        assert_eq!(map.get(1, |_| false).await, "third");
+    }
+
+    #[tokio::test]
+    async fn test_awaitable_map_retain() {
+        let map = AwaitableMap::new();
+        assert_eq!(map.insert(1, "first"), None);
+        assert_eq!(map.insert(2, "second"), None);
+        assert_eq!(map.retain(|k, _| *k == 1), 1);
+        assert_eq!(map.remove_if(&1, |_| true), Some("first"));
+        // keys without values are dropped without being counted
+        assert_eq!(map.retain(|_, _| true), 0);
+        assert!(map.inner.lock().unwrap().is_empty());
+        // unless they are awaited
+        assert_eq!(map.insert(3, "third"), None);
+        let _rx = map.inner.lock().unwrap().get(&3).unwrap().subscribe();
+        assert_eq!(map.retain(|_, _| false), 1);
+        assert_eq!(map.inner.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_awaitable_map_for_each() {
+        let map = AwaitableMap::new();
+        assert_eq!(map.insert(1, "first"), None);
+        assert_eq!(map.insert(2, "second"), None);
+        assert_eq!(map.remove_if(&2, |_| true), Some("second"));
+        let mut visited = vec![];
+        map.for_each(|k, v| visited.push((*k, *v)));
+        assert_eq!(visited, vec![(1, "first")]);
+    }
+
     #[tokio::test]
     async fn test_awaitable_map_multiple() {
         let map = Arc::new(AwaitableMap::new());
         // Insert the value that will be reset
         assert_eq!(map.insert(1, "first"), None);
         let mut callback_called = false;
-        for _ in [0..5] {
+        for _ in 0..5 {
             let get_fut = map.get(1, |v| {
                 assert_eq!(v, "first");
                 callback_called = true;
@@ -144,13 +303,3 @@ mod tests {
         }
     }
 }
-
-impl<K, V> Default for AwaitableMap<K, V>
-where
-    K: Eq + Hash,
-    V: Clone,
-{
-    fn default() -> Self {
-        Self::new()
-    }
-}
@@ -1,7 +1,9 @@
 pub mod awaitable_map;
+pub mod optional_fields;
 pub mod protocol;
 pub mod tcp_connect;
 pub mod test;
 mod tracing_helpers;
+pub mod tunnel_error;
 
 pub use tracing_helpers::{close_tracing, init_tracing, init_tracing_shared_lib};
@@ -0,0 +1,125 @@
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use std::net::Ipv4Addr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const END_TAG: u8 = 0;
+const SOURCE_VIRTUAL_IP_TAG: u8 = 1;
+const TRACE_CONTEXT_TAG: u8 = 2;
+const TAG_TAG: u8 = 3;
+const CLUSTER_ID_TAG: u8 = 4;
+
+/// Fields appended to the versioned protocol messages
+///
+/// Each field is encoded as a type byte and a length prefixed value, the list
+/// being terminated by a 0 type byte. Readers skip the field types they don't
+/// know, so new fields can be added without bumping the protocol version.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OptionalFields {
+    pub source_virtual_ip: Option<Ipv4Addr>,
+    /// Serialized context of the calling span, e.g. a W3C traceparent
+    pub trace_context: Option<String>,
+    /// Key value pairs, a key can be repeated
+    pub tags: Vec<(String, String)>,
+    /// Cluster of the source virtual IP, which identifies the source along
+    /// with it when a perforator serves multiple clusters
+    pub cluster_id: Option<String>,
+}
+
+fn invalid_data(msg: &str) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, msg)
+}
+
+async fn write_field<W: AsyncWrite + Unpin>(send: &mut W, tag: u8, value: &[u8]) -> IoResult<()> {
+    let len = u16::try_from(value.len()).map_err(|_| invalid_data("field too long"))?;
+    send.write_u8(tag).await?;
+    send.write_u16(len).await?;
+    send.write_all(value).await
+}
+
+impl OptionalFields {
+    pub async fn read<R: AsyncRead + Unpin>(recv: &mut R) -> IoResult<Self> {
+        let mut fields = Self::default();
+        loop {
+            let tag = recv.read_u8().await?;
+            if tag == END_TAG {
+                return Ok(fields);
+            }
+            let mut value = vec![0; recv.read_u16().await?.into()];
+            recv.read_exact(&mut value).await?;
+            match tag {
+                SOURCE_VIRTUAL_IP_TAG => {
+                    let octets: [u8; 4] = value
+                        .try_into()
+                        .map_err(|_| invalid_data("source virtual IP should be 4 bytes"))?;
+                    fields.source_virtual_ip = Some(octets.into());
+                }
+                TRACE_CONTEXT_TAG => {
+                    let ctx = String::from_utf8(value)
+                        .map_err(|_| invalid_data("trace context should be utf8"))?;
+                    fields.trace_context = Some(ctx);
+                }
+                TAG_TAG => {
+                    let tag =
+                        String::from_utf8(value).map_err(|_| invalid_data("tag should be utf8"))?;
+                    let (key, value) = tag
+                        .split_once('=')
+                        .ok_or_else(|| invalid_data("tag should be key=value"))?;
+                    fields.tags.push((key.to_owned(), value.to_owned()));
+                }
+                CLUSTER_ID_TAG => {
+                    let cluster_id = String::from_utf8(value)
+                        .map_err(|_| invalid_data("cluster id should be utf8"))?;
+                    fields.cluster_id = Some(cluster_id);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub async fn write<W: AsyncWrite + Unpin>(&self, send: &mut W) -> IoResult<()> {
+        if let Some(ip) = self.source_virtual_ip {
+            write_field(send, SOURCE_VIRTUAL_IP_TAG, &ip.octets()).await?;
+        }
+        if let Some(ctx) = &self.trace_context {
+            write_field(send, TRACE_CONTEXT_TAG, ctx.as_bytes()).await?;
+        }
+        for (key, value) in &self.tags {
+            write_field(send, TAG_TAG, format!("{}={}", key, value).as_bytes()).await?;
+        }
+        if let Some(cluster_id) = &self.cluster_id {
+            write_field(send, CLUSTER_ID_TAG, cluster_id.as_bytes()).await?;
+        }
+        send.write_u8(END_TAG).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn fields_roundtrip() {
+        let original = OptionalFields {
+            source_virtual_ip: Some(Ipv4Addr::new(172, 28, 0, 5)),
+            trace_context: Some(String::from("00-0af7651916cd43dd-b7ad6b71-01")),
+            tags: vec![
+                (String::from("app"), String::from("trino")),
+                (String::from("app"), String::from("worker")),
+            ],
+            cluster_id: Some(String::from("etl")),
+        };
+        let mut buf = vec![];
+        original.write(&mut buf).await.unwrap();
+        let result = OptionalFields::read(&mut buf.as_slice()).await.unwrap();
+        assert_eq!(original, result);
+    }
+
+    #[tokio::test]
+    async fn unknown_field_skipped() {
+        // a field type from a newer version followed by a known one
+        let mut buf = vec![42, 0, 2, 7, 7];
+        buf.extend_from_slice(&[SOURCE_VIRTUAL_IP_TAG, 0, 4, 10, 0, 0, 1, END_TAG]);
+        let result = OptionalFields::read(&mut buf.as_slice()).await.unwrap();
+        assert_eq!(result.source_virtual_ip, Some(Ipv4Addr::new(10, 0, 0, 1)));
+    }
+}
@@ -1,13 +1,77 @@
 /// Protocol talked between the interceptor and the perforator
+///
+/// Versioned registrations start with a 0 source port, which is never a valid
+/// bound port, followed by the version and capabilities of the interceptor.
+/// Legacy (version 0) registrations are still accepted and answered with the
+/// legacy single byte response. Conversely, interceptors query the version of
+/// the perforator before their first registration and use the legacy layout
+/// if it predates versioning, which closes the connection without answering,
+/// so that either side can be upgraded first. The other control messages
+/// require an upgraded perforator.
+use crate::optional_fields::OptionalFields;
 use crate::tcp_connect::connect_retry;
+use crate::tunnel_error::TunnelError;
+use std::collections::BTreeMap;
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
 use std::net::Ipv4Addr;
+use std::sync::Mutex;
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::time::Instant;
+use tracing::{debug, warn};
 
 const REGISTER_HEADER_LENGTH: usize = 13;
 const REGISTER_CLIENT_HEADER_BYTES: [u8; REGISTER_HEADER_LENGTH] = *b"chappy_client";
+const REGISTER_UDP_HEADER_BYTES: [u8; REGISTER_HEADER_LENGTH] = *b"chappy_udpreg";
+const WAIT_CLUSTER_HEADER_BYTES: [u8; REGISTER_HEADER_LENGTH] = *b"chappy_waitcl";
+const NODE_STATUS_HEADER_BYTES: [u8; REGISTER_HEADER_LENGTH] = *b"chappy_status";
+const NODE_IP_HEADER_BYTES: [u8; REGISTER_HEADER_LENGTH] = *b"chappy_nodeip";
+const VERSION_QUERY_HEADER_BYTES: [u8; REGISTER_HEADER_LENGTH] = *b"chappy_regver";
+
+const APP_STARTED_KIND: u8 = 1;
+const APP_EXITED_KIND: u8 = 2;
+const SHUTTING_DOWN_KIND: u8 = 3;
+
+/// Lifecycle events of a node, e.g. reported by the launcher of the app
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LifecycleEvent {
+    AppStarted,
+    AppExited(i32),
+    ShuttingDown,
+}
+
+impl LifecycleEvent {
+    fn encode(self) -> (u8, i32) {
+        match self {
+            Self::AppStarted => (APP_STARTED_KIND, 0),
+            Self::AppExited(code) => (APP_EXITED_KIND, code),
+            Self::ShuttingDown => (SHUTTING_DOWN_KIND, 0),
+        }
+    }
+
+    fn decode(kind: u8, exit_code: i32) -> Option<Self> {
+        match kind {
+            APP_STARTED_KIND => Some(Self::AppStarted),
+            APP_EXITED_KIND => Some(Self::AppExited(exit_code)),
+            SHUTTING_DOWN_KIND => Some(Self::ShuttingDown),
+            _ => None,
+        }
+    }
+}
+
+/// Current version of the registration protocol
+pub const REGISTRATION_VERSION: u8 = 2;
+
+/// First version where the perforator keeps the connection of a UDP
+/// registration open until the relay is closed
+pub const UDP_RELAY_LIFETIME_VERSION: u8 = 2;
+
+/// Placed where legacy registrations have their source port
+const VERSIONED_MARKER: u16 = 0;
+
+/// Capabilities supported by this version, as bit flags
+pub const REGISTRATION_CAPABILITIES: u32 = 0;
 
 #[derive(Debug)]
 pub enum ParsedTcpStream {
@@ -15,68 +79,852 @@ pub enum ParsedTcpStream {
         source_port: u16,
         target_virtual_ip: Ipv4Addr,
         target_port: u16,
+        /// Capabilities supported by both the interceptor and this version
+        capabilities: u32,
+        fields: OptionalFields,
         response_writer: ResponseWriter,
     },
+    /// Request for a local UDP port relaying datagrams to the target, only
+    /// sent by versioned interceptors
+    UdpRegistration {
+        target_virtual_ip: Ipv4Addr,
+        target_port: u16,
+        fields: OptionalFields,
+        response_writer: UdpResponseWriter,
+    },
+    /// Request to wait until all the nodes of the cluster are bound, or
+    /// until they all reached the stage if one is provided
+    ClusterWait {
+        stage: Option<String>,
+        fields: OptionalFields,
+        response_writer: ControlResponseWriter,
+    },
+    /// Lifecycle event of the node, None if unknown to this version
+    NodeStatus {
+        event: Option<LifecycleEvent>,
+        fields: OptionalFields,
+        response_writer: ControlResponseWriter,
+    },
+    /// Request for the virtual IP of the node, which might have been
+    /// allocated by the seed
+    VirtualIpQuery {
+        fields: OptionalFields,
+        response_writer: VirtualIpResponseWriter,
+    },
+    /// Request for the registration version of the perforator, sent by the
+    /// interceptors before their first registration
+    VersionQuery {
+        response_writer: VersionResponseWriter,
+    },
     Raw(TcpStream),
 }
 
+/// How long a connection that starts with a truncated control header is
+/// given to send the rest of it
+const HEADER_PEEK_TIMEOUT: Duration = Duration::from_secs(1);
+
+const CONTROL_HEADERS: [[u8; REGISTER_HEADER_LENGTH]; 6] = [
+    REGISTER_CLIENT_HEADER_BYTES,
+    REGISTER_UDP_HEADER_BYTES,
+    WAIT_CLUSTER_HEADER_BYTES,
+    NODE_STATUS_HEADER_BYTES,
+    NODE_IP_HEADER_BYTES,
+    VERSION_QUERY_HEADER_BYTES,
+];
+
+/// Peek the control header the stream starts with, if any
+///
+/// Peeks might return fewer bytes than the header, so we keep peeking as long
+/// as the received bytes are the beginning of a control header.
+async fn peek_header(stream: &TcpStream) -> IoResult<Option<[u8; REGISTER_HEADER_LENGTH]>> {
+    let deadline = Instant::now() + HEADER_PEEK_TIMEOUT;
+    let mut buff = [0; REGISTER_HEADER_LENGTH];
+    loop {
+        let len = stream.peek(&mut buff).await?;
+        if len == REGISTER_HEADER_LENGTH {
+            return Ok(CONTROL_HEADERS.contains(&buff).then_some(buff));
+        }
+        let is_prefix = CONTROL_HEADERS
+            .iter()
+            .any(|header| header.starts_with(&buff[..len]));
+        if len == 0 || !is_prefix || Instant::now() >= deadline {
+            return Ok(None);
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Answer malformed control messages with a version mismatch before failing
+async fn reject_malformed<T>(stream: &mut TcpStream, result: IoResult<T>) -> IoResult<T> {
+    if result.is_err() {
+        let code = TunnelError::encode(Err(TunnelError::ProtocolVersionMismatch));
+        if let Err(err) = stream.write_u8(code).await {
+            debug!(%err, "failed to reject malformed message");
+        }
+    }
+    result
+}
+
 impl ParsedTcpStream {
-    pub async fn from(mut stream: TcpStream) -> Self {
+    /// Parse the control message the stream starts with
+    ///
+    /// Streams that don't start with a control header are returned as raw.
+    /// Malformed control messages fail after being answered with a protocol
+    /// version mismatch.
+    pub async fn from(mut stream: TcpStream) -> IoResult<Self> {
+        let header = match peek_header(&stream).await? {
+            Some(header) => header,
+            None => return Ok(Self::Raw(stream)),
+        };
         let mut buff = [0; REGISTER_HEADER_LENGTH];
-        stream.peek(&mut buff).await.unwrap();
-        if buff == REGISTER_CLIENT_HEADER_BYTES {
-            stream.read_exact(&mut buff).await.unwrap();
-            let source_port = stream.read_u16().await.unwrap();
-            let target_virtual_ip: Ipv4Addr = stream.read_u32().await.unwrap().into();
-            let target_port = stream.read_u16().await.unwrap();
-            Self::ClientRegistration {
-                source_port,
-                target_virtual_ip,
-                target_port,
-                response_writer: ResponseWriter(stream),
+        stream.read_exact(&mut buff).await?;
+        let parsed = match header {
+            REGISTER_CLIENT_HEADER_BYTES => {
+                let res = async {
+                    let mut version = 0;
+                    let mut capabilities = 0;
+                    let mut source_port = stream.read_u16().await?;
+                    if source_port == VERSIONED_MARKER {
+                        // newer interceptors are answered with the version we support
+                        version = stream.read_u8().await?.min(REGISTRATION_VERSION);
+                        capabilities = stream.read_u32().await? & REGISTRATION_CAPABILITIES;
+                        source_port = stream.read_u16().await?;
+                    }
+                    let target_virtual_ip: Ipv4Addr = stream.read_u32().await?.into();
+                    let target_port = stream.read_u16().await?;
+                    let fields = if version > 0 {
+                        OptionalFields::read(&mut stream).await?
+                    } else {
+                        OptionalFields::default()
+                    };
+                    let parts = (version, capabilities, source_port, target_virtual_ip);
+                    IoResult::Ok((parts, target_port, fields))
+                }
+                .await;
+                let ((version, capabilities, source_port, target_virtual_ip), target_port, fields) =
+                    reject_malformed(&mut stream, res).await?;
+                Self::ClientRegistration {
+                    source_port,
+                    target_virtual_ip,
+                    target_port,
+                    capabilities,
+                    fields,
+                    response_writer: ResponseWriter { stream, version },
+                }
             }
-        } else {
-            Self::Raw(stream)
-        }
+            REGISTER_UDP_HEADER_BYTES => {
+                let res = async {
+                    let version = stream.read_u8().await?.min(REGISTRATION_VERSION);
+                    let target_virtual_ip: Ipv4Addr = stream.read_u32().await?.into();
+                    let target_port = stream.read_u16().await?;
+                    let fields = OptionalFields::read(&mut stream).await?;
+                    IoResult::Ok((version, target_virtual_ip, target_port, fields))
+                }
+                .await;
+                let (version, target_virtual_ip, target_port, fields) =
+                    reject_malformed(&mut stream, res).await?;
+                Self::UdpRegistration {
+                    target_virtual_ip,
+                    target_port,
+                    fields,
+                    response_writer: UdpResponseWriter { stream, version },
+                }
+            }
+            WAIT_CLUSTER_HEADER_BYTES => {
+                let res = async {
+                    let version = stream.read_u8().await?.min(REGISTRATION_VERSION);
+                    let stage_len = stream.read_u16().await?;
+                    let mut stage = vec![0; stage_len.into()];
+                    stream.read_exact(&mut stage).await?;
+                    let stage = String::from_utf8(stage)
+                        .map_err(|err| IoError::new(IoErrorKind::InvalidData, err))?;
+                    let fields = OptionalFields::read(&mut stream).await?;
+                    IoResult::Ok((version, stage, fields))
+                }
+                .await;
+                let (version, stage, fields) = reject_malformed(&mut stream, res).await?;
+                Self::ClusterWait {
+                    stage: (!stage.is_empty()).then_some(stage),
+                    fields,
+                    response_writer: ControlResponseWriter { stream, version },
+                }
+            }
+            NODE_STATUS_HEADER_BYTES => {
+                let res = async {
+                    let version = stream.read_u8().await?.min(REGISTRATION_VERSION);
+                    let kind = stream.read_u8().await?;
+                    let exit_code = stream.read_i32().await?;
+                    let fields = OptionalFields::read(&mut stream).await?;
+                    IoResult::Ok((version, kind, exit_code, fields))
+                }
+                .await;
+                let (version, kind, exit_code, fields) = reject_malformed(&mut stream, res).await?;
+                Self::NodeStatus {
+                    event: LifecycleEvent::decode(kind, exit_code),
+                    fields,
+                    response_writer: ControlResponseWriter { stream, version },
+                }
+            }
+            VERSION_QUERY_HEADER_BYTES => {
+                let res = stream.read_u8().await;
+                let version = reject_malformed(&mut stream, res).await?;
+                Self::VersionQuery {
+                    response_writer: VersionResponseWriter {
+                        stream,
+                        version: version.min(REGISTRATION_VERSION),
+                    },
+                }
+            }
+            _ => {
+                let res = async {
+                    let version = stream.read_u8().await?.min(REGISTRATION_VERSION);
+                    let fields = OptionalFields::read(&mut stream).await?;
+                    IoResult::Ok((version, fields))
+                }
+                .await;
+                let (version, fields) = reject_malformed(&mut stream, res).await?;
+                Self::VirtualIpQuery {
+                    fields,
+                    response_writer: VirtualIpResponseWriter { stream, version },
+                }
+            }
+        };
+        Ok(parsed)
+    }
+}
+
+/// Log the failures to answer, the requester might have given up already
+fn log_write_failure(result: IoResult<()>) {
+    if let Err(err) = result {
+        warn!(%err, "failed to write control response");
     }
 }
 
 #[derive(Debug)]
-pub struct ResponseWriter(TcpStream);
+pub struct ResponseWriter {
+    stream: TcpStream,
+    /// Version negotiated with the interceptor
+    version: u8,
+}
 
 impl ResponseWriter {
-    pub async fn write_success(mut self) {
-        self.0.write_u8(0).await.unwrap();
-        self.0.flush().await.unwrap();
+    async fn write(mut self, result: Result<(), TunnelError>) -> IoResult<()> {
+        self.stream.write_u8(TunnelError::encode(result)).await?;
+        if self.version > 0 {
+            self.stream.write_u8(self.version).await?;
+            self.stream.write_u32(REGISTRATION_CAPABILITIES).await?;
+        }
+        self.stream.flush().await
+    }
+
+    pub async fn write_success(self) {
+        log_write_failure(self.write(Ok(())).await);
+    }
+
+    pub async fn write_failure(self, err: TunnelError) {
+        log_write_failure(self.write(Err(err)).await);
+    }
+}
+
+#[derive(Debug)]
+pub struct UdpResponseWriter {
+    stream: TcpStream,
+    version: u8,
+}
+
+impl UdpResponseWriter {
+    async fn write_to(
+        stream: &mut TcpStream,
+        version: u8,
+        result: Result<u16, TunnelError>,
+    ) -> IoResult<()> {
+        stream
+            .write_u8(TunnelError::encode(result.map(|_| ())))
+            .await?;
+        if let Ok(relay_port) = result {
+            stream.write_u8(version).await?;
+            stream.write_u16(relay_port).await?;
+        }
+        stream.flush().await
+    }
+
+    /// Report the relay port, returning the connection that should be held
+    /// until the relay is closed if the interceptor watches it
+    pub async fn write_success(mut self, relay_port: u16) -> Option<TcpStream> {
+        let res = Self::write_to(&mut self.stream, self.version, Ok(relay_port)).await;
+        let watched = res.is_ok() && self.version >= UDP_RELAY_LIFETIME_VERSION;
+        log_write_failure(res);
+        watched.then_some(self.stream)
+    }
+
+    pub async fn write_failure(mut self, err: TunnelError) {
+        log_write_failure(Self::write_to(&mut self.stream, self.version, Err(err)).await);
+    }
+}
+
+/// A datagram relay opened by the perforator
+#[derive(Debug)]
+pub struct UdpRelay {
+    /// Local port to send the datagrams to
+    pub port: u16,
+    /// Closed by the perforator with the relay, unless it predates
+    /// [`UDP_RELAY_LIFETIME_VERSION`]
+    registration: Option<std::net::TcpStream>,
+}
+
+impl UdpRelay {
+    /// Whether the perforator closed the relay, e.g. because its QUIC
+    /// connection was closed
+    ///
+    /// Relays of perforators that don't report it are assumed open.
+    pub fn is_closed(&self) -> bool {
+        match &self.registration {
+            // nothing is sent on the connection once registered
+            Some(stream) => !matches!(
+                stream.peek(&mut [0; 1]),
+                Err(err) if err.kind() == IoErrorKind::WouldBlock
+            ),
+            None => false,
+        }
+    }
+}
+
+/// Answer to the control messages, e.g. cluster waits, that only report
+/// whether they succeeded
+#[derive(Debug)]
+pub struct ControlResponseWriter {
+    stream: TcpStream,
+    version: u8,
+}
+
+impl ControlResponseWriter {
+    pub async fn write(mut self, result: Result<(), TunnelError>) {
+        let res = async {
+            self.stream.write_u8(TunnelError::encode(result)).await?;
+            self.stream.write_u8(self.version).await?;
+            self.stream.flush().await
+        };
+        log_write_failure(res.await);
+    }
+}
+
+#[derive(Debug)]
+pub struct VirtualIpResponseWriter {
+    stream: TcpStream,
+    version: u8,
+}
+
+impl VirtualIpResponseWriter {
+    pub async fn write(mut self, result: Result<Ipv4Addr, TunnelError>) {
+        let res = async {
+            self.stream
+                .write_u8(TunnelError::encode(result.map(|_| ())))
+                .await?;
+            self.stream.write_u8(self.version).await?;
+            if let Ok(virtual_ip) = result {
+                self.stream.write_u32(virtual_ip.into()).await?;
+            }
+            self.stream.flush().await
+        };
+        log_write_failure(res.await);
     }
+}
 
-    pub async fn write_failure(mut self) {
-        self.0.write_u8(1).await.unwrap();
-        self.0.flush().await.unwrap();
+#[derive(Debug)]
+pub struct VersionResponseWriter {
+    stream: TcpStream,
+    version: u8,
+}
+
+impl VersionResponseWriter {
+    pub async fn write(mut self) {
+        let res = async {
+            self.stream.write_u8(self.version).await?;
+            self.stream.write_u32(REGISTRATION_CAPABILITIES).await?;
+            self.stream.flush().await
+        };
+        log_write_failure(res.await);
     }
 }
 
+/// Registration versions of the perforators already queried, by address
+static PERFORATOR_VERSIONS: Mutex<BTreeMap<String, u8>> = Mutex::new(BTreeMap::new());
+
+/// Get the registration version of the perforator, queried once per address
+///
+/// Perforators that predate versioning don't recognize the query and close
+/// the connection without answering it, without calling the seed.
+async fn perforator_version(perforator_address: &str) -> IoResult<u8> {
+    if let Some(version) = PERFORATOR_VERSIONS.lock().unwrap().get(perforator_address) {
+        return Ok(*version);
+    }
+    let mut stream = connect_retry(perforator_address, Duration::from_secs(3)).await?;
+    stream.write_all(&VERSION_QUERY_HEADER_BYTES).await?;
+    stream.write_u8(REGISTRATION_VERSION).await?;
+    stream.flush().await?;
+    let version = match stream.read_u8().await {
+        Ok(version) => {
+            let _perforator_capabilities = stream.read_u32().await?;
+            version
+        }
+        // the query is closed with unread bytes, which resets the connection
+        Err(err)
+            if matches!(
+                err.kind(),
+                IoErrorKind::UnexpectedEof | IoErrorKind::ConnectionReset
+            ) =>
+        {
+            debug!(perforator_address, "perforator predates versioning");
+            0
+        }
+        Err(err) => return Err(err),
+    };
+    PERFORATOR_VERSIONS
+        .lock()
+        .unwrap()
+        .insert(perforator_address.to_owned(), version);
+    Ok(version)
+}
+
 pub async fn register_client(
     perforator_address: &str,
     source_port: u16,
     target_virtual_ip: Ipv4Addr,
     target_port: u16,
+    fields: &OptionalFields,
 ) -> IoResult<()> {
+    if perforator_version(perforator_address).await? == 0 {
+        return register_legacy_client(
+            perforator_address,
+            source_port,
+            target_virtual_ip,
+            target_port,
+        )
+        .await;
+    }
     let mut stream = connect_retry(perforator_address, Duration::from_secs(3)).await?;
     stream.write_all(&REGISTER_CLIENT_HEADER_BYTES).await?;
+    stream.write_u16(VERSIONED_MARKER).await?;
+    stream.write_u8(REGISTRATION_VERSION).await?;
+    stream.write_u32(REGISTRATION_CAPABILITIES).await?;
     stream.write_u16(source_port).await?;
     stream.write_u32(target_virtual_ip.into()).await?;
     stream.write_u16(target_port).await?;
+    fields.write(&mut stream).await?;
     stream.flush().await?;
-    if stream.read_u8().await? > 0 {
-        return Err(IoError::new(
-            IoErrorKind::AddrNotAvailable,
-            anyhow::anyhow!("Perforator could not reach target"),
-        ));
-    }
+    // the tunnel error is carried as the inner error
+    TunnelError::decode(stream.read_u8().await?)?;
+    let _perforator_version = stream.read_u8().await?;
+    let _perforator_capabilities = stream.read_u32().await?;
     stream
         .read_u8()
         .await
         .expect_err("Connection should have been closed by peer");
     Ok(())
 }
+
+/// Registration understood by perforators that predate versioning, without
+/// the optional fields
+async fn register_legacy_client(
+    perforator_address: &str,
+    source_port: u16,
+    target_virtual_ip: Ipv4Addr,
+    target_port: u16,
+) -> IoResult<()> {
+    let mut stream = connect_retry(perforator_address, Duration::from_secs(3)).await?;
+    stream.write_all(&REGISTER_CLIENT_HEADER_BYTES).await?;
+    stream.write_u16(source_port).await?;
+    stream.write_u32(target_virtual_ip.into()).await?;
+    stream.write_u16(target_port).await?;
+    stream.flush().await?;
+    TunnelError::decode(stream.read_u8().await?)?;
+    Ok(())
+}
+
+/// Get the local port on which the perforator relays datagrams to the target
+pub async fn register_udp(
+    perforator_address: &str,
+    target_virtual_ip: Ipv4Addr,
+    target_port: u16,
+    fields: &OptionalFields,
+) -> IoResult<UdpRelay> {
+    let mut stream = connect_retry(perforator_address, Duration::from_secs(3)).await?;
+    stream.write_all(&REGISTER_UDP_HEADER_BYTES).await?;
+    stream.write_u8(REGISTRATION_VERSION).await?;
+    stream.write_u32(target_virtual_ip.into()).await?;
+    stream.write_u16(target_port).await?;
+    fields.write(&mut stream).await?;
+    stream.flush().await?;
+    TunnelError::decode(stream.read_u8().await?)?;
+    let perforator_version = stream.read_u8().await?;
+    let port = stream.read_u16().await?;
+    // the connection is watched without a runtime
+    let registration = if perforator_version >= UDP_RELAY_LIFETIME_VERSION {
+        Some(stream.into_std()?)
+    } else {
+        None
+    };
+    Ok(UdpRelay { port, registration })
+}
+
+/// Wait until all the nodes of the cluster are bound, or until they all
+/// reached the stage if one is provided
+///
+/// The stage is reached by the node identified by the fields, or by the
+/// primary identity of the perforator if none is provided.
+pub async fn wait_cluster(
+    perforator_address: &str,
+    stage: Option<&str>,
+    fields: &OptionalFields,
+) -> IoResult<()> {
+    let stage = stage.unwrap_or_default();
+    let stage_len = u16::try_from(stage.len())
+        .map_err(|_| IoError::new(IoErrorKind::InvalidInput, "stage name too long"))?;
+    let mut stream = connect_retry(perforator_address, Duration::from_secs(3)).await?;
+    stream.write_all(&WAIT_CLUSTER_HEADER_BYTES).await?;
+    stream.write_u8(REGISTRATION_VERSION).await?;
+    stream.write_u16(stage_len).await?;
+    stream.write_all(stage.as_bytes()).await?;
+    fields.write(&mut stream).await?;
+    stream.flush().await?;
+    TunnelError::decode(stream.read_u8().await?)?;
+    let _perforator_version = stream.read_u8().await?;
+    Ok(())
+}
+
+/// Report a lifecycle event of the node identified by the fields, or of the
+/// primary identity of the perforator if none is provided
+pub async fn report_status(
+    perforator_address: &str,
+    event: LifecycleEvent,
+    fields: &OptionalFields,
+) -> IoResult<()> {
+    let (kind, exit_code) = event.encode();
+    let mut stream = connect_retry(perforator_address, Duration::from_secs(3)).await?;
+    stream.write_all(&NODE_STATUS_HEADER_BYTES).await?;
+    stream.write_u8(REGISTRATION_VERSION).await?;
+    stream.write_u8(kind).await?;
+    stream.write_i32(exit_code).await?;
+    fields.write(&mut stream).await?;
+    stream.flush().await?;
+    TunnelError::decode(stream.read_u8().await?)?;
+    let _perforator_version = stream.read_u8().await?;
+    Ok(())
+}
+
+/// Get the virtual IP of the node identified by the fields, or of the primary
+/// identity of the perforator if none is provided
+pub async fn query_virtual_ip(
+    perforator_address: &str,
+    fields: &OptionalFields,
+) -> IoResult<Ipv4Addr> {
+    let mut stream = connect_retry(perforator_address, Duration::from_secs(3)).await?;
+    stream.write_all(&NODE_IP_HEADER_BYTES).await?;
+    stream.write_u8(REGISTRATION_VERSION).await?;
+    fields.write(&mut stream).await?;
+    stream.flush().await?;
+    TunnelError::decode(stream.read_u8().await?)?;
+    let _perforator_version = stream.read_u8().await?;
+    Ok(stream.read_u32().await?.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::available_ports;
+    use tokio::net::TcpListener;
+
+    /// Parse the first connection on the listener and answer it
+    async fn answer_registration(
+        listener: TcpListener,
+        result: Result<(), TunnelError>,
+    ) -> (u16, Ipv4Addr, u16, OptionalFields) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut parsed = ParsedTcpStream::from(stream).await.unwrap();
+        // versioned interceptors query the version before registering
+        if let ParsedTcpStream::VersionQuery { response_writer } = parsed {
+            response_writer.write().await;
+            let (stream, _) = listener.accept().await.unwrap();
+            parsed = ParsedTcpStream::from(stream).await.unwrap();
+        }
+        match parsed {
+            ParsedTcpStream::ClientRegistration {
+                source_port,
+                target_virtual_ip,
+                target_port,
+                fields,
+                response_writer,
+                ..
+            } => {
+                match result {
+                    Ok(()) => response_writer.write_success().await,
+                    Err(err) => response_writer.write_failure(err).await,
+                }
+                (source_port, target_virtual_ip, target_port, fields)
+            }
+            _ => panic!("client registration expected"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_versioned_registration() {
+        let port = available_ports(1).await[0];
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        let fields = OptionalFields {
+            source_virtual_ip: Some(Ipv4Addr::new(172, 28, 0, 1)),
+            ..Default::default()
+        };
+        let srv_handle = tokio::spawn(answer_registration(listener, Ok(())));
+        let addr = format!("127.0.0.1:{}", port);
+        register_client(&addr, 4000, Ipv4Addr::new(172, 28, 0, 2), 80, &fields)
+            .await
+            .unwrap();
+        let parsed = srv_handle.await.unwrap();
+        assert_eq!(parsed, (4000, Ipv4Addr::new(172, 28, 0, 2), 80, fields));
+    }
+
+    #[tokio::test]
+    async fn test_versioned_registration_failure() {
+        let port = available_ports(1).await[0];
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        let srv_handle = tokio::spawn(answer_registration(
+            listener,
+            Err(TunnelError::TargetTimedOut),
+        ));
+        let addr = format!("127.0.0.1:{}", port);
+        let err = register_client(&addr, 4000, Ipv4Addr::LOCALHOST, 80, &Default::default())
+            .await
+            .expect_err("registration should fail");
+        let inner = err.into_inner().unwrap().downcast::<TunnelError>().unwrap();
+        assert_eq!(*inner, TunnelError::TargetTimedOut);
+        srv_handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_legacy_registration() {
+        let port = available_ports(1).await[0];
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        let srv_handle = tokio::spawn(answer_registration(
+            listener,
+            Err(TunnelError::TargetRefused),
+        ));
+        // registration as sent by interceptors that predate versioning
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream.write_all(b"chappy_client").await.unwrap();
+        stream.write_u16(4000).await.unwrap();
+        stream.write_u32(Ipv4Addr::LOCALHOST.into()).await.unwrap();
+        stream.write_u16(80).await.unwrap();
+        let mut resp = vec![];
+        stream.read_to_end(&mut resp).await.unwrap();
+        assert_eq!(resp, vec![TunnelError::TargetRefused as u8]);
+        let parsed = srv_handle.await.unwrap();
+        assert_eq!(
+            parsed,
+            (4000, Ipv4Addr::LOCALHOST, 80, OptionalFields::default())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_legacy_perforator() {
+        let port = available_ports(1).await[0];
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        // perforator that predates versioning, which peeks the header and
+        // forwards the connections with another header as raw streams
+        let srv_handle = tokio::spawn(async move {
+            let mut raw_conns = 0;
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut header = [0; REGISTER_HEADER_LENGTH];
+                let mut peeked = 0;
+                while peeked < REGISTER_HEADER_LENGTH {
+                    peeked = stream.peek(&mut header).await.unwrap();
+                }
+                if header != REGISTER_CLIENT_HEADER_BYTES {
+                    // the raw stream finds no port mapping and is dropped
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    raw_conns += 1;
+                    continue;
+                }
+                stream.read_exact(&mut header).await.unwrap();
+                let source_port = stream.read_u16().await.unwrap();
+                let target_virtual_ip = Ipv4Addr::from(stream.read_u32().await.unwrap());
+                let target_port = stream.read_u16().await.unwrap();
+                stream.write_u8(TunnelError::encode(Ok(()))).await.unwrap();
+                return (raw_conns, (source_port, target_virtual_ip, target_port));
+            }
+        });
+        let addr = format!("127.0.0.1:{}", port);
+        let fields = OptionalFields {
+            source_virtual_ip: Some(Ipv4Addr::new(172, 28, 0, 1)),
+            ..Default::default()
+        };
+        register_client(&addr, 4000, Ipv4Addr::new(172, 28, 0, 2), 80, &fields)
+            .await
+            .unwrap();
+        let (raw_conns, parsed) = srv_handle.await.unwrap();
+        // only the version query reaches the legacy perforator unparsed, the
+        // registration itself is never misread
+        assert_eq!(raw_conns, 1);
+        assert_eq!(parsed, (4000, Ipv4Addr::new(172, 28, 0, 2), 80));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_message() {
+        let port = available_ports(1).await[0];
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        let srv_handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            ParsedTcpStream::from(stream).await
+        });
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        // the header is split to check that short peeks are completed
+        stream.write_all(b"chappy_").await.unwrap();
+        stream.flush().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stream.write_all(b"waitcl").await.unwrap();
+        stream.write_u8(REGISTRATION_VERSION).await.unwrap();
+        // stage that is not valid UTF-8
+        stream.write_u16(2).await.unwrap();
+        stream.write_all(&[0xff, 0xfe]).await.unwrap();
+        let err = srv_handle.await.unwrap().expect_err("parsing should fail");
+        assert_eq!(err.kind(), IoErrorKind::InvalidData);
+        let code = stream.read_u8().await.unwrap();
+        assert_eq!(
+            TunnelError::decode(code),
+            Err(TunnelError::ProtocolVersionMismatch)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_udp_registration() {
+        let port = available_ports(1).await[0];
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        let srv_handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            match ParsedTcpStream::from(stream).await.unwrap() {
+                ParsedTcpStream::UdpRegistration {
+                    target_virtual_ip,
+                    target_port,
+                    response_writer,
+                    ..
+                } => {
+                    let registration = response_writer.write_success(40000).await;
+                    (target_virtual_ip, target_port, registration)
+                }
+                _ => panic!("UDP registration expected"),
+            }
+        });
+        let addr = format!("127.0.0.1:{}", port);
+        let relay = register_udp(
+            &addr,
+            Ipv4Addr::new(172, 28, 0, 2),
+            8125,
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(relay.port, 40000);
+        let (target_virtual_ip, target_port, registration) = srv_handle.await.unwrap();
+        assert_eq!(
+            (target_virtual_ip, target_port),
+            (Ipv4Addr::new(172, 28, 0, 2), 8125)
+        );
+
+        // the relay is reported closed with its registration
+        assert!(!relay.is_closed());
+        drop(registration.expect("registration should be held"));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(relay.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_wait_cluster() {
+        let port = available_ports(1).await[0];
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        let srv_handle = tokio::spawn(async move {
+            let mut stages = vec![];
+            for result in [Ok(()), Err(TunnelError::BarrierBroken)] {
+                let (stream, _) = listener.accept().await.unwrap();
+                match ParsedTcpStream::from(stream).await.unwrap() {
+                    ParsedTcpStream::ClusterWait {
+                        stage,
+                        response_writer,
+                        ..
+                    } => {
+                        response_writer.write(result).await;
+                        stages.push(stage);
+                    }
+                    _ => panic!("cluster wait expected"),
+                }
+            }
+            stages
+        });
+        let addr = format!("127.0.0.1:{}", port);
+        wait_cluster(&addr, None, &Default::default())
+            .await
+            .unwrap();
+        let err = wait_cluster(&addr, Some("loaded"), &Default::default())
+            .await
+            .expect_err("barrier should be broken");
+        let inner = err.into_inner().unwrap().downcast::<TunnelError>().unwrap();
+        assert_eq!(*inner, TunnelError::BarrierBroken);
+        let stages = srv_handle.await.unwrap();
+        assert_eq!(stages, vec![None, Some(String::from("loaded"))]);
+    }
+
+    #[tokio::test]
+    async fn test_report_status() {
+        let port = available_ports(1).await[0];
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        let srv_handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            match ParsedTcpStream::from(stream).await.unwrap() {
+                ParsedTcpStream::NodeStatus {
+                    event,
+                    response_writer,
+                    ..
+                } => {
+                    response_writer.write(Ok(())).await;
+                    event
+                }
+                _ => panic!("node status expected"),
+            }
+        });
+        let addr = format!("127.0.0.1:{}", port);
+        report_status(&addr, LifecycleEvent::AppExited(-9), &Default::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            srv_handle.await.unwrap(),
+            Some(LifecycleEvent::AppExited(-9))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_virtual_ip() {
+        let port = available_ports(1).await[0];
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        let srv_handle = tokio::spawn(async move {
+            for result in [
+                Ok(Ipv4Addr::new(172, 28, 0, 7)),
+                Err(TunnelError::UnknownIdentity),
+            ] {
+                let (stream, _) = listener.accept().await.unwrap();
+                match ParsedTcpStream::from(stream).await.unwrap() {
+                    ParsedTcpStream::VirtualIpQuery {
+                        response_writer, ..
+                    } => response_writer.write(result).await,
+                    _ => panic!("virtual IP query expected"),
+                }
+            }
+        });
+        let addr = format!("127.0.0.1:{}", port);
+        let fields = OptionalFields::default();
+        let virtual_ip = query_virtual_ip(&addr, &fields).await.unwrap();
+        assert_eq!(virtual_ip, Ipv4Addr::new(172, 28, 0, 7));
+        let err = query_virtual_ip(&addr, &fields).await.unwrap_err();
+        let inner = err.get_ref().unwrap().downcast_ref::<TunnelError>();
+        assert_eq!(inner, Some(&TunnelError::UnknownIdentity));
+        srv_handle.await.unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+
+/// Reasons why a tunnel to a virtual target could not be established
+///
+/// The discriminants are the codes sent over the wire, 0 meaning success.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TunnelError {
+    /// Nothing accepted the connection on the target port
+    TargetRefused = 1,
+    TargetTimedOut = 2,
+    /// The target perforator does not forward connections to this port
+    PortNotExposed = 3,
+    QuicConnectFailed = 4,
+    /// The target did not present the certificate registered in the seed
+    CertificateMismatch = 5,
+    /// The peer sent a message this version does not understand
+    ProtocolVersionMismatch = 6,
+    /// The perforator does not serve the virtual IP and cluster of the source
+    UnknownIdentity = 7,
+    /// The target node left the cluster
+    TargetLeft = 8,
+    /// The seed could not resolve the target virtual IP, e.g. because it is
+    /// not part of the cluster
+    TargetUnresolved = 9,
+    /// A node left the cluster before the awaited barrier was reached
+    BarrierBroken = 10,
+    /// The seed aborted the cluster, e.g. because a node failed
+    ClusterAborted = 11,
+    /// The perforator could not open the local socket relaying the datagrams
+    RelayUnavailable = 12,
+}
+
+impl TunnelError {
+    pub fn encode(result: Result<(), Self>) -> u8 {
+        match result {
+            Ok(()) => 0,
+            Err(err) => err as u8,
+        }
+    }
+
+    /// Codes unknown to this version are reported as a protocol mismatch
+    pub fn decode(code: u8) -> Result<(), Self> {
+        match code {
+            0 => Ok(()),
+            1 => Err(Self::TargetRefused),
+            2 => Err(Self::TargetTimedOut),
+            3 => Err(Self::PortNotExposed),
+            4 => Err(Self::QuicConnectFailed),
+            5 => Err(Self::CertificateMismatch),
+            7 => Err(Self::UnknownIdentity),
+            8 => Err(Self::TargetLeft),
+            9 => Err(Self::TargetUnresolved),
+            10 => Err(Self::BarrierBroken),
+            11 => Err(Self::ClusterAborted),
+            12 => Err(Self::RelayUnavailable),
+            _ => Err(Self::ProtocolVersionMismatch),
+        }
+    }
+}
+
+impl fmt::Display for TunnelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            Self::TargetRefused => "target refused the connection",
+            Self::TargetTimedOut => "connection to target timed out",
+            Self::PortNotExposed => "target port not exposed",
+            Self::QuicConnectFailed => "QUIC connection to target failed",
+            Self::CertificateMismatch => "target certificate mismatch",
+            Self::ProtocolVersionMismatch => "protocol version mismatch",
+            Self::UnknownIdentity => "source identity not served by the perforator",
+            Self::TargetLeft => "target node left the cluster",
+            Self::TargetUnresolved => "target virtual IP could not be resolved",
+            Self::BarrierBroken => "a node left the cluster before reaching the barrier",
+            Self::ClusterAborted => "the cluster was aborted",
+            Self::RelayUnavailable => "local datagram relay could not be opened",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for TunnelError {}
+
+impl From<TunnelError> for IoError {
+    fn from(err: TunnelError) -> Self {
+        let kind = match err {
+            TunnelError::TargetRefused => IoErrorKind::ConnectionRefused,
+            TunnelError::TargetTimedOut => IoErrorKind::TimedOut,
+            TunnelError::PortNotExposed => IoErrorKind::PermissionDenied,
+            TunnelError::QuicConnectFailed => IoErrorKind::AddrNotAvailable,
+            TunnelError::CertificateMismatch => IoErrorKind::ConnectionAborted,
+            TunnelError::ProtocolVersionMismatch => IoErrorKind::InvalidData,
+            TunnelError::UnknownIdentity => IoErrorKind::InvalidInput,
+            TunnelError::TargetLeft => IoErrorKind::NotConnected,
+            TunnelError::TargetUnresolved => IoErrorKind::NotFound,
+            TunnelError::BarrierBroken => IoErrorKind::ConnectionAborted,
+            TunnelError::ClusterAborted => IoErrorKind::ConnectionAborted,
+            TunnelError::RelayUnavailable => IoErrorKind::Other,
+        };
+        IoError::new(kind, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code_roundtrip() {
+        for code in 0..=12 {
+            assert_eq!(TunnelError::encode(TunnelError::decode(code)), code);
+        }
+        assert_eq!(
+            TunnelError::decode(42),
+            Err(TunnelError::ProtocolVersionMismatch)
+        );
+    }
+}
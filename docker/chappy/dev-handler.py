@@ -1,6 +1,8 @@
 import logging
 import os
+import socket
 import stat
+import struct
 import subprocess
 import sys
 import tempfile
@@ -13,6 +15,14 @@ logging.getLogger().setLevel(logging.INFO)
 
 IS_COLD_START = True
 
+PERFORATOR_ADDRESS = ("127.0.0.1", 5000)
+NODE_STATUS_HEADER = b"chappy_status"
+NODE_STATUS_VERSION = 1
+APP_STARTED = 1
+APP_EXITED = 2
+APP_POLL_INTERVAL_SEC = 0.1
+APP_TERMINATION_TIMEOUT_SEC = 5
+
 
 s3 = boto3.client("s3")
 
@@ -47,6 +57,16 @@ def setup_binaries(
     return (local_app_location, local_perforator_location)
 
 
+def terminate(proc: subprocess.Popen) -> tuple[bytes, bytes]:
+    """Terminate the process, killing it if it does not exit in time"""
+    proc.terminate()
+    try:
+        return proc.communicate(timeout=APP_TERMINATION_TIMEOUT_SEC)
+    except subprocess.TimeoutExpired:
+        proc.kill()
+        return proc.communicate()
+
+
 class Perforator:
     def __init__(self, bin_path):
         self.tmp_file = tempfile.NamedTemporaryFile(mode="w+", delete=True)
@@ -74,6 +94,29 @@ class Perforator:
         self._load_logs()
         return self.logs
 
+    def report_status(self, kind: int, exit_code=0, timeout_sec=3.0):
+        """Report a lifecycle event of the app to the seed through the
+        perforator, retrying until the perforator has bound the node"""
+        # no optional fields, the perforator uses its primary identity
+        msg = NODE_STATUS_HEADER + struct.pack(
+            ">BBiB", NODE_STATUS_VERSION, kind, exit_code, 0
+        )
+        deadline = time.time() + timeout_sec
+        while True:
+            try:
+                with socket.create_connection(PERFORATOR_ADDRESS, timeout=1) as sock:
+                    sock.sendall(msg)
+                    code = sock.recv(2)[:1]
+                if code == b"\x00":
+                    return
+                err = f"error code {code!r}"
+            except OSError as e:
+                err = str(e)
+            if time.time() > deadline:
+                logging.warning(f"Reporting status {kind} failed: {err}")
+                return
+            time.sleep(0.05)
+
     def log(self, log=logging.info):
         perf_logs_prefixed = "\n".join(
             [f"[PERFORATOR] {line}" for line in self.get_logs().split("\n")]
@@ -96,6 +139,8 @@ def handler(event: dict, context):
     timeout_sec = event.get("timeout_sec", None)
     for name, value in event.get("env", {}).items():
         os.environ[name] = str(value)
+    # identify the node to the watchers of the cluster
+    os.environ.setdefault("CHAPPY_NODE_NAME", context.aws_request_id)
 
     local_app_location, local_perforator_location = setup_binaries(
         bucket_name, app_object_key, libchappy_object_key, perforator_object_key
@@ -103,22 +148,38 @@ def handler(event: dict, context):
 
     subproc_start = time.time()
     perforator = Perforator(local_perforator_location)
-    try:
-        res = subprocess.run(
-            [local_app_location],
-            stdout=subprocess.PIPE,
-            stderr=subprocess.PIPE,
-            timeout=timeout_sec,
-        )
-        stdout = res.stdout
-        stderr = res.stderr
-        returncode = res.returncode
-    except subprocess.TimeoutExpired as res:
-        logging.info(f"{local_app_location} stopped after {timeout_sec}s timeout")
-        stdout = res.stdout
-        stderr = res.stderr
-        returncode = -1
-
+    perforator.report_status(APP_STARTED)
+    app = subprocess.Popen(
+        [local_app_location],
+        stdout=subprocess.PIPE,
+        stderr=subprocess.PIPE,
+    )
+    deadline = None if timeout_sec is None else time.time() + timeout_sec
+    while True:
+        try:
+            stdout, stderr = app.communicate(timeout=APP_POLL_INTERVAL_SEC)
+            returncode = app.returncode
+            break
+        except subprocess.TimeoutExpired:
+            pass
+        if deadline is not None and time.time() > deadline:
+            logging.info(f"{local_app_location} stopped after {timeout_sec}s timeout")
+            app.kill()
+            stdout, stderr = app.communicate()
+            returncode = -1
+            break
+        # the perforator only exits early when the cluster is aborted or its
+        # bindings are rejected, in which case the app cannot succeed
+        perforator_code = perforator.proc.poll()
+        if perforator_code is not None:
+            logging.warning(
+                f"Perforator exited with code {perforator_code}, terminating {local_app_location}"
+            )
+            stdout, stderr = terminate(app)
+            returncode = app.returncode
+            break
+
+    perforator.report_status(APP_EXITED, returncode)
     subproc_duration = time.time() - subproc_start
 
     result = {